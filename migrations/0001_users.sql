@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS users (
+	id INT PRIMARY KEY,
+	first_name VARCHAR(64) NOT NULL,
+	last_name VARCHAR(64) NOT NULL,
+	gender BOOLEAN NOT NULL,
+	username VARCHAR(128) NOT NULL,
+	password TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS users_username ON users (username);
+
+CREATE TABLE IF NOT EXISTS students (
+	id INT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+	classes TEXT NOT NULL,
+	grade SMALLINT NOT NULL,
+	section CHAR(1) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS teachers (
+	id INT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+	subject VARCHAR(32) NOT NULL,
+	classes TEXT NOT NULL,
+	hod BOOLEAN NOT NULL DEFAULT FALSE
+);
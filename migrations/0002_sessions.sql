@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS sessions (
+	token CHAR(64) PRIMARY KEY,
+	user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	created BIGINT NOT NULL,
+	last_seen BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);
@@ -0,0 +1,30 @@
+-- Earlier versions let several people share `first.last`. Number the later
+-- ones the way new accounts are numbered: anshuman.medhi, anshuman.medhi2, ...
+-- skipping numbers someone already has, such as an older anshuman.medhi2.
+DO $$
+DECLARE
+	dup RECORD;
+	n INT;
+BEGIN
+	FOR dup IN
+		SELECT id, username FROM users u
+		WHERE EXISTS (SELECT 1 FROM users o WHERE o.username = u.username AND o.id < u.id)
+		ORDER BY id
+	LOOP
+		n := 2;
+		WHILE EXISTS (SELECT 1 FROM users WHERE username = dup.username || n) LOOP
+			n := n + 1;
+		END LOOP;
+		UPDATE users SET username = dup.username || n WHERE id = dup.id;
+	END LOOP;
+END
+$$;
+
+DROP INDEX IF EXISTS users_username;
+ALTER TABLE users ADD CONSTRAINT users_username_key UNIQUE (username);
+
+CREATE TABLE reserved_usernames (
+	username VARCHAR(128) PRIMARY KEY,
+	reason TEXT NOT NULL DEFAULT '',
+	created BIGINT NOT NULL
+);
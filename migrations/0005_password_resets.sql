@@ -0,0 +1,7 @@
+CREATE TABLE password_resets (
+	token CHAR(64) PRIMARY KEY,
+	user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	created BIGINT NOT NULL,
+	expires BIGINT NOT NULL
+);
+CREATE INDEX password_resets_user_id ON password_resets (user_id);
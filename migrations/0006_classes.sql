@@ -0,0 +1,44 @@
+CREATE TABLE subjects (
+	code VARCHAR(32) PRIMARY KEY
+);
+INSERT INTO subjects (code) VALUES
+	('Economics'), ('Business'),
+	('Physics'), ('Chemistry'), ('Biology'),
+	('CompSci'), ('ICT'), ('ITGS'),
+	('Psychology'),
+	('English'), ('ESL'),
+	('Spanish'), ('French'), ('German'), ('Hindi'),
+	('Art'), ('Music');
+
+CREATE TABLE classes (
+	id SERIAL PRIMARY KEY,
+	subject VARCHAR(32) NOT NULL REFERENCES subjects (code) ON UPDATE CASCADE,
+	block VARCHAR(16) NOT NULL,
+	grade SMALLINT NOT NULL,
+	UNIQUE (subject, block, grade)
+);
+
+CREATE TABLE enrollments (
+	class_id INT NOT NULL REFERENCES classes (id) ON DELETE CASCADE,
+	student_id INT NOT NULL REFERENCES students (id) ON DELETE CASCADE,
+	PRIMARY KEY (class_id, student_id)
+);
+CREATE INDEX enrollments_student_id ON enrollments (student_id);
+
+CREATE TABLE teaching_assignments (
+	class_id INT NOT NULL REFERENCES classes (id) ON DELETE CASCADE,
+	teacher_id INT NOT NULL REFERENCES teachers (id) ON DELETE CASCADE,
+	PRIMARY KEY (class_id, teacher_id)
+);
+CREATE INDEX teaching_assignments_teacher_id ON teaching_assignments (teacher_id);
+
+-- Entries of the old free-text `classes` columns that could not be parsed.
+CREATE TABLE class_import_failures (
+	user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	entry TEXT NOT NULL,
+	reason TEXT NOT NULL
+);
+
+-- Kept for reference only, nothing writes them any more.
+ALTER TABLE students ALTER COLUMN classes SET DEFAULT '';
+ALTER TABLE teachers ALTER COLUMN classes SET DEFAULT '';
@@ -0,0 +1,43 @@
+ALTER TABLE subjects
+	ADD COLUMN name VARCHAR(64) NOT NULL DEFAULT '',
+	-- IB group 1 to 6, NULL for subjects outside the Diploma Programme
+	ADD COLUMN ib_group SMALLINT CHECK (ib_group BETWEEN 1 AND 6),
+	ADD COLUMN hl BOOLEAN NOT NULL DEFAULT TRUE,
+	ADD COLUMN sl BOOLEAN NOT NULL DEFAULT TRUE,
+	ADD COLUMN head_id INT REFERENCES teachers (id) ON DELETE SET NULL;
+
+INSERT INTO subjects (code) VALUES ('Math');
+
+UPDATE subjects SET name = s.name, ib_group = s.ib_group FROM (VALUES
+	('Economics', 'Economics', 3),
+	('Business', 'Business Management', 3),
+	('Physics', 'Physics', 4),
+	('Chemistry', 'Chemistry', 4),
+	('Biology', 'Biology', 4),
+	('CompSci', 'Computer Science', 4),
+	('ICT', 'Information and Communication Technology', NULL),
+	('ITGS', 'Information Technology in a Global Society', 3),
+	('Psychology', 'Psychology', 3),
+	('English', 'English A: Language and Literature', 1),
+	('ESL', 'English B', 2),
+	('Spanish', 'Spanish B', 2),
+	('French', 'French B', 2),
+	('German', 'German B', 2),
+	('Hindi', 'Hindi A: Literature', 1),
+	('Art', 'Visual Arts', 6),
+	('Music', 'Music', 6),
+	('Math', 'Mathematics', 5)
+) AS s (code, name, ib_group)
+WHERE subjects.code = s.code;
+
+-- Other names a subject goes by, matched case-insensitively. Stored lowercase.
+CREATE TABLE subject_aliases (
+	alias VARCHAR(64) PRIMARY KEY,
+	code VARCHAR(32) NOT NULL REFERENCES subjects (code) ON UPDATE CASCADE ON DELETE CASCADE
+);
+INSERT INTO subject_aliases (alias, code) VALUES
+	('maths', 'Math'), ('mathematics', 'Math'),
+	('computer science', 'CompSci'),
+	('business management', 'Business'),
+	('visual arts', 'Art'),
+	('english b', 'ESL');
@@ -0,0 +1,15 @@
+-- The Diploma Programme core. Students complete it by being enrolled in a
+-- class of each of these subjects.
+ALTER TABLE subjects ADD COLUMN core BOOLEAN NOT NULL DEFAULT FALSE;
+INSERT INTO subjects (code, name, ib_group, hl, sl, core) VALUES
+	('TOK', 'Theory of Knowledge', NULL, FALSE, FALSE, TRUE),
+	('EE', 'Extended Essay', NULL, FALSE, FALSE, TRUE),
+	('CAS', 'Creativity, Activity, Service', NULL, FALSE, FALSE, TRUE);
+
+-- Usually the group of the subject, but a school may let a class count
+-- towards another, such as a second science instead of a group 6 subject.
+ALTER TABLE classes ADD COLUMN ib_group SMALLINT CHECK (ib_group BETWEEN 1 AND 6);
+UPDATE classes SET ib_group = subjects.ib_group FROM subjects WHERE classes.subject = subjects.code;
+
+-- HL and SL students often share a class, so the level belongs to the enrollment.
+ALTER TABLE enrollments ADD COLUMN level VARCHAR(2) CHECK (level IN ('HL', 'SL'));
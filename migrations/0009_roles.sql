@@ -0,0 +1,49 @@
+-- Permissions are "action" or "action:scope", where the scope is own,
+-- department or all (the default). "*" and "class.*" grant every action, or
+-- every action on classes.
+CREATE TABLE roles (
+	name VARCHAR(32) PRIMARY KEY,
+	description TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE role_permissions (
+	role VARCHAR(32) NOT NULL REFERENCES roles (name) ON UPDATE CASCADE ON DELETE CASCADE,
+	permission VARCHAR(64) NOT NULL,
+	PRIMARY KEY (role, permission)
+);
+
+-- Roles beyond the ones implied by the type of the user: every student has
+-- the student role, every teacher the teacher role and heads of department
+-- the hod role.
+CREATE TABLE user_roles (
+	user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	role VARCHAR(32) NOT NULL REFERENCES roles (name) ON UPDATE CASCADE ON DELETE CASCADE,
+	PRIMARY KEY (user_id, role)
+);
+
+INSERT INTO roles (name, description) VALUES
+	('admin', 'Runs the portal'),
+	('hod', 'Head of a department, given to teachers marked as such'),
+	('teacher', 'Given to every teacher'),
+	('student', 'Given to every student');
+
+INSERT INTO role_permissions (role, permission) VALUES
+	('admin', '*'),
+	('hod', 'user.view:department'),
+	('hod', 'user.edit:department'),
+	('hod', 'class.view:department'),
+	('hod', 'class.edit:department'),
+	('hod', 'subject.edit:department'),
+	('hod', 'resource.view:department'),
+	('hod', 'resource.delete:department'),
+	('teacher', 'subject.view'),
+	('teacher', 'class.view:own'),
+	('teacher', 'class.edit:own'),
+	('teacher', 'resource.view:own'),
+	('teacher', 'resource.create:own'),
+	('teacher', 'resource.delete:own'),
+	('student', 'subject.view'),
+	('student', 'class.view:own'),
+	('student', 'resource.view:own'),
+	('student', 'resource.create:own'),
+	('student', 'resource.delete:own');
@@ -0,0 +1,5 @@
+-- Users are deactivated rather than deleted, keeping their classes and
+-- files. Deactivated users cannot log in.
+ALTER TABLE users
+	ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE,
+	ADD COLUMN deactivated BIGINT;
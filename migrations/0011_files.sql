@@ -0,0 +1,16 @@
+-- Metadata of uploaded files. Their contents live on disk under the upload
+-- root, named by `key`.
+CREATE TABLE files (
+	id SERIAL PRIMARY KEY,
+	owner_id INT NOT NULL REFERENCES users (id),
+	class_id INT REFERENCES classes (id) ON DELETE SET NULL,
+	name VARCHAR(255) NOT NULL,
+	size BIGINT NOT NULL,
+	mime VARCHAR(255) NOT NULL,
+	sha256 CHAR(64) NOT NULL,
+	key VARCHAR(128) NOT NULL UNIQUE,
+	created BIGINT NOT NULL,
+	modified BIGINT NOT NULL
+);
+CREATE INDEX files_owner_id ON files (owner_id);
+CREATE INDEX files_class_id ON files (class_id);
@@ -0,0 +1,35 @@
+-- Files live in folders. Every folder belongs to exactly one space: a class,
+-- the personal space of a user or the department of a subject. Subfolders
+-- carry the space of the folder they are in.
+CREATE TABLE folders (
+	id SERIAL PRIMARY KEY,
+	parent_id INT REFERENCES folders (id) ON DELETE CASCADE,
+	name VARCHAR(255) NOT NULL,
+	class_id INT REFERENCES classes (id) ON DELETE CASCADE,
+	owner_id INT REFERENCES users (id) ON DELETE CASCADE,
+	subject VARCHAR(32) REFERENCES subjects (code) ON UPDATE CASCADE ON DELETE CASCADE,
+	created BIGINT NOT NULL,
+	CHECK ((class_id IS NOT NULL)::INT + (owner_id IS NOT NULL)::INT + (subject IS NOT NULL)::INT = 1)
+);
+CREATE UNIQUE INDEX folders_names ON folders (parent_id, lower(name)) WHERE parent_id IS NOT NULL;
+CREATE UNIQUE INDEX folders_class_roots ON folders (class_id) WHERE parent_id IS NULL AND class_id IS NOT NULL;
+CREATE UNIQUE INDEX folders_personal_roots ON folders (owner_id) WHERE parent_id IS NULL AND owner_id IS NOT NULL;
+CREATE UNIQUE INDEX folders_department_roots ON folders (subject) WHERE parent_id IS NULL AND subject IS NOT NULL;
+
+-- Folders holding files cannot go away with their class, owner or subject;
+-- the files have to be removed first, as their contents live in storage.
+ALTER TABLE files ADD COLUMN folder_id INT REFERENCES folders (id) ON DELETE RESTRICT;
+
+-- Files shared with a class move to the folder of the class, the rest to
+-- the personal folder of their owner.
+INSERT INTO folders (name, class_id, created)
+	SELECT c.subject || ' ' || c.block || ' grade ' || c.grade, c.id, extract(epoch FROM now())::BIGINT
+	FROM classes c WHERE EXISTS (SELECT 1 FROM files f WHERE f.class_id = c.id);
+INSERT INTO folders (name, owner_id, created)
+	SELECT 'Personal', u.id, extract(epoch FROM now())::BIGINT
+	FROM users u WHERE EXISTS (SELECT 1 FROM files f WHERE f.owner_id = u.id AND f.class_id IS NULL);
+UPDATE files f SET folder_id = d.id FROM folders d
+	WHERE d.class_id = f.class_id OR (f.class_id IS NULL AND d.owner_id = f.owner_id);
+
+ALTER TABLE files ALTER COLUMN folder_id SET NOT NULL, DROP COLUMN class_id;
+CREATE INDEX files_folder_id ON files (folder_id);
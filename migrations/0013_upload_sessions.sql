@@ -0,0 +1,18 @@
+-- Resumable uploads that have not been finished yet. Their chunks are
+-- appended to a file named by `key` under `uploads.temp`, `received` bytes
+-- long so far.
+CREATE TABLE upload_sessions (
+	id SERIAL PRIMARY KEY,
+	owner_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	folder_id INT NOT NULL REFERENCES folders (id) ON DELETE CASCADE,
+	name VARCHAR(255) NOT NULL,
+	mime VARCHAR(255) NOT NULL,
+	size BIGINT NOT NULL,
+	received BIGINT NOT NULL DEFAULT 0,
+	sha256 CHAR(64),
+	key VARCHAR(128) NOT NULL UNIQUE,
+	created BIGINT NOT NULL,
+	updated BIGINT NOT NULL
+);
+CREATE INDEX upload_sessions_owner_id ON upload_sessions (owner_id);
+CREATE INDEX upload_sessions_updated ON upload_sessions (updated);
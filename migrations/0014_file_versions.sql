@@ -0,0 +1,23 @@
+-- Every version of every file, the current one included. The current
+-- version's details are also kept in `files`, whose `version` says which one
+-- it is and `next_version` what number the next one gets, so that a number
+-- is never used twice even once the newest versions have been pruned.
+CREATE TABLE file_versions (
+	id SERIAL PRIMARY KEY,
+	file_id INT NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+	number INT NOT NULL,
+	author_id INT REFERENCES users (id) ON DELETE SET NULL,
+	size BIGINT NOT NULL,
+	mime VARCHAR(255) NOT NULL,
+	sha256 CHAR(64) NOT NULL,
+	key VARCHAR(128) NOT NULL UNIQUE,
+	created BIGINT NOT NULL,
+	UNIQUE (file_id, number)
+);
+CREATE INDEX file_versions_created ON file_versions (created);
+
+INSERT INTO file_versions (file_id, number, author_id, size, mime, sha256, key, created)
+	SELECT id, 1, owner_id, size, mime, sha256, key, modified FROM files;
+
+ALTER TABLE files ADD COLUMN version INT NOT NULL DEFAULT 1,
+	ADD COLUMN next_version INT NOT NULL DEFAULT 2;
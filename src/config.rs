@@ -0,0 +1,372 @@
+use rustc_serialize::json::{Json, ParserError};
+use rustc_serialize::hex::FromHex;
+
+use postgres::{SslMode, ConnectParams, ConnectTarget, UserInfo};
+use openssl::ssl::{SslContext, SslMethod};
+use log::LogLevelFilter;
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use session::Timeouts;
+use crypt::PasswordPolicy;
+
+pub const DEFAULT_PATH: &'static str = "indus.json";
+
+/// Environment variable naming the config file to use instead of `DEFAULT_PATH`.
+pub const PATH_VAR: &'static str = "INDUS_CONFIG";
+
+/// Longest `uploads.link_ttl`, in seconds: S3 presigns links for a week at most.
+pub const MAX_LINK_TTL: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug)]
+pub enum ConfigError {
+	Io(PathBuf, io::Error),
+	Parse(PathBuf, ParserError),
+	/// A required key was in neither the file nor the environment.
+	Missing(&'static str, &'static str),
+	Invalid(&'static str, String),
+	/// OpenSSL could not set up a context for `database.ssl`.
+	Ssl(String),
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ConfigError::Io(ref path, ref e) => write!(f, "could not read config file {}: {}", path.display(), e),
+			ConfigError::Parse(ref path, ref e) => write!(f, "config file {} is not valid JSON: {}", path.display(), e),
+			ConfigError::Missing(key, var) => write!(f, "missing required config key `{}` (or environment variable {})", key, var),
+			ConfigError::Invalid(key, ref val) => write!(f, "invalid value {:?} for config key `{}`", val, key),
+			ConfigError::Ssl(ref why) => write!(f, "could not set up SSL for the database: {}", why),
+		}
+	}
+}
+
+impl Error for ConfigError {
+	fn description(&self) -> &str {
+		match *self {
+			ConfigError::Io(..) => "could not read config file",
+			ConfigError::Parse(..) => "config file is not valid JSON",
+			ConfigError::Missing(..) => "missing required config key",
+			ConfigError::Invalid(..) => "invalid config value",
+			ConfigError::Ssl(..) => "could not set up SSL for the database",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ssl {
+	Disable, Prefer, Require
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseConfig {
+	pub host: String,
+	pub port: u16,
+	pub user: String,
+	pub password: Option<String>,
+	pub name: String,
+	pub ssl: Ssl,
+}
+
+impl DatabaseConfig {
+	pub fn connect_params(&self) -> ConnectParams {
+		ConnectParams {
+			target: ConnectTarget::Tcp(self.host.clone()),
+			port: Some(self.port),
+			user: Some(UserInfo {
+				user: self.user.clone(),
+				password: self.password.clone(),
+			}),
+			database: Some(self.name.clone()),
+			options: Vec::new(),
+		}
+	}
+
+	pub fn ssl_mode(&self) -> Result<SslMode, ConfigError> {
+		let context = || SslContext::new(SslMethod::Sslv23).map_err(|e| ConfigError::Ssl(e.to_string()));
+		Ok( match self.ssl {
+			Ssl::Disable => SslMode::None,
+			Ssl::Prefer => SslMode::Prefer(try!(context())),
+			Ssl::Require => SslMode::Require(try!(context())),
+		} )
+	}
+}
+
+/// Where the contents of stored files are kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+	/// On disk under the upload root.
+	Local,
+	/// In a bucket of an S3 compatible service.
+	S3,
+}
+
+impl ::std::str::FromStr for Backend {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Backend, String> {
+		match s {
+			"local" => Ok(Backend::Local),
+			"s3" => Ok(Backend::S3),
+			other => Err(format!("{:?} is not a storage backend", other)),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Config {
+	/// Such as `https://s3.amazonaws.com` or `http://localhost:9000`.
+	pub endpoint: String,
+	pub bucket: String,
+	pub region: String,
+	pub access_key: String,
+	pub secret_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadConfig {
+	pub backend: Backend,
+	/// Where stored files live with the local backend.
+	pub root: PathBuf,
+	/// Where uploads are written before they are complete.
+	pub temp: PathBuf,
+	/// Largest file that may be uploaded, in bytes.
+	pub max_size: u64,
+	/// Set whenever `uploads.s3.bucket` is, whichever backend is in use, so
+	/// that files can be copied there.
+	pub s3: Option<S3Config>,
+	/// The address of this server as users reach it, for links to files.
+	pub url: String,
+	/// Seconds that links to files stay valid, up to `MAX_LINK_TTL`.
+	pub link_ttl: i64,
+	/// Seconds a resumable upload may go without a chunk before it is
+	/// thrown away.
+	pub resume_ttl: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+	pub database: DatabaseConfig,
+	pub pool_size: usize,
+	/// Milliseconds to wait for a free database connection.
+	pub pool_timeout: u64,
+	pub bind: String,
+	pub log_level: LogLevelFilter,
+	pub uploads: UploadConfig,
+	/// Key for signing session cookies. A random one is used when absent.
+	pub session_secret: Option<Vec<u8>>,
+	pub session_timeouts: Timeouts,
+	pub password_policy: PasswordPolicy,
+	/// Seconds a password reset token stays valid.
+	pub password_reset_ttl: i64,
+}
+
+/// Looks a key up in the environment first and then in the config file.
+struct Source {
+	json: Json,
+}
+
+impl Source {
+	fn get(&self, key: &'static str, var: &'static str) -> Option<String> {
+		if let Ok(val) = env::var(var) {
+			return Some(val)
+		}
+		let path = key.split('.').collect::<Vec<_>>();
+		self.json.find_path(&path).and_then(|val| match *val {
+			Json::String(ref s) => Some(s.clone()),
+			Json::I64(n) => Some(n.to_string()),
+			Json::U64(n) => Some(n.to_string()),
+			Json::F64(n) => Some(n.to_string()),
+			Json::Boolean(b) => Some(b.to_string()),
+			_ => None,
+		})
+	}
+
+	fn required(&self, key: &'static str, var: &'static str) -> Result<String, ConfigError> {
+		self.get(key, var).ok_or(ConfigError::Missing(key, var))
+	}
+
+	fn parsed<T: ::std::str::FromStr>(&self, key: &'static str, var: &'static str, default: T) -> Result<T, ConfigError> {
+		match self.get(key, var) {
+			Some(val) => val.parse().map_err(|_| ConfigError::Invalid(key, val)),
+			None => Ok(default),
+		}
+	}
+}
+
+impl Config {
+	/// Loads the file named by `INDUS_CONFIG`, or `indus.json`.
+	pub fn load() -> Result<Config, ConfigError> {
+		let path = env::var(PATH_VAR).map(PathBuf::from).unwrap_or(PathBuf::from(DEFAULT_PATH));
+		Config::from_file(path)
+	}
+
+	/// Loads a config file. A missing file is allowed as long as the
+	/// environment provides every required key.
+	pub fn from_file<P: Into<PathBuf>>(path: P) -> Result<Config, ConfigError> {
+		let path = path.into();
+		let json = match File::open(&path) {
+			Ok(mut f) => {
+				let mut buf = String::new();
+				try!( f.read_to_string(&mut buf).map_err(|e| ConfigError::Io(path.clone(), e)) );
+				try!( Json::from_str(&buf).map_err(|e| ConfigError::Parse(path.clone(), e)) )
+			},
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => Json::Object(Default::default()),
+			Err(e) => return Err(ConfigError::Io(path, e)),
+		};
+		Config::from_json(json)
+	}
+
+	pub fn from_json(json: Json) -> Result<Config, ConfigError> {
+		let src = Source { json: json };
+		let ssl = match &*src.get("database.ssl", "INDUS_DB_SSL").unwrap_or("disable".into()) {
+			"disable" => Ssl::Disable,
+			"prefer" => Ssl::Prefer,
+			"require" => Ssl::Require,
+			other => return Err(ConfigError::Invalid("database.ssl", other.into())),
+		};
+		let secret = match src.get("session.secret", "INDUS_SESSION_SECRET") {
+			Some(s) => Some( try!( s.from_hex().map_err(|_| ConfigError::Invalid("session.secret", s.clone())) ) ),
+			None => None,
+		};
+		let root = PathBuf::from( try!(src.required("uploads.root", "INDUS_UPLOAD_ROOT")) );
+		let s3 = match src.get("uploads.s3.bucket", "INDUS_S3_BUCKET") {
+			Some(bucket) => Some(S3Config {
+				endpoint: try!(src.required("uploads.s3.endpoint", "INDUS_S3_ENDPOINT")),
+				bucket: bucket,
+				region: src.get("uploads.s3.region", "INDUS_S3_REGION").unwrap_or("us-east-1".into()),
+				access_key: try!(src.required("uploads.s3.access_key", "INDUS_S3_ACCESS_KEY")),
+				secret_key: try!(src.required("uploads.s3.secret_key", "INDUS_S3_SECRET_KEY")),
+			}),
+			None => None,
+		};
+		let backend = try!(src.parsed("uploads.backend", "INDUS_UPLOAD_BACKEND", Backend::Local));
+		if backend == Backend::S3 && s3.is_none() {
+			return Err(ConfigError::Missing("uploads.s3.bucket", "INDUS_S3_BUCKET"))
+		}
+		let link_ttl = try!(src.parsed("uploads.link_ttl", "INDUS_UPLOAD_LINK_TTL", 15 * 60));
+		if link_ttl <= 0 || link_ttl > MAX_LINK_TTL {
+			return Err(ConfigError::Invalid("uploads.link_ttl", link_ttl.to_string()))
+		}
+		let bind = src.get("bind", "INDUS_BIND").unwrap_or("localhost:3000".into());
+		let defaults = Timeouts::new();
+		let policy = PasswordPolicy::new();
+		Ok(Config {
+			database: DatabaseConfig {
+				host: try!(src.required("database.host", "INDUS_DB_HOST")),
+				port: try!(src.parsed("database.port", "INDUS_DB_PORT", 5432)),
+				user: try!(src.required("database.user", "INDUS_DB_USER")),
+				password: src.get("database.password", "INDUS_DB_PASSWORD"),
+				name: try!(src.required("database.name", "INDUS_DB_NAME")),
+				ssl: ssl,
+			},
+			pool_size: try!(src.parsed("pool_size", "INDUS_POOL_SIZE", 4)),
+			pool_timeout: try!(src.parsed("pool_timeout", "INDUS_POOL_TIMEOUT", 5000)),
+			bind: bind.clone(),
+			log_level: try!(src.parsed("log_level", "INDUS_LOG_LEVEL", LogLevelFilter::Info)),
+			uploads: UploadConfig {
+				backend: backend,
+				temp: src.get("uploads.temp", "INDUS_UPLOAD_TEMP").map(PathBuf::from).unwrap_or(root.join("tmp")),
+				root: root,
+				max_size: try!(src.parsed("uploads.max_size", "INDUS_UPLOAD_MAX_SIZE", 1 << 30)),
+				s3: s3,
+				url: src.get("uploads.url", "INDUS_UPLOAD_URL").unwrap_or(format!("http://{}", bind)),
+				link_ttl: link_ttl,
+				resume_ttl: try!(src.parsed("uploads.resume_ttl", "INDUS_UPLOAD_RESUME_TTL", 24 * 60 * 60)),
+			},
+			session_secret: secret,
+			session_timeouts: Timeouts {
+				idle: try!(src.parsed("session.idle_timeout", "INDUS_SESSION_IDLE", defaults.idle)),
+				absolute: try!(src.parsed("session.absolute_timeout", "INDUS_SESSION_ABSOLUTE", defaults.absolute)),
+			},
+			password_policy: PasswordPolicy {
+				min_length: try!(src.parsed("password.min_length", "INDUS_PASSWORD_MIN_LENGTH", policy.min_length)),
+				require_letter: try!(src.parsed("password.require_letter", "INDUS_PASSWORD_REQUIRE_LETTER", policy.require_letter)),
+				require_digit: try!(src.parsed("password.require_digit", "INDUS_PASSWORD_REQUIRE_DIGIT", policy.require_digit)),
+				require_symbol: try!(src.parsed("password.require_symbol", "INDUS_PASSWORD_REQUIRE_SYMBOL", policy.require_symbol)),
+			},
+			password_reset_ttl: try!(src.parsed("password.reset_ttl", "INDUS_PASSWORD_RESET_TTL", 24 * 60 * 60)),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rustc_serialize::json::Json;
+	use log::LogLevelFilter;
+
+	fn json(s: &str) -> Json {
+		Json::from_str(s).unwrap()
+	}
+
+	#[test]
+	fn defaults() {
+		let config = Config::from_json(json(r#"{
+			"database": { "host": "db.iisb", "user": "indus", "name": "indus" },
+			"uploads": { "root": "/srv/indus" }
+		}"#)).unwrap();
+		assert_eq!(config.database.port, 5432);
+		assert_eq!(config.database.password, None);
+		assert_eq!(config.database.ssl, Ssl::Disable);
+		assert_eq!(config.log_level, LogLevelFilter::Info);
+		assert_eq!(config.uploads.temp, ::std::path::PathBuf::from("/srv/indus/tmp"));
+		assert_eq!(config.uploads.max_size, 1 << 30);
+		assert_eq!(config.uploads.backend, Backend::Local);
+		assert_eq!(config.uploads.s3, None);
+		assert_eq!(config.uploads.url, "http://localhost:3000");
+		assert_eq!(config.uploads.resume_ttl, 24 * 60 * 60);
+	}
+
+	#[test]
+	fn s3() {
+		let config = Config::from_json(json(r#"{
+			"database": { "host": "db.iisb", "user": "indus", "name": "indus" },
+			"uploads": { "root": "/srv/indus", "backend": "s3", "s3": {
+				"endpoint": "http://localhost:9000", "bucket": "indus", "access_key": "minio", "secret_key": "minio123"
+			} }
+		}"#)).unwrap();
+		assert_eq!(config.uploads.backend, Backend::S3);
+		assert_eq!(config.uploads.s3.unwrap().region, "us-east-1");
+		match Config::from_json(json(r#"{
+			"database": { "host": "db.iisb", "user": "indus", "name": "indus" },
+			"uploads": { "root": "/srv/indus", "backend": "s3" }
+		}"#)) {
+			Err(ConfigError::Missing(key, _)) => assert_eq!(key, "uploads.s3.bucket"),
+			other => panic!("{:?}", other),
+		}
+	}
+
+	#[test]
+	fn missing_key() {
+		match Config::from_json(json(r#"{
+			"database": { "host": "db.iisb", "user": "indus" },
+			"uploads": { "root": "/srv/indus" }
+		}"#)) {
+			Err(ConfigError::Missing(key, _)) => assert_eq!(key, "database.name"),
+			other => panic!("{:?}", other),
+		}
+	}
+
+	#[test]
+	fn invalid_value() {
+		match Config::from_json(json(r#"{
+			"database": { "host": "db.iisb", "user": "indus", "name": "indus", "port": "fivefourthreetwo" },
+			"uploads": { "root": "/srv/indus" }
+		}"#)) {
+			Err(ConfigError::Invalid(key, _)) => assert_eq!(key, "database.port"),
+			other => panic!("{:?}", other),
+		}
+		match Config::from_json(json(r#"{
+			"database": { "host": "db.iisb", "user": "indus", "name": "indus" },
+			"uploads": { "root": "/srv/indus", "link_ttl": 691200 }
+		}"#)) {
+			Err(ConfigError::Invalid(key, _)) => assert_eq!(key, "uploads.link_ttl"),
+			other => panic!("{:?}", other),
+		}
+	}
+}
@@ -1,12 +1,218 @@
 use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use crypto::sha2::Sha256;
+use crypto::pbkdf2::{pbkdf2_simple, pbkdf2_check};
+use crypto::util::fixed_time_eq;
 
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::ToHex;
+use rand::{OsRng, Rng};
+
+use error::{IndusError, IndusResult};
+
+/// PBKDF2-HMAC-SHA256 iterations used for new hashes. Stored hashes record
+/// their own count, so raising this only affects hashes made afterwards.
+pub const ROUNDS: u32 = 20000;
+
+const PREFIX: &'static str = "$rpbkdf2$0$";
+
+/// Hashes a password into a self-describing, salted PBKDF2 string.
+#[inline]
 pub fn encrypt(pass: &str) -> String {
+	encrypt_with(pass, ROUNDS)
+}
+
+pub fn encrypt_with(pass: &str, rounds: u32) -> String {
+	pbkdf2_simple(pass, rounds).unwrap()
+}
+
+/// Unsalted SHA-256, as stored by earlier versions.
+fn legacy(pass: &str) -> String {
 	let mut hasher = Sha256::new();
 	hasher.input_str(pass);
 	hasher.result_str()
 }
 
+#[inline]
+fn is_legacy(hash: &str) -> bool {
+	!hash.starts_with(PREFIX)
+}
+
+/// The iteration count recorded in a PBKDF2 hash.
+fn rounds(hash: &str) -> Option<u32> {
+	if is_legacy(hash) {
+		return None
+	}
+	hash[PREFIX.len()..].split('$').next()
+		.and_then(|r| r.from_base64().ok())
+		.and_then(|r| if r.len() == 4 {
+			Some( (r[0] as u32) << 24 | (r[1] as u32) << 16 | (r[2] as u32) << 8 | r[3] as u32 )
+		} else { None })
+}
+
+/// Checks `input` against a stored hash in constant time. Both PBKDF2 and
+/// legacy SHA-256 hashes are accepted.
 pub fn check(input: &str, pass: &str) -> bool {
-	&encrypt(input) == pass
-}
\ No newline at end of file
+	if is_legacy(pass) {
+		fixed_time_eq(legacy(input).as_bytes(), pass.as_bytes())
+	} else {
+		pbkdf2_check(input, pass).unwrap_or(false)
+	}
+}
+
+/// Whether a stored hash is weaker than what `encrypt` would produce now.
+pub fn needs_rehash(pass: &str) -> bool {
+	rounds(pass).map(|r| r < ROUNDS).unwrap_or(true)
+}
+
+/// 32 random bytes from the OS, hex encoded. Used for session and reset tokens.
+pub fn random_token() -> String {
+	let mut bytes = [0u8; 32];
+	OsRng::new().unwrap().fill_bytes(&mut bytes);
+	bytes.to_hex()
+}
+
+/// 32 random bytes from the OS, for signing keys that need not survive a
+/// restart.
+pub fn random_secret() -> Vec<u8> {
+	let mut secret = vec![0u8; 32];
+	OsRng::new().unwrap().fill_bytes(&mut secret);
+	secret
+}
+
+/// A key for one `purpose`, derived from a configured secret so that the
+/// secret itself only ever signs one kind of thing.
+pub fn derive_key(secret: &[u8], purpose: &str) -> Vec<u8> {
+	let mut mac = Hmac::new(Sha256::new(), secret);
+	mac.input(purpose.as_bytes());
+	mac.result().code().to_vec()
+}
+
+/// A password for a new account, to be handed out and changed after the
+/// first login. It follows `policy` and leaves out characters that are easy
+/// to mistake for each other, such as `l`, `1` and `O`.
+pub fn random_password(policy: &PasswordPolicy) -> String {
+	const LETTERS: &'static [u8] = b"abcdefghjkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ";
+	const DIGITS: &'static [u8] = b"23456789";
+	const SYMBOLS: &'static [u8] = b"!#%+-=?@";
+	let mut chars = LETTERS.to_vec();
+	chars.extend(DIGITS.iter().cloned());
+	if policy.require_symbol {
+		chars.extend(SYMBOLS.iter().cloned());
+	}
+	let len = if policy.min_length > 12 { policy.min_length } else { 12 };
+	let mut rng = OsRng::new().unwrap();
+	loop {
+		let pass = (0..len).map(|_| chars[rng.gen_range(0, chars.len())] as char).collect::<String>();
+		if policy.check(&pass).is_ok() {
+			return pass
+		}
+	}
+}
+
+/// How tokens are stored, so that a leaked table can't be used to log in.
+#[inline]
+pub fn hash_token(token: &str) -> String {
+	legacy(token)
+}
+
+/// Rules a password must follow when a user picks it themselves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PasswordPolicy {
+	pub min_length: usize,
+	pub require_letter: bool,
+	pub require_digit: bool,
+	/// Require something that is neither a letter nor a digit.
+	pub require_symbol: bool,
+}
+
+impl PasswordPolicy {
+	#[inline] pub fn new() -> PasswordPolicy {
+		PasswordPolicy {
+			min_length: 8, require_letter: true, require_digit: true, require_symbol: false,
+		}
+	}
+
+	pub fn check(&self, pass: &str) -> IndusResult<()> {
+		let fail = |why: String| Err(IndusError::Validation(why));
+		if pass.chars().count() < self.min_length {
+			return fail(format!("password must be at least {} characters long", self.min_length))
+		}
+		if self.require_letter && !pass.chars().any(char::is_alphabetic) {
+			return fail("password must contain a letter".into())
+		}
+		if self.require_digit && !pass.chars().any(char::is_numeric) {
+			return fail("password must contain a digit".into())
+		}
+		if self.require_symbol && pass.chars().all(char::is_alphanumeric) {
+			return fail("password must contain a symbol".into())
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn salted() {
+		let a = encrypt("2cool4uuu");
+		let b = encrypt("2cool4uuu");
+		assert!(a != b);
+		assert!(check("2cool4uuu", &a));
+		assert!(check("2cool4uuu", &b));
+		assert!(!check("2cool4uu", &a));
+		assert!(!needs_rehash(&a));
+	}
+
+	#[test]
+	fn legacy_hashes() {
+		// sha256("abc")
+		let old = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+		assert!(needs_rehash(old));
+		assert!(check("abc", old));
+		assert!(!check("abd", old));
+	}
+
+	#[test]
+	fn weak_rounds() {
+		assert!(needs_rehash(&encrypt_with("killthelion", ROUNDS / 2)));
+		assert!(check("killthelion", &encrypt_with("killthelion", ROUNDS / 2)));
+	}
+
+	#[test]
+	fn derived_keys() {
+		let a = derive_key(b"secret", "file-links");
+		assert_eq!(a.len(), 32);
+		assert_eq!(a, derive_key(b"secret", "file-links"));
+		assert!(a != derive_key(b"secret", "sessions"));
+		assert!(a != derive_key(b"secreT", "file-links"));
+	}
+
+	#[test]
+	fn policy() {
+		let policy = PasswordPolicy::new();
+		assert!(policy.check("2cool4uuu").is_ok());
+		assert!(policy.check("2cool4u").is_err());
+		assert!(policy.check("killthelion").is_err());
+		assert!(policy.check("123456789").is_err());
+		let strict = PasswordPolicy { require_symbol: true, ..policy };
+		assert!(strict.check("2cool4uuu").is_err());
+		assert!(strict.check("2cool4uuu!").is_ok());
+	}
+
+	#[test]
+	fn random_passwords() {
+		let policy = PasswordPolicy::new();
+		let strict = PasswordPolicy { min_length: 16, require_symbol: true, ..policy };
+		for _ in 0..20 {
+			assert!(policy.check(&random_password(&policy)).is_ok());
+			assert!(strict.check(&random_password(&strict)).is_ok());
+		}
+		assert_eq!(random_password(&strict).len(), 16);
+		assert!(!random_password(&policy).contains('l'));
+		assert!(random_password(&policy) != random_password(&policy));
+	}
+}
@@ -1,8 +1,12 @@
+use std::error::Error;
+use std::fmt;
 use std::str::FromStr;
 
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+
 #[derive(Clone, PartialEq, Eq, Debug, RustcEncodable, RustcDecodable)]
 pub struct Counts {
-	pub usrcnt: i32, stdcnt: i32, tchcnt: i32,
+	pub usrcnt: i32, pub stdcnt: i32, pub tchcnt: i32,
 }
 
 impl Counts {
@@ -16,16 +20,6 @@ impl Counts {
 			usrcnt: u, stdcnt: s, tchcnt: t,
 		}
 	}
-	pub fn incr_students(&mut self) {
-		self.usrcnt += 1;
-		self.stdcnt += 1;
-		println!("{:?}", self);
-	}
-	pub fn incr_teachers(&mut self) {
-		self.usrcnt += 1;
-		self.tchcnt += 1;
-		println!("{:?}", self);
-	}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, RustcEncodable, RustcDecodable)]
@@ -72,172 +66,668 @@ pub struct IndusTeacher {
 	pub hod: bool
 }
 
-#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
-pub enum Subject {
-	Economics, Business,
-	Physics, Chemistry, Biology,
-	CompSci, ICT, ITGS,
-	Psychology,
-	English, ESL,
-	Spanish, French, German, Hindi,
-	Art, Music
-}
+/// A handle on a subject of the catalogue in the `subjects` table, by code.
+///
+/// Handles from `subjects::resolve` or read from the database always name a
+/// subject that exists. Ones parsed from text only have a well-formed code,
+/// and are resolved when a class using them is stored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Subject(String);
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct InvalidSubject;
 
+/// Longest code or alias of a subject.
+pub const SUBJECT_MAX_LEN: usize = 64;
+
+impl Subject {
+	#[inline] pub fn code(&self) -> &str {
+		&self.0
+	}
+
+	#[inline] pub fn tostr(self) -> String {
+		self.0
+	}
+}
+
 impl FromStr for Subject {
 	type Err = InvalidSubject;
+	/// Accepts any code or alias that could be in the catalogue: non-empty,
+	/// without surrounding whitespace and at most `SUBJECT_MAX_LEN` characters.
 	fn from_str(s: &str) -> Result<Subject, InvalidSubject> {
-		Ok( match s {
-			"Economics" => Subject::Economics,
-			"Business" => Subject::Business,
-			"Physics" => Subject::Physics,
-			"Chemistry" => Subject::Chemistry,
-			"Biology" => Subject::Biology,
-			"CompSci" => Subject::CompSci,
-			"ICT" => Subject::ICT,
-			"ITGS" => Subject::ITGS,
-			"Psychology" => Subject::Psychology,
-			"English" => Subject::English,
-			"ESL" => Subject::ESL,
-			"Spanish" => Subject::Spanish,
-			"French" => Subject::French,
-			"German" => Subject::German,
-			"Hindi" => Subject::Hindi,
-			"Art" => Subject::Art,
-			"Music" => Subject::Music,
-			_ => return Err(InvalidSubject)
-		} )
+		if s.is_empty() || s.trim() != s || s.chars().count() > SUBJECT_MAX_LEN {
+			return Err(InvalidSubject)
+		}
+		Ok(Subject(s.into()))
 	}
 }
 
 impl Into<String> for Subject {
-	fn into(self) -> String {
-		match self {
-			Subject::Economics => "Economics",
-			Subject::Business => "Business",
-			Subject::Physics => "Physics",
-			Subject::Chemistry => "Chemistry",
-			Subject::Biology => "Biology",
-			Subject::CompSci => "CompSci",
-			Subject::ICT => "ICT",
-			Subject::ITGS => "ITGS",
-			Subject::Psychology => "Psychology",
-			Subject::English => "English",
-			Subject::ESL => "ESL",
-			Subject::Spanish => "Spanish",
-			Subject::French => "French",
-			Subject::German => "German",
-			Subject::Hindi => "Hindi",
-			Subject::Art => "Art",
-			Subject::Music => "Music",
-		}.into()
+	#[inline] fn into(self) -> String {
+		self.0
 	}
 }
 
-impl Subject {
-	#[inline] fn tostr(self) -> String {
-		Into::<String>::into(self)
+impl fmt::Display for Subject {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
 
-#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+impl Encodable for Subject {
+	fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+		s.emit_str(&self.0)
+	}
+}
+
+impl Decodable for Subject {
+	fn decode<D: Decoder>(d: &mut D) -> Result<Subject, D::Error> {
+		let code = try!(d.read_str());
+		code.parse().map_err(|_| d.error(&format!("invalid subject {:?}", code)))
+	}
+}
+
+/// The level a Diploma Programme course is taken at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum Level {
+	HL, SL
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidLevel;
+
+impl FromStr for Level {
+	type Err = InvalidLevel;
+	fn from_str(s: &str) -> Result<Level, InvalidLevel> {
+		match s {
+			"HL" | "hl" => Ok(Level::HL),
+			"SL" | "sl" => Ok(Level::SL),
+			_ => Err(InvalidLevel),
+		}
+	}
+}
+
+impl fmt::Display for Level {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
+			Level::HL => "HL",
+			Level::SL => "SL",
+		})
+	}
+}
+
+/// Version of the text and JSON encodings of `Class`. Bump it whenever a
+/// field is added, and keep reading the older versions.
+pub const CLASS_FORMAT: u32 = 2;
+
+/// A class a student takes or a teacher teaches.
+///
+/// Its text form is `v2|block|subject|level|group|grade|teacher`, where the
+/// level and group may be empty and `\`, `|` and `,` inside the block or
+/// teacher are escaped with a backslash. Version 1, which is read but no
+/// longer written, is `v1|block|subject|grade|teacher`. Lists of classes are
+/// separated by commas, see `Classes`.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Class {
-	pub subject: Subject, pub teacher: String, pub block: String, pub grade: i16
+	pub subject: Subject, pub teacher: String, pub block: String, pub grade: i16,
+	/// The level the student takes the class at; `None` outside the Diploma
+	/// Programme and for teachers.
+	pub level: Option<Level>,
+	/// The IB group the class counts towards, usually that of its subject.
+	pub group: Option<i16>,
 }
 
-pub struct InvalidClass;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClassError {
+	/// The entry doesn't start with a `v<number>` version.
+	Version,
+	UnsupportedVersion(u32),
+	/// A field, named here, is missing.
+	Missing(&'static str),
+	EmptyBlock,
+	InvalidSubject,
+	InvalidLevel,
+	/// The group is not a number from 1 to 6.
+	InvalidGroup,
+	InvalidGrade,
+	/// A backslash followed by something that needs no escaping.
+	InvalidEscape,
+	/// Something follows a complete class.
+	Trailing,
+}
 
-pub struct Classes;
-impl Classes {
-	pub fn from_student(grade: i16, src: &str) -> Vec<Class> {
-		src.split(",").flat_map(|s| {
-			let mut values = s.split("-").map(str::trim);
-			Some( Class {
-				block: match values.next() {
-					Some(s) => s.into(),
-					None => return None
-				},
-				subject: match values.next() {
-					Some(s) => match s.parse() {
-						Ok(o) => o,
-						Err(_) => return None,
-					},
-					None => return None
-				},
-				teacher: match values.next() {
-					Some(s) => s.into(),
-					None => return None
-				},
-				grade: grade,
-			} )
-		}).collect()
+/// Why a class didn't parse, and the byte offset at which it went wrong.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidClass {
+	pub pos: usize,
+	pub kind: ClassError,
+}
+
+impl fmt::Display for InvalidClass {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(match self.kind {
+			ClassError::Version => write!(f, "expected a version such as `v{}`", CLASS_FORMAT),
+			ClassError::UnsupportedVersion(v) => write!(f, "unsupported class format version {}", v),
+			ClassError::Missing(field) => write!(f, "missing {}", field),
+			ClassError::EmptyBlock => write!(f, "empty block"),
+			ClassError::InvalidSubject => write!(f, "invalid subject"),
+			ClassError::InvalidLevel => write!(f, "level is neither HL nor SL"),
+			ClassError::InvalidGroup => write!(f, "group is not a number from 1 to 6"),
+			ClassError::InvalidGrade => write!(f, "grade is not a number"),
+			ClassError::InvalidEscape => write!(f, "only `\\\\`, `\\|` and `\\,` may be escaped"),
+			ClassError::Trailing => write!(f, "unexpected text after class"),
+		});
+		write!(f, " at byte {}", self.pos)
 	}
+}
 
-	pub fn to_student(from: Vec<Class>) -> String {
-		from.iter()
-			.map(|elem| format!("{} - {} - {}", elem.block, elem.subject.clone().tostr(), elem.teacher))
-			.collect::<Vec<_>>()
-			.connect(", ")
-	}
-
-	pub fn from_teacher(teacher: &str, subject: &str, src: &str) 
-		-> Vec<Class> {
-		src.split(",").flat_map(|s| {
-			let mut values = s.split("-").map(str::trim);
-			Some( Class {
-				block: match values.next() {
-					Some(s) => s.into(),
-					None => return None
-				},
-				subject: match subject.parse() {
-					Ok(o) => o,
-					Err(_) => return None,
-				},
-				teacher: teacher.into(),
-				grade: match values.next() {
-					Some(s) => match s.parse() {
-						Ok(o) => o,
-						Err(_) => return None,
-					},
-					None => return None
-				},
-			} )
-		}).collect()
+impl Error for InvalidClass {
+	fn description(&self) -> &str {
+		"invalid class"
+	}
+}
+
+fn write_escaped(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+	for c in s.chars() {
+		if c == '\\' || c == '|' || c == ',' {
+			try!(write!(f, "\\"));
+		}
+		try!(write!(f, "{}", c));
+	}
+	Ok(())
+}
+
+impl fmt::Display for Class {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f, "v{}|", CLASS_FORMAT));
+		try!(write_escaped(f, &self.block));
+		try!(write!(f, "|{}|", self.subject));
+		if let Some(level) = self.level {
+			try!(write!(f, "{}", level));
+		}
+		try!(write!(f, "|"));
+		if let Some(group) = self.group {
+			try!(write!(f, "{}", group));
+		}
+		try!(write!(f, "|{}|", self.grade));
+		write_escaped(f, &self.teacher)
+	}
+}
+
+struct Parser<'a> {
+	src: &'a str,
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	#[inline]
+	fn fail<T>(pos: usize, kind: ClassError) -> Result<T, InvalidClass> {
+		Err(InvalidClass { pos: pos, kind: kind })
 	}
 
-	pub fn to_teacher(from: Vec<Class>) -> String {
-		from.iter().map(|elem| format!("{} {}", elem.block, elem.grade)).collect::<Vec<_>>().connect(", ")
+	#[inline]
+	fn peek(&self) -> Option<char> {
+		self.src[self.pos..].chars().next()
+	}
+
+	fn skip_whitespace(&mut self) {
+		while let Some(c) = self.peek() {
+			if !c.is_whitespace() {
+				break
+			}
+			self.pos += c.len_utf8();
+		}
+	}
+
+	/// Reads up to the next unescaped `|` or `,`, returning where the field
+	/// started and its unescaped contents.
+	fn field(&mut self) -> Result<(usize, String), InvalidClass> {
+		let start = self.pos;
+		let mut out = String::new();
+		let mut escaped = false;
+		for (i, c) in self.src[start..].char_indices() {
+			if escaped {
+				if c != '\\' && c != '|' && c != ',' {
+					return Parser::fail(start + i - 1, ClassError::InvalidEscape)
+				}
+				out.push(c);
+				escaped = false;
+			} else if c == '\\' {
+				escaped = true;
+			} else if c == '|' || c == ',' {
+				self.pos = start + i;
+				return Ok((start, out))
+			} else {
+				out.push(c);
+			}
+		}
+		if escaped {
+			return Parser::fail(self.src.len() - 1, ClassError::InvalidEscape)
+		}
+		self.pos = self.src.len();
+		Ok((start, out))
+	}
+
+	fn separator(&mut self, next: &'static str) -> Result<(), InvalidClass> {
+		if self.peek() == Some('|') {
+			self.pos += 1;
+			Ok(())
+		} else {
+			Parser::fail(self.pos, ClassError::Missing(next))
+		}
+	}
+
+	fn version(&mut self) -> Result<u32, InvalidClass> {
+		if self.peek() != Some('v') {
+			return Parser::fail(self.pos, ClassError::Version)
+		}
+		let start = self.pos + 1;
+		let digits = self.src[start..].chars().take_while(|c| c.is_digit(10)).count();
+		self.pos = start + digits;
+		match self.src[start..self.pos].parse() {
+			Ok(v) if v >= 1 && v <= CLASS_FORMAT => Ok(v),
+			Ok(v) => Parser::fail(start, ClassError::UnsupportedVersion(v)),
+			Err(_) => Parser::fail(start, ClassError::Version),
+		}
+	}
+
+	fn class(&mut self) -> Result<Class, InvalidClass> {
+		let version = try!(self.version());
+		try!(self.separator("block"));
+		let (pos, block) = try!(self.field());
+		if block.is_empty() {
+			return Parser::fail(pos, ClassError::EmptyBlock)
+		}
+		try!(self.separator("subject"));
+		let (pos, subject) = try!(self.field());
+		let subject = match subject.parse() {
+			Ok(s) => s,
+			Err(_) => return Parser::fail(pos, ClassError::InvalidSubject),
+		};
+		let (mut level, mut group) = (None, None);
+		if version >= 2 {
+			try!(self.separator("level"));
+			let (pos, text) = try!(self.field());
+			if !text.is_empty() {
+				level = match text.parse() {
+					Ok(l) => Some(l),
+					Err(_) => return Parser::fail(pos, ClassError::InvalidLevel),
+				};
+			}
+			try!(self.separator("group"));
+			let (pos, text) = try!(self.field());
+			if !text.is_empty() {
+				group = match text.parse() {
+					Ok(g) if g >= 1 && g <= 6 => Some(g),
+					_ => return Parser::fail(pos, ClassError::InvalidGroup),
+				};
+			}
+		}
+		try!(self.separator("grade"));
+		let (pos, grade) = try!(self.field());
+		let grade = match grade.parse() {
+			Ok(g) => g,
+			Err(_) => return Parser::fail(pos, ClassError::InvalidGrade),
+		};
+		try!(self.separator("teacher"));
+		let (_, teacher) = try!(self.field());
+		Ok( Class {
+			subject: subject, teacher: teacher, block: block, grade: grade, level: level, group: group,
+		} )
 	}
 }
 
 impl FromStr for Class {
 	type Err = InvalidClass;
 	fn from_str(s: &str) -> Result<Class, InvalidClass> {
-		let mut values = s.split("-").map(str::trim);
-		Ok( Class {
-			subject: match values.next() {
-				Some(s) => match s.parse() {
-					Ok(o) => o,
-					Err(_) => return Err(InvalidClass),
-				},
-				None => return Err(InvalidClass)
-			},
-			teacher: match values.next() {
-				Some(s) => s.into(),
-				None => return Err(InvalidClass)
-			},
-			block: match values.next() {
-				Some(s) => s.into(),
-				None => return Err(InvalidClass)
+		let mut parser = Parser { src: s, pos: 0 };
+		let class = try!(parser.class());
+		if parser.pos < s.len() {
+			return Parser::fail(parser.pos, ClassError::Trailing)
+		}
+		Ok(class)
+	}
+}
+
+impl Encodable for Class {
+	fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+		s.emit_struct("Class", 7, |s| {
+			try!(s.emit_struct_field("version", 0, |s| s.emit_u32(CLASS_FORMAT)));
+			try!(s.emit_struct_field("block", 1, |s| self.block.encode(s)));
+			try!(s.emit_struct_field("subject", 2, |s| self.subject.encode(s)));
+			try!(s.emit_struct_field("level", 3, |s| self.level.encode(s)));
+			try!(s.emit_struct_field("group", 4, |s| self.group.encode(s)));
+			try!(s.emit_struct_field("grade", 5, |s| self.grade.encode(s)));
+			s.emit_struct_field("teacher", 6, |s| self.teacher.encode(s))
+		})
+	}
+}
+
+impl Decodable for Class {
+	/// Reads any supported version; a missing `version` is taken to be 1.
+	/// Version 1 has no level or group.
+	fn decode<D: Decoder>(d: &mut D) -> Result<Class, D::Error> {
+		d.read_struct("Class", 7, |d| {
+			let version: Option<u32> = try!(d.read_struct_field("version", 0, Decodable::decode));
+			let version = version.unwrap_or(1);
+			if version < 1 || version > CLASS_FORMAT {
+				return Err(d.error(&format!("unsupported class format version {}", version)))
+			}
+			let (level, group): (Option<Level>, Option<i16>) = if version >= 2 {
+				(try!(d.read_struct_field("level", 3, Decodable::decode)),
+				 try!(d.read_struct_field("group", 4, Decodable::decode)))
+			} else {
+				(None, None)
+			};
+			match group {
+				Some(g) if g < 1 || g > 6 => return Err(d.error(&format!("there is no IB group {}", g))),
+				_ => {}
+			}
+			Ok( Class {
+				block: try!(d.read_struct_field("block", 1, Decodable::decode)),
+				subject: try!(d.read_struct_field("subject", 2, Decodable::decode)),
+				grade: try!(d.read_struct_field("grade", 5, Decodable::decode)),
+				teacher: try!(d.read_struct_field("teacher", 6, Decodable::decode)),
+				level: level,
+				group: group,
+			} )
+		})
+	}
+}
+
+pub struct Classes;
+impl Classes {
+	/// Parses a comma separated list of classes in the text form of `Class`.
+	/// Error positions are relative to the whole list.
+	pub fn parse(src: &str) -> Result<Vec<Class>, InvalidClass> {
+		let mut parser = Parser { src: src, pos: 0 };
+		let mut classes = Vec::new();
+		parser.skip_whitespace();
+		while parser.pos < src.len() {
+			classes.push(try!(parser.class()));
+			match parser.peek() {
+				Some(',') => parser.pos += 1,
+				None => break,
+				Some(_) => return Parser::fail(parser.pos, ClassError::Trailing),
+			}
+			parser.skip_whitespace();
+			if parser.pos == src.len() {
+				return Parser::fail(parser.pos, ClassError::Missing("class"))
+			}
+		}
+		Ok(classes)
+	}
+
+	/// The inverse of `parse`.
+	pub fn format(classes: &[Class]) -> String {
+		classes.iter().map(|class| class.to_string()).collect::<Vec<_>>().connect(", ")
+	}
+
+	/// Whether a list is in the current text form rather than one of the
+	/// formats of the old `classes` columns.
+	pub fn is_canonical(src: &str) -> bool {
+		let mut chars = src.trim_left().chars();
+		chars.next() == Some('v') && chars.next().map(|c| c.is_digit(10)).unwrap_or(false)
+	}
+
+	/// Splits an old-style entry on dashes, keeping where each part starts.
+	fn legacy_fields(s: &str) -> Vec<(usize, &str)> {
+		let mut start = 0;
+		s.split("-").map(|part| {
+			let trimmed = part.trim_left();
+			let pos = start + part.len() - trimmed.len();
+			start += part.len() + 1;
+			(pos, trimmed.trim_right())
+		}).collect()
+	}
+
+	/// Splits a trailing ` HL` or ` SL` off a subject.
+	fn split_level(s: &str) -> (&str, Option<Level>) {
+		match s.rfind(char::is_whitespace) {
+			Some(i) => match s[i + 1..].parse() {
+				Ok(level) => (s[..i].trim_right(), Some(level)),
+				Err(_) => (s, None),
 			},
-			grade: match values.next() {
-				Some(s) => match s.parse() {
-					Ok(o) => o,
-					Err(_) => return Err(InvalidClass),
-				},
-				None => return Err(InvalidClass)
+			None => (s, None),
+		}
+	}
+
+	/// Splits an entry on whitespace into its block and the rest, with the
+	/// position of the rest.
+	fn split_block(s: &str) -> Result<(&str, usize, &str), InvalidClass> {
+		let start = s.len() - s.trim_left().len();
+		let rest = &s[start..];
+		let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+		if end == 0 {
+			return Parser::fail(start, ClassError::EmptyBlock)
+		}
+		let after = &rest[end..];
+		Ok( (&rest[..end], start + end + after.len() - after.trim_left().len(), after.trim()) )
+	}
+
+	/// Parses one entry of a student's old `classes` column, either
+	/// `block - subject - teacher` or `block subject`, where the subject may
+	/// be followed by its level as in `C3 Math HL`.
+	pub fn student_entry(grade: i16, s: &str) -> Result<Class, InvalidClass> {
+		let fields = Classes::legacy_fields(s);
+		let (block, pos, subject, teacher) = if fields.len() == 1 {
+			let (block, pos, subject) = try!(Classes::split_block(s));
+			(block, pos, subject, "")
+		} else {
+			let (_, block) = fields[0];
+			if block.is_empty() {
+				return Parser::fail(0, ClassError::EmptyBlock)
+			}
+			let (pos, subject) = fields[1];
+			let teacher = match fields.get(2) {
+				Some(&(start, _)) => s[start..].trim(),
+				None => return Parser::fail(s.len(), ClassError::Missing("teacher")),
+			};
+			(block, pos, subject, teacher)
+		};
+		let (subject, level) = Classes::split_level(subject);
+		if subject.is_empty() {
+			return Parser::fail(pos, if pos == s.len() { ClassError::Missing("subject") } else { ClassError::InvalidSubject })
+		}
+		let subject = match subject.parse() {
+			Ok(o) => o,
+			Err(_) => return Parser::fail(pos, ClassError::InvalidSubject),
+		};
+		Ok( Class {
+			block: block.into(), subject: subject, teacher: teacher.into(), grade: grade, level: level, group: None,
+		} )
+	}
+
+	/// Parses one `block - grade` or `block grade` entry of a teacher's old
+	/// `classes` column.
+	pub fn teacher_entry(teacher: &str, subject: &str, s: &str) -> Result<Class, InvalidClass> {
+		let fields = Classes::legacy_fields(s);
+		let (block, grade) = if fields.len() == 1 {
+			let (block, pos, grade) = try!(Classes::split_block(s));
+			(block, if grade.is_empty() { None } else { Some((pos, grade)) })
+		} else {
+			let (_, block) = fields[0];
+			if block.is_empty() {
+				return Parser::fail(0, ClassError::EmptyBlock)
+			}
+			(block, Some(fields[1]))
+		};
+		let subject = match subject.parse() {
+			Ok(o) => o,
+			Err(_) => return Parser::fail(0, ClassError::InvalidSubject),
+		};
+		let grade = match grade {
+			Some((pos, g)) => match g.parse() {
+				Ok(o) => o,
+				Err(_) => return Parser::fail(pos, ClassError::InvalidGrade),
 			},
+			None => return Parser::fail(s.len(), ClassError::Missing("grade")),
+		};
+		Ok( Class {
+			block: block.into(), subject: subject, teacher: teacher.into(), grade: grade, level: None, group: None,
 		} )
 	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use quickcheck::{quickcheck, Arbitrary, Gen};
+	use rustc_serialize::json;
+
+	const SUBJECTS: &'static [&'static str] = &[
+		"Economics", "Business", "Physics", "Chemistry", "Biology", "CompSci", "ICT", "ITGS",
+		"Psychology", "English", "ESL", "Spanish", "French", "German", "Hindi", "Art", "Music",
+	];
+
+	impl Arbitrary for Subject {
+		fn arbitrary<G: Gen>(g: &mut G) -> Subject {
+			g.choose(SUBJECTS).unwrap().parse().unwrap()
+		}
+	}
+
+	impl Arbitrary for Level {
+		fn arbitrary<G: Gen>(g: &mut G) -> Level {
+			if g.gen() { Level::HL } else { Level::SL }
+		}
+	}
+
+	impl Arbitrary for Class {
+		fn arbitrary<G: Gen>(g: &mut G) -> Class {
+			let block = String::arbitrary(g);
+			Class {
+				subject: Subject::arbitrary(g),
+				teacher: String::arbitrary(g),
+				block: if block.is_empty() { "C1".into() } else { block },
+				grade: i16::arbitrary(g),
+				level: Option::arbitrary(g),
+				group: if g.gen() { Some(g.gen_range(1, 7)) } else { None },
+			}
+		}
+	}
+
+	fn class(block: &str, teacher: &str) -> Class {
+		Class {
+			subject: "Economics".parse().unwrap(), teacher: teacher.into(), block: block.into(), grade: 11,
+			level: None, group: None,
+		}
+	}
+
+	fn hl(mut class: Class) -> Class {
+		class.level = Some(Level::HL);
+		class.group = Some(3);
+		class
+	}
+
+	#[test]
+	fn text() {
+		assert_eq!( class("C4", "Hari Prasad").to_string(), "v2|C4|Economics|||11|Hari Prasad" );
+		assert_eq!( hl(class("C4", "")).to_string(), "v2|C4|Economics|HL|3|11|" );
+		assert_eq!( class("C|4", "Prasad, Hari").to_string(), "v2|C\\|4|Economics|||11|Prasad\\, Hari" );
+		assert_eq!( "v1|C4|Economics|11|".parse(), Ok(class("C4", "")) );
+		assert_eq!( "v2|C4|Economics|HL|3|11|".parse(), Ok(hl(class("C4", ""))) );
+	}
+
+	#[test]
+	fn error_positions() {
+		fn error(s: &str) -> InvalidClass {
+			s.parse::<Class>().unwrap_err()
+		}
+		assert_eq!( error("C4|Economics"), InvalidClass { pos: 0, kind: ClassError::Version } );
+		assert_eq!( error("v3|C4|Economics|11|"), InvalidClass { pos: 1, kind: ClassError::UnsupportedVersion(3) } );
+		assert_eq!( error("v2|C4|Economics|XL||11|"), InvalidClass { pos: 16, kind: ClassError::InvalidLevel } );
+		assert_eq!( error("v2|C4|Economics|HL|7|11|"), InvalidClass { pos: 19, kind: ClassError::InvalidGroup } );
+		assert_eq!( error("v2|C4|Economics|11|"), InvalidClass { pos: 16, kind: ClassError::InvalidLevel } );
+		assert_eq!( error("v1|C4||11|"), InvalidClass { pos: 6, kind: ClassError::InvalidSubject } );
+		assert_eq!( error("v1|C4| Economics|11|"), InvalidClass { pos: 6, kind: ClassError::InvalidSubject } );
+		assert_eq!( error("v1|C4|Economics|XI|"), InvalidClass { pos: 16, kind: ClassError::InvalidGrade } );
+		assert_eq!( error("v1|C4|Economics|11"), InvalidClass { pos: 18, kind: ClassError::Missing("teacher") } );
+		assert_eq!( error("v1||Economics|11|"), InvalidClass { pos: 3, kind: ClassError::EmptyBlock } );
+		assert_eq!( error("v1|C\\4|Economics|11|"), InvalidClass { pos: 4, kind: ClassError::InvalidEscape } );
+		assert_eq!( error("v1|C4|Economics|11|x|y"), InvalidClass { pos: 20, kind: ClassError::Trailing } );
+		assert_eq!(
+			Classes::parse("v1|C4|Economics|11|, v1|C5|Art|11"),
+			Err(InvalidClass { pos: 33, kind: ClassError::Missing("teacher") })
+		);
+	}
+
+	#[test]
+	fn lists() {
+		assert_eq!( Classes::parse("  "), Ok(vec![]) );
+		assert_eq!( Classes::format(&[]), "" );
+		assert_eq!(
+			Classes::parse("v1|C4|Economics|11|Hari Prasad, v1|C5|Economics|11|"),
+			Ok(vec![class("C4", "Hari Prasad"), class("C5", "")])
+		);
+		assert!( Classes::parse("v1|C4|Economics|11|,").is_err() );
+		assert!( Classes::is_canonical(" v1|C4|Economics|11|") );
+		assert!( !Classes::is_canonical("C1 - English - Jane Doe") );
+	}
+
+	#[test]
+	fn legacy() {
+		let c = Classes::student_entry(11, " C4 - Economics - Hari Prasad").unwrap();
+		assert_eq!( c, class("C4", "Hari Prasad") );
+		let mut math = class("C3", "");
+		math.subject = "Math".parse().unwrap();
+		math.level = Some(Level::HL);
+		assert_eq!( Classes::student_entry(11, "C3 Math HL"), Ok(math.clone()) );
+		assert_eq!( Classes::student_entry(11, "C3 - Math HL - "), Ok(math) );
+		assert_eq!( Classes::student_entry(11, "C3").unwrap_err().kind, ClassError::Missing("subject") );
+		assert_eq!( Classes::student_entry(11, "C1 - English - Jean-Luc").unwrap().teacher, "Jean-Luc" );
+		assert_eq!( Classes::student_entry(11, "C3 - - X").unwrap_err().pos, 5 );
+		assert_eq!( Classes::teacher_entry("Hari Prasad", "Economics", "C4 - 11").unwrap(), class("C4", "Hari Prasad") );
+		assert_eq!( Classes::teacher_entry("", "Economics", "C4 - eleven").unwrap_err().pos, 5 );
+		assert_eq!( Classes::teacher_entry("", "Economics", "C4 11").unwrap().grade, 11 );
+	}
+
+	#[test]
+	fn json_versions() {
+		let c = class("C4", "Hari Prasad");
+		let encoded = json::encode(&c).unwrap();
+		assert!( encoded.contains("\"version\":2") );
+		let unversioned = r#"{"block":"C4","subject":"Economics","grade":11,"teacher":"Hari Prasad"}"#;
+		assert_eq!( json::decode::<Class>(unversioned).unwrap(), c );
+		// version 1 had no level, so any that is there is ignored
+		let v1 = r#"{"version":1,"block":"C4","subject":"Economics","level":"HL","grade":11,"teacher":"Hari Prasad"}"#;
+		assert_eq!( json::decode::<Class>(v1).unwrap(), c );
+		let v2 = r#"{"version":2,"block":"C4","subject":"Economics","level":"HL","group":3,"grade":11,"teacher":""}"#;
+		assert_eq!( json::decode::<Class>(v2).unwrap(), hl(class("C4", "")) );
+		assert!( json::decode::<Class>(&encoded.replace("\"version\":2", "\"version\":3")).is_err() );
+	}
+
+	#[test]
+	fn text_round_trips() {
+		fn prop(c: Class) -> bool {
+			c.to_string().parse() == Ok(c)
+		}
+		quickcheck(prop as fn(Class) -> bool);
+	}
+
+	#[test]
+	fn list_round_trips() {
+		fn prop(cs: Vec<Class>) -> bool {
+			Classes::parse(&Classes::format(&cs)) == Ok(cs)
+		}
+		quickcheck(prop as fn(Vec<Class>) -> bool);
+	}
+
+	#[test]
+	fn json_round_trips() {
+		fn prop(c: Class) -> bool {
+			json::decode::<Class>(&json::encode(&c).unwrap()).ok() == Some(c)
+		}
+		quickcheck(prop as fn(Class) -> bool);
+	}
+
+	#[test]
+	fn errors_point_into_input() {
+		fn prop(s: String) -> bool {
+			match Classes::parse(&s) {
+				Ok(_) => true,
+				Err(e) => e.pos <= s.len(),
+			}
+		}
+		quickcheck(prop as fn(String) -> bool);
+	}
 }
\ No newline at end of file
@@ -1,254 +1,415 @@
 use postgres::types::ToSql;
-use postgres::{Connection, IntoConnectParams, ConnectParams, ConnectTarget, SslMode, UserInfo};
-use postgres::error::Error as pgError;
+use postgres::GenericConnection;
+use postgres::Transaction;
+use postgres::error::{Error as pgError, SqlState};
 
 use std::convert::Into;
+use std::time::Duration;
 
-use crypt::{encrypt, self};
+use crypt::{encrypt, random_token, hash_token, PasswordPolicy, self};
 
-use rustc_serialize::json::{encode, decode};
-use std::io::{Read, Write};
-
-use std::path::{Path, PathBuf};
-use std::fs::{File, OpenOptions};
 
 use data::*;
+use config::{Config, DatabaseConfig};
+use migrations::{self, Migration};
+use error::{IndusError, IndusResult};
+use pool::{Pool, PooledConnection};
+use usernames;
+use session;
+use enrollment::{self, ImportFailure};
+
+use chrono::UTC;
 
 pub struct PostgreDatabase {
-	pub conn: Connection,
+	pub pool: Pool,
 }
 
 impl PostgreDatabase {
 	#[inline]
-	pub fn new<S1: Into<String>, S2: Into<String>, S3: Into<String>>(host: S1, port: Option<u16>, user: S2, pass: Option<S3>) -> PostgreDatabase {
-		PostgreDatabase {
-			conn: Connection::connect(ConnectParams {
-				target: ConnectTarget::Tcp(host.into()),
-				port: port,
-				user: Some(UserInfo {
-					user: user.into(),
-					password: pass.map(Into::into),
-				}),
-				database: None,
-				options: Vec::new()
-			}, &SslMode::None).unwrap()
-		}
-	}
-	#[inline]
-	pub fn connect<S1: Into<String>, S2: Into<String>, S3: Into<String>, S4: Into<String>>
-		(host: S1, port: Option<u16>, user: S2, pass: Option<S3>, db: Option<S4>, args: Vec<(String, String)>, mode: SslMode) -> PostgreDatabase {
-		PostgreDatabase {
-			conn: Connection::connect(ConnectParams {
-				target: ConnectTarget::Tcp(host.into()),
-				port: port,
-				user: Some(UserInfo {
-					user: user.into(),
-					password: pass.map(Into::into),
-				}),
-				database: db.map(Into::into),
-				options: args
-			}, &mode).unwrap()
-		}
-		// PostgreDatabase::cons(&format!("postgres://{}:{}@{}", user, pass, host), &SslMode::None)
+	pub fn connect(config: &DatabaseConfig, size: usize, timeout: Duration) -> IndusResult<PostgreDatabase> {
+		Ok( PostgreDatabase {
+			pool: try!( Pool::new(config.clone(), size, timeout) )
+		} )
 	}
 
-	#[inline]	
-	pub fn cons<I: IntoConnectParams>(i: I, mode: &SslMode) -> PostgreDatabase {
-		PostgreDatabase {
-			conn: Connection::connect(i, mode).unwrap()
-		}
+	#[inline]
+	pub fn conn(&self) -> IndusResult<PooledConnection> {
+		self.pool.get()
 	}
 
 	#[inline]
-	pub fn exec(&self, query: &str, args: &[&ToSql]) -> Result<u64, pgError> {
-		self.conn.execute(query, args)
+	pub fn exec(&self, query: &str, args: &[&ToSql]) -> IndusResult<u64> {
+		let conn = try!(self.conn());
+		Ok( try!(conn.execute(query, args)) )
 	}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LoginFailure {
-	NoAccount, DuplicateAccounts, PasswordMismatch
+	NoAccount, PasswordMismatch,
+	/// The password is right but an admin has deactivated the account.
+	Deactivated,
 }
 
+/// The Indus data model on top of a connection pool. It is `Sync`, so one
+/// instance can be shared by every request handler.
 pub struct IndusDatabase {
 	database: PostgreDatabase,
-	props: PathBuf, pub cnts: Counts,
+	pub policy: PasswordPolicy,
+	/// Seconds a password reset token stays valid.
+	pub reset_ttl: i64,
 }
 
 impl IndusDatabase {
-	pub fn new() -> IndusDatabase {
-		let props = PathBuf::from("props.cfg");
-		let file = File::open(&props);
-		let counts = decode(
-			&file.map(|mut f| {
-				let mut buf = String::new();
-				f.read_to_string(&mut buf).unwrap();
-				buf
-			}).unwrap_or(encode(&Counts::new()).unwrap())
-		).unwrap();
-		println!("{:?}", counts);
-		IndusDatabase {
-			database: PostgreDatabase::connect(
-				"localhost", Some(5432), 
-				"rusti", Some("2cool4uuu"), 
-				Some("postgres"), 
-				Vec::new(), SslMode::None
-			),
-			props: props, cnts: counts
-		}
-	}
-
-	pub fn login(&self, username: &str, password: &str) -> Result<i32, LoginFailure> {
-		let stmt = self.database.conn
-			.prepare("SELECT id FROM users WHERE username = $1 AND password = $2").unwrap();
-		let pwd = encrypt(password);
-		let rows = match stmt.query(&[&username, &pwd]) {
-			Ok(rows) => rows, 
-			Err(_) => return Result::Err(LoginFailure::NoAccount),
-		};
-		if rows.len() > 1 {
-			Err(LoginFailure::DuplicateAccounts)
-		} else {
-			Ok(rows.get(0).get(0))
+	/// Connects using the config from `Config::load`.
+	pub fn new() -> IndusResult<IndusDatabase> {
+		IndusDatabase::with_config( &try!(Config::load()) )
+	}
+
+	pub fn with_config(config: &Config) -> IndusResult<IndusDatabase> {
+		Ok( IndusDatabase {
+			database: try!( PostgreDatabase::connect(
+				&config.database, config.pool_size, Duration::from_millis(config.pool_timeout)
+			) ),
+			policy: config.password_policy,
+			reset_ttl: config.password_reset_ttl,
+		} )
+	}
+
+	pub fn login(&self, username: &str, password: &str) -> IndusResult<i32> {
+		let conn = try!(self.database.conn());
+		let stmt = try!( conn.prepare("SELECT id, password, active FROM users WHERE username = $1") );
+		let rows = try!( stmt.query(&[&username]) );
+		if rows.is_empty() {
+			return Err(IndusError::Auth(LoginFailure::NoAccount))
 		}
+		let row = rows.get(0);
+		let id: i32 = row.get(0);
+		let hash: String = row.get(1);
+		if !crypt::check(password, hash.trim()) {
+			return Err(IndusError::Auth(LoginFailure::PasswordMismatch))
+		}
+		let active: bool = row.get(2);
+		if !active {
+			return Err(IndusError::Auth(LoginFailure::Deactivated))
+		}
+		if crypt::needs_rehash(hash.trim()) {
+			if let Err(e) = conn.execute("UPDATE users SET password = $1 WHERE id = $2", &[&encrypt(password), &id]) {
+				warn!("Could not rehash password of user {}: {}", id, e);
+			}
+		}
+		Ok(id)
+	}
+
+	pub fn login_profile(&self, username: &str, password: &str) -> IndusResult<IndusUser> {
+		let id = try!(self.login(username, password));
+		self.profile(id)
 	}
 
-	pub fn login_profile(&self, username: &str, password: &str) -> Result<IndusUser, LoginFailure> {
-		let stmt = self.database.conn.prepare(
-			"SELECT id, first_name, last_name, gender 
-			FROM users WHERE username = $1 AND password = $2").unwrap();
-		let pwd = encrypt(password);
-		let rows = match stmt.query(&[&username, &pwd]) {
-			Ok(rows) => rows, 
-			Err(_) => return Result::Err(LoginFailure::NoAccount),
+	pub fn profile(&self, id: i32) -> IndusResult<IndusUser> {
+		let conn = try!(self.database.conn());
+		let stmt = try!( conn.prepare(
+			"SELECT first_name, last_name, gender FROM users WHERE id = $1") );
+		let idargs: &[&ToSql] = &[&id];
+		let rows = try!( stmt.query(idargs) );
+		if rows.is_empty() {
+			return Err(IndusError::NotFound(format!("user {}", id)))
+		}
+		let row = rows.get(0);
+		let first_name = { 
+			let temp: String = row.get(0);
+			String::from( temp.trim() )
+		};
+		let last_name = { 
+			let temp: String = row.get(1);
+			String::from( temp.trim() )
+		};
+		let gender = {
+			let temp: bool = row.get(2);
+			Gender::from(temp)
+		};
+		let role = {
+			let srows_stmt = try!( conn.prepare("SELECT grade, section FROM students WHERE id = $1") );
+			let trows_stmt = try!( conn.prepare("SELECT subject, hod FROM teachers WHERE id = $1") );
+			let srows = try!( srows_stmt.query(idargs) );
+			let trows = try!( trows_stmt.query(idargs) );
+			if !srows.is_empty() {
+				let srow = srows.get(0);
+				let section: String = srow.get(1);
+				StudentTeacher::Student(IndusStudent {
+					classes: try!( enrollment::student_classes(&*conn, id) ),
+					grade: srow.get(0), section: section.char_at(0)
+				})
+			} else if !trows.is_empty() {
+				let trow = trows.get(0);
+				let subject: String = trow.get(0);
+				let hod: bool = trow.get(1);
+				StudentTeacher::Teacher(IndusTeacher {
+					subject: subject.trim().into(),
+					classes: try!( enrollment::teacher_classes(&*conn, id) ),
+					hod: hod,
+				})
+			} else {
+				return Err(IndusError::NotFound(format!("student or teacher {}", id)))
+			}
 		};
-		if rows.len() > 1 {
-			Err(LoginFailure::DuplicateAccounts)
-		} else {
+		Ok(IndusUser {
+			first_name: first_name,
+			last_name: last_name,
+			gender: gender,
+			role: role,
+		})
+	}
+
+	/// Checks a connection out of the pool. Hold on to it only as long as
+	/// needed, other requests are waiting for it.
+	#[inline]
+	pub fn conn(&self) -> IndusResult<PooledConnection> {
+		self.database.conn()
+	}
+
+	/// Runs `f` inside a transaction, committing only if it returns `Ok`.
+	/// Every write that touches more than one table should go through here.
+	pub fn transaction<T, F>(&self, f: F) -> IndusResult<T>
+		where F: FnOnce(&Transaction) -> IndusResult<T> {
+		let conn = try!(self.database.conn());
+		let tx = try!(conn.transaction());
+		let result = try!(f(&tx));
+		try!(tx.commit());
+		Ok(result)
+	}
+
+	/// Brings the schema up to date, returning the migrations that were applied.
+	pub fn migrate(&self) -> IndusResult<Vec<&'static Migration>> {
+		let conn = try!(self.database.conn());
+		Ok( try!(migrations::migrate(&conn)) )
+	}
+
+	pub fn pending_migrations(&self) -> IndusResult<Vec<&'static Migration>> {
+		let conn = try!(self.database.conn());
+		Ok( try!(migrations::pending(&conn)) )
+	}
+
+	/// Sets a password without any checks and logs the user out everywhere.
+	/// Meant for admins; users should go through `change_password`.
+	pub fn change_pwd(&self, username: &str, password: &str) -> IndusResult<()> {
+		self.transaction(|tx| {
+			let stmt = try!( tx.prepare("UPDATE users SET password = $1 WHERE username = $2 RETURNING id") );
+			let rows = try!( stmt.query(&[&encrypt(password), &username]) );
+			if rows.is_empty() {
+				return Err(IndusError::NotFound(format!("user {}", username)))
+			}
+			try!( session::revoke_all(tx, rows.get(0).get(0)) );
+			Ok(())
+		})
+	}
+
+	fn set_password(tx: &Transaction, id: i32, password: &str) -> IndusResult<()> {
+		try!( tx.execute("UPDATE users SET password = $1 WHERE id = $2", &[&encrypt(password), &id]) );
+		try!( tx.execute("DELETE FROM password_resets WHERE user_id = $1", &[&id]) );
+		try!( session::revoke_all(tx, id) );
+		Ok(())
+	}
+
+	/// Lets a user replace their password, provided they know the old one
+	/// and the new one follows the password policy.
+	pub fn change_password(&self, id: i32, old: &str, new: &str) -> IndusResult<()> {
+		try!(self.policy.check(new));
+		self.transaction(|tx| {
+			let stmt = try!( tx.prepare("SELECT password FROM users WHERE id = $1 FOR UPDATE") );
+			let rows = try!( stmt.query(&[&id]) );
+			if rows.is_empty() {
+				return Err(IndusError::NotFound(format!("user {}", id)))
+			}
+			let hash: String = rows.get(0).get(0);
+			if !crypt::check(old, hash.trim()) {
+				return Err(IndusError::Auth(LoginFailure::PasswordMismatch))
+			}
+			IndusDatabase::set_password(tx, id, new)
+		})
+	}
+
+	/// Issues a one-time token with which a user can pick a new password
+	/// without knowing the old one. Only a hash of it is stored.
+	pub fn issue_reset_token(&self, id: i32) -> IndusResult<String> {
+		let token = random_token();
+		let now = UTC::now().timestamp();
+		let conn = try!(self.database.conn());
+		match conn.execute(
+			"INSERT INTO password_resets (token, user_id, created, expires) VALUES ($1, $2, $3, $4)",
+			&[&hash_token(&token), &id, &now, &(now + self.reset_ttl)]
+		) {
+			Ok(_) => Ok(token),
+			// the only foreign key is the user
+			Err(pgError::DbError(ref e)) if e.code() == &SqlState::ForeignKeyViolation =>
+				Err(IndusError::NotFound(format!("user {}", id))),
+			Err(e) => Err(IndusError::from(e)),
+		}
+	}
+
+	/// Sets a new password using a reset token, which is used up. Returns the
+	/// id of the user whose password changed.
+	pub fn reset_password(&self, token: &str, new: &str) -> IndusResult<i32> {
+		try!(self.policy.check(new));
+		self.transaction(|tx| {
+			let stmt = try!( tx.prepare("DELETE FROM password_resets WHERE token = $1 RETURNING user_id, expires") );
+			let rows = try!( stmt.query(&[&hash_token(token)]) );
+			if rows.is_empty() {
+				return Err(IndusError::NotFound("password reset token".into()))
+			}
 			let row = rows.get(0);
-			let id: i32 = row.get(0);
-			let idargs: &[&ToSql] = &[&id];
-			let first_name = { 
-				let temp: String = row.get(1);
-				String::from( temp.trim() )
-			};
-			let last_name = { 
-				let temp: String = row.get(2);
-				String::from( temp.trim() )
-			};
-			let gender = {
-				let temp: bool = row.get(3);
-				Gender::from(temp)
-			};
-			let role = {
-				let srows_stmt = self.database.conn
-					.prepare("SELECT classes, grade, section FROM students WHERE id = $1").unwrap();
-				let student_rows = srows_stmt.query(idargs);
-				match student_rows {
-					Ok(srows) => {
-						let srow = srows.get(0);
-						let classes: String = srow.get(0);
-						let section: String = srow.get(2);
-						StudentTeacher::Student(IndusStudent {
-							classes: Classes::from_student(srow.get(1), classes.trim()),
-							grade: srow.get(1), section: section.char_at(0)
-						})
-					},
-					Err(_) => {
-						let trows_stmt = self.database.conn
-							.prepare("SELECT subject, classes, hod FROM teachers WHERE id = $1").unwrap();
-						let teacher_rows = trows_stmt.query(idargs);
-						match teacher_rows {
-							Ok(trows) => {
-								let trow = trows.get(0);
-								let subject: String = trow.get(0);
-								let classes: String = trow.get(1);
-								let hod: bool = trow.get(2);
-								StudentTeacher::Teacher(IndusTeacher {
-									subject: subject.trim().into(),
-									classes: Classes::from_teacher(
-										&subject, &format!("{} {}", first_name, last_name),
-										classes.trim()
-									),
-									hod: hod,
-								})
-							},
-							Err(_) => return Err(LoginFailure::NoAccount)
-						}
-					}
-				}
-			};
-			Ok(IndusUser {
-				first_name: first_name,
-				last_name: last_name,
-				gender: gender,
-				role: role,
-			})
-		}	
+			let (id, expires): (i32, i64) = (row.get(0), row.get(1));
+			if expires < UTC::now().timestamp() {
+				return Err(IndusError::NotFound("password reset token".into()))
+			}
+			try!( IndusDatabase::set_password(tx, id, new) );
+			Ok(id)
+		})
 	}
 
-	pub fn change_pwd(&self, username: &str, password: &str) -> Result<u64, pgError> {
-		let hash = encrypt(password);
-		self.database.exec("UPDATE users SET password='$1' WHERE username='$2'", 
-			&[&hash, &username])
+	/// The id of the user with a username.
+	pub fn user_id(&self, username: &str) -> IndusResult<i32> {
+		let conn = try!(self.database.conn());
+		let stmt = try!( conn.prepare("SELECT id FROM users WHERE username = $1") );
+		let rows = try!( stmt.query(&[&username]) );
+		if rows.is_empty() {
+			return Err(IndusError::NotFound(format!("user {}", username)))
+		}
+		Ok(rows.get(0).get(0))
 	}
 
-	pub fn insert_student<S1: Into<String>, S2: Into<String>, S3: Into<String>, S4: Into<String>, G: Into<bool>>
-		(&mut self, first_name: S1, last_name: S2, gender: G, classes: S3, grade: i16, section: S4, password: &str)
-		-> Result<u64, pgError> {
-		let firstname = first_name.into();
-		let lastname = last_name.into();
-		try!( self.database.exec(
-			"INSERT INTO Users (ID, first_name, last_name, gender, username, password) VALUES ($1, $2, $3, $4, $5, $6)", 
-			&[&self.cnts.usrcnt, &firstname, &lastname, &gender.into(), &format!("{}.{}", firstname.to_lowercase(), lastname.to_lowercase()), &encrypt(password)]
+	/// Inserts the `users` row shared by students and teachers, returning the
+	/// id the database picked for it.
+	fn insert_user(conn: &GenericConnection, firstname: &str, lastname: &str, gender: bool, password: &str) -> IndusResult<i32> {
+		if firstname.trim().is_empty() || lastname.trim().is_empty() {
+			return Err(IndusError::Validation("first and last name are required".into()))
+		}
+		let username = try!( usernames::allocate(conn, firstname, lastname) );
+		let stmt = try!( conn.prepare(
+			"INSERT INTO users (first_name, last_name, gender, username, password) VALUES ($1, $2, $3, $4, $5) RETURNING id"
 		) );
-		try!( self.database.exec(
-			"INSERT INTO Students (ID, classes, grade, section) VALUES ($1, $2, $3, $4)",
-			&[&self.cnts.usrcnt, &classes.into(), &grade, &section.into()]
+		let rows = try!( stmt.query(
+			&[&firstname, &lastname, &gender, &username, &encrypt(password)]
 		) );
-		self.cnts.incr_students();
-		Ok(2)
+		Ok(rows.get(0).get(0))
+	}
 
+	pub fn username(&self, id: i32) -> IndusResult<String> {
+		let conn = try!(self.database.conn());
+		let stmt = try!( conn.prepare("SELECT username FROM users WHERE id = $1") );
+		let rows = try!( stmt.query(&[&id]) );
+		if rows.is_empty() {
+			return Err(IndusError::NotFound(format!("user {}", id)))
+		}
+		Ok(rows.get(0).get(0))
 	}
-	pub fn insert_teacher<S1: Into<String>, S2: Into<String>, S3: Into<String>, S4: Into<String>, G: Into<bool>>
-		(&mut self, first_name: S1, last_name: S2, gender: G, subject: S3, classes: S4, hod: bool, password: &str)
-		-> Result<u64, pgError> {
-		let firstname = first_name.into();
-		let lastname = last_name.into();
-		try!( self.database.exec(
-			"INSERT INTO Users (ID, first_name, last_name, gender, username, password) VALUES ($1, $2, $3, $4, $5, $6)", 
-			&[&self.cnts.usrcnt, &firstname, &lastname, &gender.into(), &format!("{}.{}", firstname.to_lowercase(), lastname.to_lowercase()), &encrypt(password)]
+
+	/// Gives a user a username picked by an admin.
+	pub fn rename_user(&self, id: i32, username: &str) -> IndusResult<()> {
+		self.transaction(|tx| usernames::rename(tx, id, username))
+	}
+
+	/// Keeps a username from being handed out to new users.
+	pub fn reserve_username(&self, username: &str, reason: &str) -> IndusResult<()> {
+		self.transaction(|tx| usernames::reserve(tx, username, reason))
+	}
+
+	pub fn release_username(&self, username: &str) -> IndusResult<()> {
+		let conn = try!(self.database.conn());
+		usernames::release(&*conn, username)
+	}
+
+	/// Creates a student and returns their id. `classes` is a list in the text
+	/// form of `Class`, or of old-style `block - subject - teacher` entries;
+	/// entries that don't parse end up in `class_import_failures`.
+	pub fn insert_student<S1: Into<String>, S2: Into<String>, S3: Into<String>, S4: Into<String>, G: Into<bool>>
+		(&self, first_name: S1, last_name: S2, gender: G, classes: S3, grade: i16, section: S4, password: &str)
+		-> IndusResult<i32> {
+		let (first_name, last_name, gender, classes, section) =
+			(first_name.into(), last_name.into(), gender.into(), classes.into(), section.into());
+		self.transaction(|tx| {
+			IndusDatabase::add_student(tx, &first_name, &last_name, gender, &classes, grade, &section, password)
+				.map(|(id, _)| id)
+		})
+	}
+
+	/// `insert_student` on a connection or transaction of the caller. Also
+	/// returns the class entries that could not be imported.
+	pub fn add_student(conn: &GenericConnection, first_name: &str, last_name: &str, gender: bool,
+		classes: &str, grade: i16, section: &str, password: &str) -> IndusResult<(i32, Vec<ImportFailure>)> {
+		if section.chars().count() != 1 {
+			return Err(IndusError::Validation(format!("section must be a single letter, not {:?}", section)))
+		}
+		let id = try!( IndusDatabase::insert_user(conn, first_name, last_name, gender, password) );
+		try!( conn.execute(
+			"INSERT INTO Students (ID, grade, section) VALUES ($1, $2, $3)",
+			&[&id, &grade, &section]
 		) );
-		try!( self.database.exec(
-			"INSERT INTO Teachers (ID, subject, classes, hod) VALUES ($1, $2, $3, $4)",
-			&[&self.cnts.usrcnt, &subject.into(), &classes.into(), &hod]
+		let failures = try!( enrollment::import_student(conn, id, grade, classes) );
+		Ok((id, failures))
+	}
+
+	/// Creates a teacher and returns their id. `classes` is a list in the text
+	/// form of `Class`, or of old-style `block - grade` entries of `subject`.
+	pub fn insert_teacher<S1: Into<String>, S2: Into<String>, S3: Into<String>, S4: Into<String>, G: Into<bool>>
+		(&self, first_name: S1, last_name: S2, gender: G, subject: S3, classes: S4, hod: bool, password: &str)
+		-> IndusResult<i32> {
+		let (first_name, last_name, gender, subject, classes) =
+			(first_name.into(), last_name.into(), gender.into(), subject.into(), classes.into());
+		self.transaction(|tx| {
+			IndusDatabase::add_teacher(tx, &first_name, &last_name, gender, &subject, &classes, hod, password)
+				.map(|(id, _)| id)
+		})
+	}
+
+	/// `insert_teacher` on a connection or transaction of the caller. Also
+	/// returns the class entries that could not be imported.
+	pub fn add_teacher(conn: &GenericConnection, first_name: &str, last_name: &str, gender: bool,
+		subject: &str, classes: &str, hod: bool, password: &str) -> IndusResult<(i32, Vec<ImportFailure>)> {
+		let id = try!( IndusDatabase::insert_user(conn, first_name, last_name, gender, password) );
+		try!( conn.execute(
+			"INSERT INTO Teachers (ID, subject, hod) VALUES ($1, $2, $3)",
+			&[&id, &subject, &hod]
 		) );
-		self.cnts.incr_teachers();
-		Ok(2)
+		let failures = try!( enrollment::import_teacher(conn, id, subject, classes) );
+		Ok((id, failures))
 	}
 
-	pub fn insert(&mut self, user: IndusUser, password: &str) -> Result<u64, pgError> {
+	pub fn insert(&self, user: IndusUser, password: &str) -> IndusResult<i32> {
 		match user.role {
 			StudentTeacher::Student(stdnt) => 
 				self.insert_student(
 					user.first_name, user.last_name, user.gender, 
-					Classes::to_student(stdnt.classes), stdnt.grade, stdnt.section.to_string(), password),
+					Classes::format(&stdnt.classes), stdnt.grade, stdnt.section.to_string(), password),
 			StudentTeacher::Teacher(tchr) => 
 				self.insert_teacher(
 					user.first_name, user.last_name, user.gender,
-					tchr.subject, Classes::to_teacher(tchr.classes), tchr.hod, password)
+					tchr.subject, Classes::format(&tchr.classes), tchr.hod, password)
 		}
 	}
 
-	pub fn clear(&self) -> Result<u64, pgError> {
-		self.database.exec("TRUNCATE users CASCADE", &[])
+	/// Live user, student and teacher counts.
+	pub fn counts(&self) -> IndusResult<Counts> {
+		let conn = try!(self.database.conn());
+		let stmt = try!( conn.prepare(
+			"SELECT (SELECT COUNT(*) FROM users)::INT, (SELECT COUNT(*) FROM students)::INT, (SELECT COUNT(*) FROM teachers)::INT"
+		) );
+		let rows = try!( stmt.query(&[]) );
+		let row = rows.get(0);
+		Ok(Counts::from(row.get(0), row.get(1), row.get(2)))
 	}
-}
 
-use std::ops::Drop;
-impl Drop for IndusDatabase {
-	fn drop(&mut self) {
-		write!(&mut File::create("props.cfg").unwrap(), "{}", encode(&self.cnts).unwrap());
+	/// Deletes every user, class and file record, returning the number of
+	/// users. Stored file contents stay on disk. The subject catalogue stays;
+	/// `TRUNCATE ... CASCADE` would empty it too, as subjects refer to their
+	/// department heads.
+	pub fn clear(&self) -> IndusResult<u64> {
+		self.transaction(|tx| {
+			try!( tx.execute("DELETE FROM files", &[]) );
+			try!( tx.execute("DELETE FROM folders", &[]) );
+			try!( tx.execute("DELETE FROM classes", &[]) );
+			Ok( try!(tx.execute("DELETE FROM users", &[])) )
+		})
 	}
 }
 
@@ -256,20 +417,963 @@ impl Drop for IndusDatabase {
 mod tests {
 	use super::*;
 	use test::Bencher;
-	use std::fs::File;
-	use rustc_serialize::json::encode;
-	use std::io::Write;
+	use data::*;
+	use error::{IndusError, IndusResult};
+	use import::ImportOptions;
+	use export::{Export, ExportFilter};
+	use roles::{Grants, Role, Target};
+	use session;
+	use users::{UserQuery, UserUpdate};
+	use storage::{FileQuery, NewFile, Storage, StorageBackend, LocalDisk, TempDir, UrlSigner};
+	use config::{Backend, UploadConfig};
+	use folders::{Access, Space};
+	use versions::Retention;
+	use std::fs;
+	use std::io::{self, Read};
+	use std::ops::Deref;
+	use std::sync::{StaticMutex, MUTEX_INIT};
+
+	/// Every test works on the same database, so they take turns.
+	static DATABASE: StaticMutex = MUTEX_INIT;
+
+	fn fresh() -> IndusDatabase {
+		let database = IndusDatabase::new().unwrap();
+		database.migrate().unwrap();
+		database.clear().unwrap();
+		database
+	}
+
+	fn failure<T>(result: IndusResult<T>) -> Option<LoginFailure> {
+		match result {
+			Err(IndusError::Auth(f)) => Some(f),
+			_ => None,
+		}
+	}
+
+	fn subject(code: &str) -> Subject {
+		code.parse().unwrap()
+	}
+
+	fn insert_anshuman(database: &mut IndusDatabase) {
+		database.insert_student(
+			"Anshuman", "Medhi", Gender::Male, 
+			"C1 English SL, C2 Spanish SL, C3 Math HL, C4 Economics SL, C5 Chemistry HL, C6 Physics HL",
+			11, "B", "2cool4uuu"
+		).unwrap();
+	}
+
+	#[test]
+	fn login_succeeds() {
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
+		insert_anshuman(&mut database);
+		assert!( database.login("anshuman.medhi", "2cool4uuu").is_ok() );
+		assert_eq!( database.login_profile("anshuman.medhi", "2cool4uuu").unwrap().first_name, "Anshuman" );
+	}
+
+	#[test]
+	fn login_no_account() {
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
+		assert_eq!( failure(database.login("anshuman.medhi", "2cool4uuu")), Some(LoginFailure::NoAccount) );
+		insert_anshuman(&mut database);
+		assert_eq!( failure(database.login("anshuman.medhii", "2cool4uuu")), Some(LoginFailure::NoAccount) );
+		assert_eq!( failure(database.login_profile("anshuman.medhii", "2cool4uuu")), Some(LoginFailure::NoAccount) );
+	}
+
+	#[test]
+	fn login_password_mismatch() {
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
+		insert_anshuman(&mut database);
+		assert_eq!( failure(database.login("anshuman.medhi", "coolpoopbags")), Some(LoginFailure::PasswordMismatch) );
+		assert_eq!( failure(database.login("anshuman.medhi", "")), Some(LoginFailure::PasswordMismatch) );
+		assert_eq!( failure(database.login_profile("anshuman.medhi", "coolpoopbags")), Some(LoginFailure::PasswordMismatch) );
+	}
+
+	#[test]
+	fn duplicate_names_are_numbered() {
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
+		insert_anshuman(&mut database);
+		insert_anshuman(&mut database);
+		insert_anshuman(&mut database);
+		assert!( database.login("anshuman.medhi", "2cool4uuu").is_ok() );
+		assert!( database.login("anshuman.medhi2", "2cool4uuu").is_ok() );
+		assert!( database.login("anshuman.medhi3", "2cool4uuu").is_ok() );
+		assert_eq!( failure(database.login("anshuman.medhi2", "coolpoopbags")), Some(LoginFailure::PasswordMismatch) );
+	}
+
+	#[test]
+	fn reserved_and_renamed_usernames() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		database.reserve_username("anshuman.medhi", "alumnus").unwrap();
+		assert!( database.reserve_username("anshuman.medhi", "again").is_err() );
+		let id = database.insert_student("Anshuman", "Medhi", Gender::Male, "", 11, "B", "2cool4uuu").unwrap();
+		assert_eq!( database.username(id).unwrap(), "anshuman.medhi2" );
+
+		let other = database.insert_student("Ánshuman", "Médhi", Gender::Male, "", 11, "B", "2cool4uuu").unwrap();
+		assert_eq!( database.username(other).unwrap(), "anshuman.medhi3" );
+		match database.rename_user(other, "anshuman.medhi2") {
+			Err(IndusError::Duplicate(_)) => {},
+			r => panic!("{:?}", r),
+		}
+		database.release_username("anshuman.medhi").unwrap();
+		database.rename_user(other, "anshuman.medhi").unwrap();
+		assert!( database.login("anshuman.medhi", "2cool4uuu").is_ok() );
+	}
+
+	#[test]
+	fn counts() {
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
+		assert_eq!( database.counts().unwrap(), Counts::new() );
+		insert_anshuman(&mut database);
+		insert_anshuman(&mut database);
+		database.insert_teacher(
+			"Hari", "Prasad", Gender::Male,
+			"Economics", "Wiggle Wiggle Wiggle Wiggle Wiggle YEAH", false,
+			"killthelion"
+		).unwrap();
+		assert_eq!( database.counts().unwrap(), Counts::from(3, 2, 1) );
+	}
+
+	#[test]
+	fn insert_is_atomic() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		// too long for teachers.subject, so the second INSERT fails
+		assert!( database.insert_teacher(
+			"Hari", "Prasad", Gender::Male,
+			"Economics and a great many other things besides", "C4 11", false,
+			"killthelion"
+		).is_err() );
+		assert_eq!( database.counts().unwrap(), Counts::new() );
+		assert_eq!( failure(database.login("hari.prasad", "killthelion")), Some(LoginFailure::NoAccount) );
+	}
+
+	#[test]
+	fn insert_returns_id() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let id = database.insert_teacher(
+			"Hari", "Prasad", Gender::Male,
+			"Economics", "C4 11", false, "killthelion"
+		).unwrap();
+		assert_eq!( database.login("hari.prasad", "killthelion").unwrap(), id );
+	}
+
+	#[test]
+	fn change_password() {
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
+		insert_anshuman(&mut database);
+		let id = database.user_id("anshuman.medhi").unwrap();
+		let sessions = session::Sessions::ephemeral(session::Timeouts::new());
+		let token = sessions.create(&database.conn().unwrap(), id).unwrap().token;
+
+		assert_eq!( failure(database.change_password(id, "coolpoopbags", "3cool5uuu")), Some(LoginFailure::PasswordMismatch) );
+		match database.change_password(id, "2cool4uuu", "short") {
+			Err(IndusError::Validation(_)) => {},
+			r => panic!("{:?}", r),
+		}
+		assert!( sessions.resolve(&database.conn().unwrap(), &token).unwrap().is_some() );
+
+		database.change_password(id, "2cool4uuu", "3cool5uuu").unwrap();
+		assert_eq!( failure(database.login("anshuman.medhi", "2cool4uuu")), Some(LoginFailure::PasswordMismatch) );
+		assert_eq!( database.login("anshuman.medhi", "3cool5uuu").unwrap(), id );
+		assert!( sessions.resolve(&database.conn().unwrap(), &token).unwrap().is_none() );
+
+		database.change_pwd("anshuman.medhi", "4cool6uuu").unwrap();
+		assert_eq!( database.login("anshuman.medhi", "4cool6uuu").unwrap(), id );
+		assert!( database.change_pwd("nobody.here", "4cool6uuu").is_err() );
+	}
+
+	#[test]
+	fn reset_password() {
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
+		insert_anshuman(&mut database);
+		let id = database.user_id("anshuman.medhi").unwrap();
+		let token = database.issue_reset_token(id).unwrap();
+
+		assert!( database.reset_password("not a token", "3cool5uuu").is_err() );
+		assert!( database.reset_password(&token, "short").is_err() );
+		assert_eq!( database.reset_password(&token, "3cool5uuu").unwrap(), id );
+		assert_eq!( database.login("anshuman.medhi", "3cool5uuu").unwrap(), id );
+		// tokens are single use
+		assert!( database.reset_password(&token, "4cool6uuu").is_err() );
+
+		database.reset_ttl = -1;
+		let expired = database.issue_reset_token(id).unwrap();
+		assert!( database.reset_password(&expired, "4cool6uuu").is_err() );
+		assert!( database.issue_reset_token(id + 1000).is_err() );
+	}
+
+	#[test]
+	fn pool_is_shared() {
+		use std::sync::Arc;
+		use std::thread;
+
+		let _lock = DATABASE.lock().unwrap();
+		let database = Arc::new(fresh());
+		let handles = (0..8).map(|i| {
+			let database = database.clone();
+			thread::spawn(move || {
+				database.insert_teacher(
+					format!("Teacher{}", i), "Pool", Gender::Female,
+					"Economics", "C4 11", false, "killthelion"
+				).unwrap();
+			})
+		}).collect::<Vec<_>>();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!( database.counts().unwrap(), Counts::from(8, 0, 8) );
+	}
+
+	#[test]
+	fn enrollments() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let student = database.insert_student("Anshuman", "Medhi", Gender::Male, "", 11, "B", "2cool4uuu").unwrap();
+		let teacher = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "", false, "killthelion").unwrap();
+		let class = database.create_class(&subject("Economics"), "C4", 11).unwrap();
+		assert_eq!( database.create_class(&subject("Economics"), "C4", 11).unwrap(), class );
+		assert_eq!( database.class_id(&subject("Economics"), "C4", 11).unwrap(), class );
+
+		database.enroll(student, class, Some(Level::HL)).unwrap();
+		database.assign_teacher(teacher, class).unwrap();
+		match database.enroll(student, class, None) {
+			Err(IndusError::Duplicate(_)) => {},
+			r => panic!("{:?}", r),
+		}
+		match database.enroll(student + 1000, class, None) {
+			Err(IndusError::NotFound(_)) => {},
+			r => panic!("{:?}", r),
+		}
+
+		let roster = database.roster(class).unwrap();
+		assert_eq!( roster.len(), 1 );
+		assert_eq!( roster[0].username, "anshuman.medhi" );
+		match database.profile(student).unwrap().role {
+			StudentTeacher::Student(s) => {
+				assert_eq!( s.classes.len(), 1 );
+				assert_eq!( s.classes[0].teacher, "Hari Prasad" );
+				assert_eq!( s.classes[0].level, Some(Level::HL) );
+				assert_eq!( s.classes[0].group, Some(3) );
+			},
+			r => panic!("{:?}", r),
+		}
+
+		database.unenroll(student, class).unwrap();
+		assert!( database.roster(class).unwrap().is_empty() );
+		assert!( database.unenroll(student, class).is_err() );
+		database.unassign_teacher(teacher, class).unwrap();
+	}
+
+	#[test]
+	fn class_strings_are_imported() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let student = database.insert_student(
+			"Anshuman", "Medhi", Gender::Male, "C1 - English - Jane Doe, C2 - Maths - Jane Doe, C3 Math HL, C4 - Latin - X", 11, "B", "2cool4uuu"
+		).unwrap();
+		let teacher = database.insert_teacher("Hari", "Prasad", Gender::Male, "English", "C1 - 11", false, "killthelion").unwrap();
+
+		let class = database.class_id(&subject("English"), "C1", 11).unwrap();
+		assert_eq!( database.roster(class).unwrap()[0].id, student );
+		match database.profile(teacher).unwrap().role {
+			StudentTeacher::Teacher(t) => assert_eq!( t.classes[0].block, "C1" ),
+			r => panic!("{:?}", r),
+		}
+		assert!( database.class_id(&subject("Math"), "C2", 11).is_ok() );
+		assert!( database.class_id(&subject("Math"), "C3", 11).is_ok() );
+		let failures = database.class_import_failures().unwrap();
+		assert_eq!( failures.len(), 1 );
+		assert_eq!( failures[0].user_id, student );
+		assert_eq!( failures[0].entry, "C4 - Latin - X" );
+	}
+
+	#[test]
+	fn insert_keeps_classes() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let classes = vec![
+			Class {
+				subject: subject("Economics"), teacher: "".into(), block: "C4".into(), grade: 11,
+				level: Some(Level::SL), group: Some(3),
+			},
+			Class {
+				subject: subject("Physics"), teacher: "".into(), block: "C6".into(), grade: 11,
+				level: Some(Level::HL), group: Some(6),
+			},
+		];
+		let id = database.insert(IndusUser {
+			first_name: "Anshuman".into(),
+			last_name: "Medhi".into(),
+			gender: Gender::Male,
+			role: StudentTeacher::Student(IndusStudent { classes: classes.clone(), grade: 11, section: 'B' })
+		}, "2cool4uuu").unwrap();
+		match database.profile(id).unwrap().role {
+			StudentTeacher::Student(s) => assert_eq!( s.classes, classes ),
+			r => panic!("{:?}", r),
+		}
+		assert!( database.class_import_failures().unwrap().is_empty() );
+	}
+
+	#[test]
+	fn subject_catalogue() {
+		use subjects::SubjectInfo;
+
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		for name in &["Math", "Maths", "mathematics", "MATHEMATICS"] {
+			assert_eq!( database.subject(name).unwrap().subject, subject("Math") );
+		}
+		assert_eq!( database.subject("Computer Science").unwrap().group, Some(4) );
+		assert!( database.subject("Basket Weaving").is_err() );
+
+		// left behind if this failed before
+		let _ = database.delete_subject(&subject("Philosophy"));
+		let _ = database.delete_subject(&subject("Philo"));
+
+		let teacher = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "", true, "killthelion").unwrap();
+		let mut info = SubjectInfo {
+			subject: subject("Philosophy"), name: "Philosophy".into(), group: Some(3),
+			levels: vec![Level::SL], head: Some(teacher), core: false, aliases: vec!["Philo".into()],
+		};
+		database.create_subject(&info).unwrap();
+		match database.create_subject(&info) {
+			Err(IndusError::Duplicate(_)) => {},
+			r => panic!("{:?}", r),
+		}
+		info.aliases = vec!["philo".into()];
+		assert_eq!( database.subject("PHILO").unwrap(), info );
+
+		info.group = Some(7);
+		assert!( database.update_subject(&info).is_err() );
+		info.group = Some(3);
+		info.aliases = vec!["maths".into()];
+		assert!( database.update_subject(&info).is_err() );
+		info.aliases = vec!["wisdom".into()];
+		info.head = None;
+		database.update_subject(&info).unwrap();
+		assert_eq!( database.subject("Philosophy").unwrap(), info );
+
+		let class = database.create_class(&subject("Philosophy"), "C2", 12).unwrap();
+		let head = database.insert_teacher("Sofia", "Iyer", Gender::Female, "philosophy", "", true, "allegoryofthecave").unwrap();
+		let sage = database.insert_teacher("Arjun", "Bose", Gender::Male, "Wisdom", "", false, "knowthyself").unwrap();
+		let renamed = database.rename_subject(&subject("Philosophy"), "Philo").unwrap();
+		assert_eq!( database.class_id(&renamed, "C2", 12).unwrap(), class );
+		{
+			let conn = database.conn().unwrap();
+			let stmt = conn.prepare("SELECT subject FROM teachers WHERE id = $1").unwrap();
+			for id in &[head, sage] {
+				assert_eq!( stmt.query(&[id]).unwrap().get(0).get::<_, String>(0), "Philo" );
+			}
+		}
+		assert_eq!( database.grants(head).unwrap().department, Some(renamed.clone()) );
+		match database.delete_subject(&renamed) {
+			Err(IndusError::Validation(_)) => {},
+			r => panic!("{:?}", r),
+		}
+		database.clear().unwrap();
+		let storage = storage();
+		let head = database.insert_teacher("Sofia", "Iyer", Gender::Female, "Philo", "", true, "allegoryofthecave").unwrap();
+		let shelf = database.root_folder(&Space::Department(renamed.clone())).unwrap();
+		let syllabus = database.upload(&storage, head, &NewFile {
+			name: "Syllabus.txt".into(), mime: None, folder: shelf.id,
+		}, &b"plato"[..]).unwrap();
+		match database.delete_subject(&renamed) {
+			Err(IndusError::Validation(ref why)) if why.contains("files") => {},
+			r => panic!("{:?}", r),
+		}
+		database.delete_file(&storage, syllabus.id).unwrap();
+		database.delete_subject(&renamed).unwrap();
+		assert!( database.subject("Philo").is_err() );
+	}
+
+	#[test]
+	fn programme() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let id = database.insert_student(
+			"Anshuman", "Medhi", Gender::Male,
+			"C1 English SL, C2 Spanish SL, C3 Math HL, C4 Economics SL, C5 Chemistry HL, C6 Physics HL",
+			11, "B", "2cool4uuu"
+		).unwrap();
+		let report = database.programme(id).unwrap();
+		assert!( report.missing_groups.is_empty() );
+		assert_eq!( (report.hl, report.sl), (3, 3) );
+		assert_eq!( report.missing_core.len(), 3 );
+		assert!( !report.complete );
+
+		for code in &["TOK", "EE", "CAS"] {
+			let class = database.create_class(&subject(code), "Core", 11).unwrap();
+			database.enroll(id, class, None).unwrap();
+		}
+		assert!( database.programme(id).unwrap().complete );
+		let math = database.class_id(&subject("Math"), "C3", 11).unwrap();
+		database.set_level(id, math, Some(Level::SL)).unwrap();
+		assert_eq!( database.programme(id).unwrap().hl, 2 );
+		assert_eq!( database.programmes(Some(11)).unwrap().len(), 1 );
+		assert!( database.programmes(Some(12)).unwrap().is_empty() );
+		assert!( database.programme(id + 1000).is_err() );
+	}
+
+	#[test]
+	fn roles() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		// left behind if this failed before
+		let _ = database.delete_role("librarian");
+
+		let student = database.insert_student(
+			"Anshuman", "Medhi", Gender::Male, "C4 Economics SL, C6 Physics HL", 11, "B", "2cool4uuu"
+		).unwrap();
+		let hod = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "C4 - 11", true, "killthelion").unwrap();
+		let teacher = database.insert_teacher("Nikhil", "Rao", Gender::Male, "Physics", "C6 - 11", false, "newtonrocks").unwrap();
+		let economics_id = database.class_id(&subject("Economics"), "C4", 11).unwrap();
+		let economics = Target::Class(economics_id);
+		let physics = Target::Class(database.class_id(&subject("Physics"), "C6", 11).unwrap());
+
+		let h = database.grants(hod).unwrap();
+		let t = database.grants(teacher).unwrap();
+		let s = database.grants(student).unwrap();
+		assert_eq!( h.roles, vec!["hod", "teacher"] );
+		assert_eq!( h.department, Some(subject("Economics")) );
+		assert_eq!( t.department, None );
+		assert_eq!( s.roles, vec!["student"] );
+		{
+			let conn = database.conn().unwrap();
+			assert!( h.allows(&*conn, "class.edit", &economics).unwrap() );
+			assert!( !h.allows(&*conn, "class.edit", &physics).unwrap() );
+			assert!( h.allows(&*conn, "user.edit", &Target::User(hod)).unwrap() );
+			assert!( !h.allows(&*conn, "user.edit", &Target::User(teacher)).unwrap() );
+			assert!( h.allows(&*conn, "resource.delete", &Target::Resource { owner: student, class: Some(economics_id) }).unwrap() );
+			assert!( t.allows(&*conn, "class.edit", &physics).unwrap() );
+			assert!( !t.allows(&*conn, "class.edit", &economics).unwrap() );
+			assert!( t.allows(&*conn, "resource.delete", &Target::Resource { owner: teacher, class: None }).unwrap() );
+			assert!( !t.allows(&*conn, "resource.delete", &Target::Resource { owner: student, class: None }).unwrap() );
+			assert!( s.allows(&*conn, "class.view", &physics).unwrap() );
+			assert!( !s.allows(&*conn, "class.edit", &physics).unwrap() );
+			match s.require(&*conn, "user.create", &Target::Any) {
+				Err(IndusError::Forbidden(_)) => {},
+				r => panic!("{:?}", r),
+			}
+		}
+
+		let deputy = Grants { permissions: vec!["role.assign:department".parse().unwrap()], ..h.clone() };
+		match database.assign_role(&deputy, hod, "admin", true) {
+			Err(IndusError::Forbidden(_)) => {},
+			r => panic!("{:?}", r),
+		}
+		assert!( !database.grants(hod).unwrap().roles.contains(&"admin".to_string()) );
+
+		database.grant_role(student, "admin").unwrap();
+		database.grant_role(student, "admin").unwrap();
+		assert!( database.grants(student).unwrap().has("user.create") );
+		database.revoke_role(student, "admin").unwrap();
+		assert!( database.revoke_role(student, "admin").is_err() );
+		assert!( database.grant_role(student, "wizard").is_err() );
+
+		let mut librarian = Role {
+			name: "librarian".into(), description: "Keeps the books".into(),
+			permissions: vec!["resource.*".into(), "class.view:all".into()],
+		};
+		database.create_role(&librarian).unwrap();
+		librarian.permissions = vec!["class.view".into(), "resource.*".into()];
+		assert!( database.roles().unwrap().contains(&librarian) );
+		librarian.permissions.push("resource.burn:everywhere".into());
+		assert!( database.update_role(&librarian).is_err() );
+		database.grant_role(teacher, "librarian").unwrap();
+		assert!( database.grants(teacher).unwrap().roles.contains(&"librarian".to_string()) );
+		assert!( database.delete_role("teacher").is_err() );
+		database.delete_role("librarian").unwrap();
+		assert!( !database.grants(teacher).unwrap().roles.contains(&"librarian".to_string()) );
+	}
+
+	#[test]
+	fn user_management() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let anshuman = database.insert_student("Anshuman", "Medhi", Gender::Male, "", 11, "B", "2cool4uuu").unwrap();
+		let priya = database.insert_student("Priya", "Shah", Gender::Female, "", 12, "A", "ilovebooks").unwrap();
+		let hari = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "", true, "killthelion").unwrap();
+		database.grant_role(hari, "admin").unwrap();
+
+		let all = database.users(&UserQuery::default()).unwrap();
+		assert_eq!( all.total, 3 );
+		assert_eq!( all.users.iter().map(|u| u.id).collect::<Vec<_>>(), vec![anshuman, hari, priya] );
+		let search = |query: UserQuery| database.users(&query).unwrap().users.iter().map(|u| u.id).collect::<Vec<_>>();
+		assert_eq!( search(UserQuery { name: Some("MEDHI".into()), ..Default::default() }), vec![anshuman] );
+		assert_eq!( search(UserQuery { name: Some("a s".into()), ..Default::default() }), vec![priya] );
+		assert_eq!( search(UserQuery { name: Some("%".into()), ..Default::default() }), vec![] );
+		assert_eq!( search(UserQuery { role: Some("teacher".into()), ..Default::default() }), vec![hari] );
+		assert_eq!( search(UserQuery { role: Some("admin".into()), ..Default::default() }), vec![hari] );
+		assert_eq!( search(UserQuery { grade: Some(12), ..Default::default() }), vec![priya] );
+		assert_eq!( search(UserQuery { section: Some("B".into()), ..Default::default() }), vec![anshuman] );
+		assert_eq!( search(UserQuery { subject: Some("economics".into()), ..Default::default() }), vec![hari] );
+		let page = database.users(&UserQuery { page: 2, per_page: 2, ..Default::default() }).unwrap();
+		assert_eq!( (page.total, page.users.len()), (3, 1) );
+		assert!( database.users(&UserQuery { per_page: -1, ..Default::default() }).is_err() );
+
+		let user = database.update_user(anshuman, &UserUpdate {
+			last_name: Some(" Medhi-Rao ".into()), grade: Some(12), section: Some("A".into()), ..Default::default()
+		}).unwrap();
+		assert_eq!( (&*user.last_name, user.grade, user.section.as_ref().map(|s| &**s)), ("Medhi-Rao", Some(12), Some("A")) );
+		assert_eq!( user.username, "anshuman.medhi" );
+		assert!( database.update_user(anshuman, &UserUpdate { hod: Some(true), ..Default::default() }).is_err() );
+		assert!( database.update_user(hari, &UserUpdate { grade: Some(11), ..Default::default() }).is_err() );
+		assert!( database.update_user(priya, &UserUpdate { first_name: Some(" ".into()), ..Default::default() }).is_err() );
+		assert!( database.update_user(hari, &UserUpdate { subject: Some("Business".into()), ..Default::default() }).unwrap().hod );
+
+		database.deactivate_user(anshuman).unwrap();
+		assert_eq!( failure(database.login("anshuman.medhi", "2cool4uuu")), Some(LoginFailure::Deactivated) );
+		assert_eq!( failure(database.login("anshuman.medhi", "wrong")), Some(LoginFailure::PasswordMismatch) );
+		assert!( database.user(anshuman).unwrap().deactivated.is_some() );
+		assert_eq!( search(UserQuery { active: Some(false), ..Default::default() }), vec![anshuman] );
+		database.reactivate_user(anshuman).unwrap();
+		database.login("anshuman.medhi", "2cool4uuu").unwrap();
+		assert!( database.deactivate_user(anshuman + 1000).is_err() );
+
+		assert!( database.delete_user(priya, "anshuman.medhi").is_err() );
+		database.delete_user(priya, "priya.shah").unwrap();
+		assert!( database.user(priya).is_err() );
+		assert_eq!( database.users(&UserQuery::default()).unwrap().total, 2 );
+	}
+
+	#[test]
+	fn csv_import() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let csv = "Type,First Name,Last Name,Gender,Grade,Section,Subject,HOD,Classes,Password
+student,Anshuman,Medhi,M,11,B,,,\"C3 Math HL, C4 Economics SL\",
+student,Priya,Shah,F,12,A,,,,ilovebooks9
+teacher,Hari,Prasad,M,,,Economics,yes,C4 - 11,
+student,Rohan,,M,11,B,,,,
+student,Nikhil,Rao,M,11,B,,,C1 Basketweaving SL,
+student,Meera,Iyer,F,11,C,,,,abc
+";
+		let mut options = ImportOptions::new();
+		options.dry_run = true;
+		options.batch_size = 2;
+		let report = database.import_users(csv.as_bytes(), &options).unwrap();
+		assert_eq!( report.rows, 6 );
+		assert_eq!( report.created.iter().map(|c| c.row).collect::<Vec<_>>(), vec![1, 2, 3] );
+		assert_eq!( report.errors.iter().map(|e| e.row).collect::<Vec<_>>(), vec![4, 5, 6] );
+		assert!( report.created.iter().all(|c| c.password.is_none()) );
+		assert_eq!( database.counts().unwrap().usrcnt, 0 );
+
+		options.dry_run = false;
+		let report = database.import_users(csv.as_bytes(), &options).unwrap();
+		assert_eq!( report.created.len(), 3 );
+		assert_eq!( report.failed, None );
+		assert_eq!( database.counts().unwrap().usrcnt, 3 );
+		assert!( database.class_import_failures().unwrap().is_empty() );
+		let anshuman = &report.created[0];
+		assert_eq!( anshuman.username, "anshuman.medhi" );
+		let password = anshuman.password.clone().unwrap();
+		assert_eq!( database.login("anshuman.medhi", &password).unwrap(), anshuman.id );
+		match database.profile(anshuman.id).unwrap().role {
+			StudentTeacher::Student(s) => assert_eq!( s.classes.len(), 2 ),
+			_ => panic!("not a student"),
+		}
+		assert!( report.created[1].password.is_none() );
+		database.login("priya.shah", "ilovebooks9").unwrap();
+		let passwords = report.passwords_csv();
+		assert!( passwords.contains(&password) );
+		assert_eq!( passwords.lines().count(), 3 );
+	}
+
+	#[test]
+	fn exports() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let anshuman = database.insert_student(
+			"Anshuman", "Medhi", Gender::Male, "C3 Math HL, C4 Economics SL", 11, "B", "2cool4uuu"
+		).unwrap();
+		database.insert_student("Priya", "Shah", Gender::Female, "C4 Economics HL", 11, "A", "ilovebooks9").unwrap();
+		let hari = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "C4 - 11, C2 - 12", true, "killthelion").unwrap();
+
+		let users = database.export(Export::Users, &ExportFilter::default()).unwrap();
+		assert_eq!( users.rows.len(), 3 );
+		assert!( users.rows.iter().all(|row| !row.contains_key("password")) );
+		assert!( !users.csv().contains("rpbkdf2") && !users.json().contains("rpbkdf2") );
+
+		let section_b = ExportFilter { section: Some("B".into()), ..Default::default() };
+		let users = database.export(Export::Users, &section_b).unwrap()
+			.select(&["username".into(), "grade".into()]).unwrap();
+		assert_eq!( users.csv(), "username,grade\r\nanshuman.medhi,11\r\n" );
+
+		let economics = ExportFilter { subject: Some("economics".into()), ..Default::default() };
+		let rosters = database.export(Export::Rosters, &economics).unwrap();
+		assert_eq!( rosters.rows.len(), 2 );
+		assert!( rosters.json().contains("\"level\":\"HL\"") );
+		let rosters = database.export(Export::Rosters, &ExportFilter { grade: Some(11), ..Default::default() }).unwrap();
+		assert_eq!( rosters.rows.len(), 3 );
+
+		let timetable = database.export(Export::Timetables, &ExportFilter::default()).unwrap()
+			.select(&["teacher_id".into(), "block".into(), "students".into()]).unwrap();
+		assert_eq!( timetable.csv(), format!("teacher_id,block,students\r\n{0},C2,0\r\n{0},C4,2\r\n", hari) );
+		let timetable = database.export(Export::Timetables, &section_b).unwrap();
+		assert_eq!( timetable.rows.len(), 1 );
+
+		let counts = database.export(Export::Counts, &ExportFilter::default()).unwrap();
+		assert_eq!( counts.csv(), "users,students,teachers\r\n3,2,1\r\n" );
+		let counts = database.export(Export::Counts, &ExportFilter { grade: Some(12), ..Default::default() }).unwrap();
+		assert_eq!( counts.csv(), "users,students,teachers\r\n1,0,1\r\n" );
+
+		database.deactivate_user(anshuman).unwrap();
+		assert_eq!( database.export(Export::Rosters, &economics).unwrap().rows.len(), 1 );
+		assert_eq!( database.export(Export::Users, &section_b).unwrap().rows.len(), 0 );
+		assert!( database.export(Export::Users, &ExportFilter { subject: Some("Basket Weaving".into()), ..Default::default() }).is_err() );
+	}
+
+	/// Storage that removes its directory once the test is done with it.
+	struct TestStorage {
+		storage: Storage,
+		_dir: TempDir,
+	}
+
+	impl Deref for TestStorage {
+		type Target = Storage;
+		fn deref(&self) -> &Storage {
+			&self.storage
+		}
+	}
+
+	/// Local storage in a directory of its own, allowing uploads of up to
+	/// 1 KiB.
+	fn storage() -> TestStorage {
+		let dir = TempDir::new();
+		let root = dir.0.clone();
+		TestStorage {
+			storage: Storage::new(&UploadConfig {
+				backend: Backend::Local, temp: root.join("tmp"), root: root, max_size: 1024,
+				s3: None, url: "http://localhost:3000".into(), link_ttl: 60, resume_ttl: 60,
+			}, vec![0; 32]).unwrap(),
+			_dir: dir,
+		}
+	}
+
+	#[test]
+	fn files() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let storage = storage();
+		let anshuman = database.insert_student(
+			"Anshuman", "Medhi", Gender::Male, "C4 Economics SL", 11, "B", "2cool4uuu"
+		).unwrap();
+		let hari = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "C4 - 11", false, "killthelion").unwrap();
+		let class = database.class_id(&subject("Economics"), "C4", 11).unwrap();
+		let personal = database.root_folder(&Space::Personal(anshuman)).unwrap().id;
+		let shelf = database.root_folder(&Space::Class(class)).unwrap().id;
+		let notes = |name: &str, folder: i32| NewFile {
+			name: name.into(), mime: Some("text/plain".into()), folder: folder,
+		};
+
+		let mine = database.upload(&storage, anshuman, &notes("notes.txt", personal), &b"supply and demand"[..]).unwrap();
+		assert_eq!( mine.size, 17 );
+		assert_eq!( mine.sha256, "9bfa39e7d682bd09f5cff4b3a5e4efeb8ea7e8e42200d454599e4db9382a74a9" );
+		let (info, mut contents) = database.download(&storage, mine.id).unwrap();
+		let mut read = String::new();
+		contents.read_to_string(&mut read).unwrap();
+		assert_eq!( (info, read), (mine.clone(), "supply and demand".to_string()) );
+
+		let shared = database.upload(&storage, hari, &notes("Elasticity.txt", shelf), &b"ped"[..]).unwrap();
+		assert_eq!( (shared.class, shared.folder), (Some(class), shelf) );
+		assert_eq!( database.files(&FileQuery { owner: Some(anshuman), ..Default::default() }).unwrap(), vec![mine.clone()] );
+		assert_eq!( database.files(&FileQuery { class: Some(class), ..Default::default() }).unwrap(), vec![shared.clone()] );
+		assert_eq!( database.files(&FileQuery { folder: Some(personal), ..Default::default() }).unwrap(), vec![mine.clone()] );
+		assert_eq!( database.files(&FileQuery::default()).unwrap(), vec![shared.clone(), mine.clone()] );
+
+		let big = vec![0u8; 1025];
+		match database.upload(&storage, anshuman, &notes("big.bin", personal), &big[..]) {
+			Err(IndusError::Validation(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		match database.upload(&storage, anshuman, &notes("../notes.txt", personal), &b""[..]) {
+			Err(IndusError::Validation(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		match database.upload(&storage, anshuman, &notes("notes.txt", -1), &b""[..]) {
+			Err(IndusError::NotFound(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		assert_eq!( database.files(&FileQuery::default()).unwrap().len(), 2 );
+
+		assert!( database.delete_user(anshuman, "anshuman.medhi").is_err() );
+		database.delete_file(&storage, mine.id).unwrap();
+		match database.download(&storage, mine.id) {
+			Err(IndusError::NotFound(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		database.delete_user(anshuman, "anshuman.medhi").unwrap();
+	}
+
+	#[test]
+	fn folders() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let storage = storage();
+		let anshuman = database.insert_student(
+			"Anshuman", "Medhi", Gender::Male, "C4 Economics SL", 11, "B", "2cool4uuu"
+		).unwrap();
+		let priya = database.insert_student("Priya", "Sharma", Gender::Female, "C2 Physics HL", 11, "A", "quarksandleptons").unwrap();
+		let hari = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "C4 - 11", true, "killthelion").unwrap();
+		let vikram = database.insert_teacher("Vikram", "Rao", Gender::Male, "Economics", "", false, "keynesian").unwrap();
+		let class = database.class_id(&subject("Economics"), "C4", 11).unwrap();
+		let grants = |id| database.grants(id).unwrap();
+		let access = |id, space: &Space| database.access(&grants(id), space).unwrap();
+
+		let economics = Space::Class(class);
+		assert_eq!( access(hari, &economics), Access::Write );
+		assert_eq!( access(anshuman, &economics), Access::Read );
+		assert_eq!( access(priya, &economics), Access::None );
+
+		let own = Space::Personal(anshuman);
+		assert_eq!( access(anshuman, &own), Access::Write );
+		assert_eq!( access(priya, &own), Access::None );
+		assert_eq!( access(hari, &own), Access::None );
+
+		let department = Space::Department(subject("Economics"));
+		assert_eq!( access(hari, &department), Access::Write );
+		assert_eq!( access(vikram, &department), Access::Read );
+		assert_eq!( access(anshuman, &department), Access::None );
+
+		// Top folders are only made once something goes in them.
+		assert!( database.root_folders(anshuman).unwrap().is_empty() );
+		for space in &[own.clone(), economics.clone(), department.clone()] {
+			database.root_folder(space).unwrap();
+		}
+		let roots = database.root_folders(anshuman).unwrap();
+		assert_eq!( roots.iter().map(|f| f.space()).collect::<Vec<_>>(), vec![own.clone(), economics.clone()] );
+		assert_eq!( roots[1].name, "Economics C4 grade 11" );
+		assert_eq!( database.root_folders(vikram).unwrap().len(), 1 );
+
+		let shelf = database.root_folder(&economics).unwrap();
+		let worksheets = database.create_folder(shelf.id, "Worksheets").unwrap();
+		assert_eq!( (worksheets.parent, worksheets.space()), (Some(shelf.id), economics.clone()) );
+		match database.create_folder(shelf.id, "worksheets") {
+			Err(IndusError::Duplicate(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		let sheet = database.upload(&storage, hari, &NewFile {
+			name: "Elasticity.txt".into(), mime: None, folder: worksheets.id,
+		}, &b"ped"[..]).unwrap();
+
+		let listing = database.folder_listing(&grants(anshuman), shelf.id).unwrap();
+		assert_eq!( (listing.writable, listing.folders, listing.files), (false, vec![worksheets.clone()], vec![]) );
+		let listing = database.folder_listing(&grants(hari), worksheets.id).unwrap();
+		assert_eq!( (listing.writable, listing.files), (true, vec![sheet.clone()]) );
+
+		assert!( database.delete_folder(worksheets.id).is_err() );
+		assert!( database.delete_folder(shelf.id).is_err() );
+		database.delete_file(&storage, sheet.id).unwrap();
+		database.delete_folder(worksheets.id).unwrap();
+		match database.folder(worksheets.id) {
+			Err(IndusError::NotFound(_)) => {}
+			other => panic!("{:?}", other),
+		}
+	}
+
+	/// Gives `data`, then fails as if the connection dropped.
+	struct Dropped<'a>(Option<&'a [u8]>);
+
+	impl<'a> Read for Dropped<'a> {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			match self.0.take() {
+				Some(mut data) => data.read(buf),
+				None => Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset")),
+			}
+		}
+	}
+
+	#[test]
+	fn resumable_uploads() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let storage = storage();
+		let anshuman = database.insert_student("Anshuman", "Medhi", Gender::Male, "", 11, "B", "2cool4uuu").unwrap();
+		let personal = database.root_folder(&Space::Personal(anshuman)).unwrap().id;
+		let file = NewFile { name: "notes.txt".into(), mime: Some("text/plain".into()), folder: personal };
+		let sha256 = "9bfa39e7d682bd09f5cff4b3a5e4efeb8ea7e8e42200d454599e4db9382a74a9";
+
+		let session = database.create_upload(&storage, anshuman, &file, 17, Some(sha256)).unwrap();
+		assert_eq!( (session.offset, session.expires), (0, session.updated + 60) );
+		assert_eq!( database.upload_chunk(&storage, session.id, 0, &b"supply "[..]).unwrap().offset, 7 );
+		match database.upload_chunk(&storage, session.id, 0, &b"supply "[..]) {
+			Err(IndusError::Conflict(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		// As if another chunk were still arriving.
+		storage.uploading().lock().unwrap().insert(session.id);
+		match database.upload_chunk(&storage, session.id, 7, &b"and"[..]) {
+			Err(IndusError::Conflict(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		storage.uploading().lock().unwrap().remove(&session.id);
+		match database.finish_upload(&storage, session.id, None) {
+			Err(IndusError::Conflict(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		// What arrives before the connection drops is kept.
+		assert!( database.upload_chunk(&storage, session.id, 7, Dropped(Some(&b"and"[..]))).is_err() );
+		assert_eq!( database.upload_session(&storage, session.id).unwrap().offset, 10 );
+		assert!( database.upload_chunk(&storage, session.id, 10, &b" demand!"[..]).is_err() );
+		assert_eq!( database.upload_chunk(&storage, session.id, 10, &b" demand"[..]).unwrap().offset, 17 );
+		assert_eq!( database.upload_sessions(&storage, anshuman).unwrap().len(), 1 );
+
+		let info = database.finish_upload(&storage, session.id, None).unwrap();
+		assert_eq!( (&*info.name, info.size, &*info.sha256, info.folder), ("notes.txt", 17, sha256, personal) );
+		let mut contents = String::new();
+		database.download(&storage, info.id).unwrap().1.read_to_string(&mut contents).unwrap();
+		assert_eq!( contents, "supply and demand" );
+		assert!( database.upload_session(&storage, session.id).is_err() );
+
+		// Uploads that do not match their checksum are thrown away.
+		let session = database.create_upload(&storage, anshuman, &file, 6, None).unwrap();
+		database.upload_chunk(&storage, session.id, 0, &b"supply"[..]).unwrap();
+		match database.finish_upload(&storage, session.id, Some(sha256)) {
+			Err(IndusError::Validation(_)) => {}
+			other => panic!("{:?}", other),
+		}
+		assert!( database.upload_session(&storage, session.id).is_err() );
+		assert!( database.create_upload(&storage, anshuman, &file, 1025, None).is_err() );
+		assert!( database.create_upload(&storage, anshuman, &file, 6, Some("abc")).is_err() );
+
+		let abandoned = database.create_upload(&storage, anshuman, &file, 6, None).unwrap();
+		let kept = database.create_upload(&storage, anshuman, &file, 6, None).unwrap();
+		database.conn().unwrap().execute("UPDATE upload_sessions SET updated = 0 WHERE id = $1", &[&abandoned.id]).unwrap();
+		assert!( database.upload_session(&storage, abandoned.id).is_err() );
+		assert_eq!( database.purge_uploads(&storage).unwrap(), 1 );
+		assert_eq!( fs::read_dir(storage.temp().join("sessions")).unwrap().count(), 1 );
+		assert_eq!( database.upload_sessions(&storage, anshuman).unwrap(), vec![kept.clone()] );
+		database.cancel_upload(&storage, kept.id).unwrap();
+		assert_eq!( fs::read_dir(storage.temp().join("sessions")).unwrap().count(), 0 );
+	}
+
+	#[test]
+	fn file_versions() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let storage = storage();
+		let hari = database.insert_teacher("Hari", "Prasad", Gender::Male, "Economics", "C4 - 11", false, "killthelion").unwrap();
+		let vikram = database.insert_teacher("Vikram", "Rao", Gender::Male, "Economics", "C4 - 11", false, "keynesian").unwrap();
+		let class = database.class_id(&subject("Economics"), "C4", 11).unwrap();
+		let shelf = database.root_folder(&Space::Class(class)).unwrap().id;
+		let sheet = database.upload(&storage, hari, &NewFile {
+			name: "worksheet.txt".into(), mime: Some("text/plain".into()), folder: shelf,
+		}, &b"first"[..]).unwrap();
+		let read = |number: i32| {
+			let (info, mut src) = database.download_version(&storage, sheet.id, number).unwrap();
+			let mut contents = String::new();
+			src.read_to_string(&mut contents).unwrap();
+			(info.version, info.size, contents)
+		};
+
+		database.replace_file(&storage, sheet.id, hari, None, &b"second!"[..]).unwrap();
+		let current = database.replace_file(&storage, sheet.id, vikram, None, &b"third draft"[..]).unwrap();
+		assert_eq!( (current.version, current.size, current.owner, &*current.mime), (3, 11, hari, "text/plain") );
+		let versions = database.file_versions(sheet.id).unwrap();
+		assert_eq!( versions.iter().map(|v| (v.number, v.author, v.current)).collect::<Vec<_>>(),
+			vec![(3, Some(vikram), true), (2, Some(hari), false), (1, Some(hari), false)] );
+		assert_eq!( read(1), (1, 5, "first".to_string()) );
+		// Links made before the file was replaced still lead to what it held.
+		let first = {
+			let conn = database.conn().unwrap();
+			let stmt = conn.prepare("SELECT key FROM file_versions WHERE file_id = $1 AND number = 1").unwrap();
+			stmt.query(&[&sheet.id]).unwrap().get(0).get::<_, String>(0)
+		};
+		let linked = database.file_by_key(&first).unwrap();
+		assert_eq!( (linked.id, linked.version, linked.size), (sheet.id, 1, 5) );
+
+		let restored = database.restore_version(sheet.id, 1).unwrap();
+		assert_eq!( (restored.version, restored.size, restored.sha256.clone()), (1, 5, sheet.sha256.clone()) );
+		let mut contents = String::new();
+		database.download(&storage, sheet.id).unwrap().1.read_to_string(&mut contents).unwrap();
+		assert_eq!( contents, "first" );
+		assert!( database.restore_version(sheet.id, 4).is_err() );
+
+		// The current version is kept whatever the policy.
+		let pruned = database.prune_versions(&storage, Some(sheet.id), Retention::Last(1)).unwrap();
+		assert_eq!( pruned.iter().map(|v| v.number).collect::<Vec<_>>(), vec![2] );
+		assert!( database.download_version(&storage, sheet.id, 2).is_err() );
+		assert_eq!( database.replace_file(&storage, sheet.id, hari, None, &b"fourth"[..]).unwrap().version, 4 );
+		assert_eq!( storage.backend().list("").unwrap().len(), 3 );
+
+		assert!( database.prune_versions(&storage, None, Retention::Days(30)).unwrap().is_empty() );
+		database.conn().unwrap().execute("UPDATE file_versions SET created = 0 WHERE number < 4", &[]).unwrap();
+		let pruned = database.prune_versions(&storage, None, Retention::Days(30)).unwrap();
+		assert_eq!( pruned.iter().map(|v| v.number).collect::<Vec<_>>(), vec![1, 3] );
+		assert_eq!( read(4), (4, 6, "fourth".to_string()) );
+
+		// Numbers are not used again once the newest version is pruned.
+		assert_eq!( database.replace_file(&storage, sheet.id, vikram, None, &b"fifth"[..]).unwrap().version, 5 );
+		database.restore_version(sheet.id, 4).unwrap();
+		database.conn().unwrap().execute("UPDATE file_versions SET created = 0 WHERE number = 5", &[]).unwrap();
+		let pruned = database.prune_versions(&storage, None, Retention::Days(30)).unwrap();
+		assert_eq!( pruned.iter().map(|v| v.number).collect::<Vec<_>>(), vec![5] );
+		assert_eq!( database.replace_file(&storage, sheet.id, hari, None, &b"sixth"[..]).unwrap().version, 6 );
+
+		database.delete_file(&storage, sheet.id).unwrap();
+		assert!( storage.backend().list("").unwrap().is_empty() );
+	}
+
+	#[test]
+	fn copying_objects() {
+		let _lock = DATABASE.lock().unwrap();
+		let database = fresh();
+		let storage = storage();
+		let dir = TempDir::new();
+		let temp = &dir.0;
+		let to = LocalDisk::new(temp.join("objects"), UrlSigner::new(vec![0; 32], "http://localhost:3000"));
+		let anshuman = database.insert_student("Anshuman", "Medhi", Gender::Male, "", 11, "B", "2cool4uuu").unwrap();
+		let personal = database.root_folder(&Space::Personal(anshuman)).unwrap().id;
+		let file = |name: &str| NewFile { name: name.into(), mime: None, folder: personal };
+		database.upload(&storage, anshuman, &file("supply.txt"), &b"supply"[..]).unwrap();
+		database.upload(&storage, anshuman, &file("demand.txt"), &b"demand"[..]).unwrap();
+		// Objects nobody recorded are copied too, checked against the original.
+		storage.backend().put("orphan", &mut &b"elasticity"[..], 10).unwrap();
+
+		let report = database.copy_objects(storage.backend(), &to, &temp.join("tmp")).unwrap();
+		assert_eq!( (report.copied.len(), report.skipped.len(), report.failed.len()), (3, 0, 0) );
+		let mut keys = to.list("").unwrap();
+		keys.sort();
+		let mut expected = storage.backend().list("").unwrap();
+		expected.sort();
+		assert_eq!( keys, expected );
+		let mut contents = String::new();
+		to.get("orphan").unwrap().read_to_string(&mut contents).unwrap();
+		assert_eq!( contents, "elasticity" );
+
+		// Running again copies only what is missing or wrong.
+		to.put("orphan", &mut &b"inelastic"[..], 9).unwrap();
+		let report = database.copy_objects(storage.backend(), &to, &temp.join("tmp")).unwrap();
+		assert_eq!( report.copied, vec!["orphan".to_string()] );
+		assert_eq!( report.skipped.len(), 2 );
+
+		// Originals that no longer match their recorded checksum are not copied.
+		let key = keys.iter().find(|k| *k != "orphan").unwrap().clone();
+		storage.backend().put(&key, &mut &b"tampered"[..], 8).unwrap();
+		to.delete(&key).unwrap();
+		let report = database.copy_objects(storage.backend(), &to, &temp.join("tmp")).unwrap();
+		assert_eq!( report.failed.iter().map(|f| f.0.clone()).collect::<Vec<_>>(), vec![key.clone()] );
+		assert!( to.get(&key).is_err() );
+	}
 
 	#[test]
 	fn it_works() {
-		let mut database = IndusDatabase::new();
-		database.clear();
-		write!(&mut File::create("props.cfg").unwrap(), "{}", encode(&Counts::new()).unwrap());
+		let _lock = DATABASE.lock().unwrap();
+		let mut database = fresh();
 
 		println!("Testing Anshuman Login (NoAcc)");
-		assert_eq!( database.login("anshuman.medhi", "2cool4uuu"), Err(LoginFailure::NoAccount) );
+		assert_eq!( failure(database.login("anshuman.medhi", "2cool4uuu")), Some(LoginFailure::NoAccount) );
 		println!("Testing Hari Prasad Login (NoAcc)");
-		assert_eq!( database.login("hari.prasad", "killthelion"), Err(LoginFailure::NoAccount) );
+		assert_eq!( failure(database.login("hari.prasad", "killthelion")), Some(LoginFailure::NoAccount) );
 
 		println!("Testing Anshuman Insert");
 		database.insert_student(
@@ -290,9 +1394,9 @@ mod tests {
 		database.login("hari.prasad", "killthelion").unwrap();
 
 		println!("Testing Anshuman Login (PwdMismatch)");
-		assert_eq!( database.login("anshuman.medhi", "coolpoopbags"), Err(LoginFailure::PasswordMismatch) );
+		assert_eq!( failure(database.login("anshuman.medhi", "coolpoopbags")), Some(LoginFailure::PasswordMismatch) );
 		println!("Testing Hari Prasad Login (PwdMismatch)");
-		assert_eq!( database.login("hari.prasad", "aosdjbd"), Err(LoginFailure::PasswordMismatch) );
+		assert_eq!( failure(database.login("hari.prasad", "aosdjbd")), Some(LoginFailure::PasswordMismatch) );
 
 		println!("Testing Anshuman InsertObj (Succ)");
 		database.insert(IndusUser {
@@ -315,10 +1419,10 @@ mod tests {
 			})
 		}, "killthelion").unwrap();
 
-		println!("Testing Anshuman Login (DupAcc)");
-		assert_eq!( database.login("anshuman.medhi", "2cool4uuu"), Err(LoginFailure::DuplicateAccounts) );
-		println!("Testing Hari Prasad Login (DupAcc)");
-		assert_eq!( database.login("hari.prasad", "killthelion"), Err(LoginFailure::DuplicateAccounts) );
+		println!("Testing Anshuman Login (Numbered)");
+		database.login("anshuman.medhi2", "2cool4uuu").unwrap();
+		println!("Testing Hari Prasad Login (Numbered)");
+		database.login("hari.prasad2", "killthelion").unwrap();
 	}
 
 	// #[bench]
@@ -0,0 +1,364 @@
+use postgres::{GenericConnection, Transaction};
+
+use data::*;
+use db::IndusDatabase;
+use error::{missing, IndusError, IndusResult};
+use subjects;
+
+pub type ClassId = i32;
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct RosterEntry {
+	pub id: i32,
+	pub first_name: String,
+	pub last_name: String,
+	pub username: String,
+	pub grade: i16,
+	pub section: String,
+}
+
+/// An entry of an old free-text `classes` column that could not be imported.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct ImportFailure {
+	pub user_id: i32,
+	pub entry: String,
+	pub reason: String,
+}
+
+/// The class with a subject, block and grade. `subject` must be a code, not
+/// an alias.
+pub fn class_id(conn: &GenericConnection, subject: &Subject, block: &str, grade: i16) -> IndusResult<Option<ClassId>> {
+	let stmt = try!( conn.prepare("SELECT id FROM classes WHERE subject = $1 AND block = $2 AND grade = $3") );
+	let rows = try!( stmt.query(&[&subject.code(), &block, &grade]) );
+	Ok( rows.iter().next().map(|row| row.get(0)) )
+}
+
+/// Finds or creates a class, resolving `subject` through the catalogue. A new
+/// class counts towards `group`, or if that is `None` its subject's group.
+/// Holds a transaction-level lock on the class, so `conn` should be a
+/// transaction.
+pub fn find_or_create_class(conn: &GenericConnection, subject: &Subject, block: &str, grade: i16, group: Option<i16>)
+	-> IndusResult<ClassId> {
+	let subject = try!(subjects::resolve(conn, subject.code()));
+	try!( conn.execute(
+		"SELECT pg_advisory_xact_lock(hashtext($1))",
+		&[&format!("class {} {} {}", subject, block, grade)]
+	) );
+	if let Some(id) = try!(class_id(conn, &subject, block, grade)) {
+		return Ok(id)
+	}
+	let stmt = try!( conn.prepare(
+		"INSERT INTO classes (subject, block, grade, ib_group)
+		SELECT $1, $2, $3, COALESCE($4, ib_group) FROM subjects WHERE code = $1
+		RETURNING id"
+	) );
+	let rows = try!( stmt.query(&[&subject.code(), &block, &grade, &group]) );
+	Ok(rows.get(0).get(0))
+}
+
+fn record_failure(conn: &GenericConnection, user_id: i32, entry: &str, reason: &str) -> IndusResult<ImportFailure> {
+	warn!("Could not import class {:?} of user {}: {}", entry, user_id, reason);
+	try!( conn.execute(
+		"INSERT INTO class_import_failures (user_id, entry, reason) VALUES ($1, $2, $3)",
+		&[&user_id, &entry, &reason]
+	) );
+	Ok(ImportFailure { user_id: user_id, entry: entry.into(), reason: reason.into() })
+}
+
+#[inline]
+fn level_text(level: Option<Level>) -> Option<String> {
+	level.map(|l| l.to_string())
+}
+
+fn enroll_in(conn: &GenericConnection, id: i32, class: &Class) -> IndusResult<()> {
+	let class_id = try!(find_or_create_class(conn, &class.subject, &class.block, class.grade, class.group));
+	try!( conn.execute(
+		"INSERT INTO enrollments (class_id, student_id, level)
+		SELECT $1, $2, $3 WHERE NOT EXISTS (SELECT 1 FROM enrollments WHERE class_id = $1 AND student_id = $2)",
+		&[&class_id, &id, &level_text(class.level)]
+	) );
+	Ok(())
+}
+
+fn assign_to(conn: &GenericConnection, id: i32, class: &Class) -> IndusResult<()> {
+	let class_id = try!(find_or_create_class(conn, &class.subject, &class.block, class.grade, class.group));
+	try!( conn.execute(
+		"INSERT INTO teaching_assignments (class_id, teacher_id)
+		SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM teaching_assignments WHERE class_id = $1 AND teacher_id = $2)",
+		&[&class_id, &id]
+	) );
+	Ok(())
+}
+
+/// The classes in `src`, which is either a list in the text form of `Class`
+/// or in one of the old formats, read by `legacy`. Each comes with the entry
+/// it was read from; entries that don't parse come back with the reason.
+fn parse_entries<L>(src: &str, legacy: L) -> (Vec<(String, Class)>, Vec<(String, String)>)
+	where L: Fn(&str) -> Result<Class, InvalidClass> {
+	let mut classes = Vec::new();
+	let mut invalid = Vec::new();
+	if Classes::is_canonical(src) {
+		match Classes::parse(src) {
+			Ok(parsed) => classes.extend(parsed.into_iter().map(|class| (class.to_string(), class))),
+			Err(e) => invalid.push((src.to_string(), e.to_string())),
+		}
+	} else {
+		for entry in src.split(",").map(str::trim).filter(|e| !e.is_empty()) {
+			match legacy(entry) {
+				Ok(class) => classes.push((entry.to_string(), class)),
+				Err(e) => invalid.push((entry.to_string(), e.to_string())),
+			}
+		}
+	}
+	(classes, invalid)
+}
+
+/// Runs `add` for every class in `src`, read as by `parse_entries`. Entries
+/// that don't parse or name an unknown subject are recorded and returned.
+fn import<L, A>(conn: &GenericConnection, id: i32, src: &str, legacy: L, add: A) -> IndusResult<Vec<ImportFailure>>
+	where L: Fn(&str) -> Result<Class, InvalidClass>, A: Fn(&GenericConnection, i32, &Class) -> IndusResult<()> {
+	let (classes, invalid) = parse_entries(src, legacy);
+	let mut failures = Vec::new();
+	for (entry, reason) in invalid {
+		failures.push( try!(record_failure(conn, id, &entry, &reason)) );
+	}
+	for (entry, class) in classes {
+		match add(conn, id, &class) {
+			Ok(()) => {},
+			Err(IndusError::NotFound(what)) =>
+				failures.push( try!(record_failure(conn, id, &entry, &format!("{} not found", what))) ),
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(failures)
+}
+
+/// Enrolls a student in every class of a list. Old-style entries are
+/// `block - subject - teacher` and taken to be in the student's grade.
+pub fn import_student(conn: &GenericConnection, id: i32, grade: i16, src: &str) -> IndusResult<Vec<ImportFailure>> {
+	import(conn, id, src, |entry| Classes::student_entry(grade, entry), enroll_in)
+}
+
+/// Assigns a teacher to every class of a list. Old-style entries are
+/// `block - grade` of the teacher's subject.
+pub fn import_teacher(conn: &GenericConnection, id: i32, subject: &str, src: &str) -> IndusResult<Vec<ImportFailure>> {
+	import(conn, id, src, |entry| Classes::teacher_entry("", subject, entry), assign_to)
+}
+
+const ENROLL_V6: &'static str =
+	"INSERT INTO enrollments (class_id, student_id)
+	SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM enrollments WHERE class_id = $1 AND student_id = $2)";
+
+const ASSIGN_V6: &'static str =
+	"INSERT INTO teaching_assignments (class_id, teacher_id)
+	SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM teaching_assignments WHERE class_id = $1 AND teacher_id = $2)";
+
+/// Adds the classes of one old `classes` column with `link`, one of the
+/// statements above, and returns how many entries could not be imported.
+/// Only uses the tables as migration 6 made them.
+fn import_v6(tx: &Transaction, id: i32, entries: (Vec<(String, Class)>, Vec<(String, String)>), link: &str)
+	-> IndusResult<usize> {
+	let (classes, mut invalid) = entries;
+	let subject = try!( tx.prepare("SELECT code FROM subjects WHERE lower(code) = lower($1)") );
+	let find = try!( tx.prepare("SELECT id FROM classes WHERE subject = $1 AND block = $2 AND grade = $3") );
+	let create = try!( tx.prepare("INSERT INTO classes (subject, block, grade) VALUES ($1, $2, $3) RETURNING id") );
+	let link = try!( tx.prepare(link) );
+	for (entry, class) in classes {
+		let rows = try!( subject.query(&[&class.subject.code().trim()]) );
+		if rows.is_empty() {
+			invalid.push((entry, format!("subject {} not found", class.subject)));
+			continue
+		}
+		let code: String = rows.get(0).get(0);
+		let mut rows = try!( find.query(&[&code, &class.block, &class.grade]) );
+		if rows.is_empty() {
+			rows = try!( create.query(&[&code, &class.block, &class.grade]) );
+		}
+		let class_id: ClassId = rows.get(0).get(0);
+		try!( link.execute(&[&class_id, &id]) );
+	}
+	for &(ref entry, ref reason) in &invalid {
+		warn!("Could not import class {:?} of user {}: {}", entry, id, reason);
+		try!( tx.execute(
+			"INSERT INTO class_import_failures (user_id, entry, reason) VALUES ($1, $2, $3)",
+			&[&id, entry, reason]
+		) );
+	}
+	Ok(invalid.len())
+}
+
+/// Moves the free-text `classes` columns into the class tables. Run once by
+/// migration 6, and so written against its schema rather than with the
+/// functions above, which follow the newest one.
+pub fn import_legacy(tx: &Transaction) -> IndusResult<()> {
+	let mut failures = 0;
+	let students = try!( tx.prepare("SELECT id, grade, classes FROM students") );
+	for row in try!(students.query(&[])).iter() {
+		let (grade, classes): (i16, String) = (row.get(1), row.get(2));
+		let entries = parse_entries(&classes, |entry| Classes::student_entry(grade, entry));
+		failures += try!(import_v6(tx, row.get(0), entries, ENROLL_V6));
+	}
+	let teachers = try!( tx.prepare("SELECT id, subject, classes FROM teachers") );
+	for row in try!(teachers.query(&[])).iter() {
+		let (subject, classes): (String, String) = (row.get(1), row.get(2));
+		let entries = parse_entries(&classes, |entry| Classes::teacher_entry("", subject.trim(), entry));
+		failures += try!(import_v6(tx, row.get(0), entries, ASSIGN_V6));
+	}
+	if failures > 0 {
+		warn!("{} class entries could not be imported, see class_import_failures", failures);
+	}
+	Ok(())
+}
+
+fn classes_from(conn: &GenericConnection, query: &str, id: i32) -> IndusResult<Vec<Class>> {
+	let stmt = try!( conn.prepare(query) );
+	let rows = try!( stmt.query(&[&id]) );
+	Ok( rows.iter().filter_map(|row| {
+		let subject: String = row.get(0);
+		let level: Option<String> = row.get(4);
+		Some( Class {
+			subject: match subject.parse() {
+				Ok(s) => s,
+				Err(_) => {
+					warn!("Unknown subject {:?} in classes", subject);
+					return None
+				}
+			},
+			block: row.get(1), grade: row.get(2), teacher: row.get(3),
+			level: level.and_then(|l| l.parse().ok()),
+			group: row.get(5),
+		} )
+	}).collect() )
+}
+
+/// The classes a student is enrolled in, with their teachers' names and the
+/// level the student takes them at.
+pub fn student_classes(conn: &GenericConnection, id: i32) -> IndusResult<Vec<Class>> {
+	classes_from(conn,
+		"SELECT c.subject, c.block, c.grade,
+			COALESCE(string_agg(u.first_name || ' ' || u.last_name, ', ' ORDER BY u.last_name), ''),
+			e.level, c.ib_group
+		FROM enrollments e
+		JOIN classes c ON c.id = e.class_id
+		LEFT JOIN teaching_assignments t ON t.class_id = c.id
+		LEFT JOIN users u ON u.id = t.teacher_id
+		WHERE e.student_id = $1
+		GROUP BY c.id, e.level ORDER BY c.block", id)
+}
+
+/// The classes a teacher teaches.
+pub fn teacher_classes(conn: &GenericConnection, id: i32) -> IndusResult<Vec<Class>> {
+	classes_from(conn,
+		"SELECT c.subject, c.block, c.grade, u.first_name || ' ' || u.last_name, NULL::VARCHAR, c.ib_group
+		FROM teaching_assignments t
+		JOIN classes c ON c.id = t.class_id
+		JOIN users u ON u.id = t.teacher_id
+		WHERE t.teacher_id = $1
+		ORDER BY c.block", id)
+}
+
+impl IndusDatabase {
+	/// Creates a class, or returns the existing one with the same subject,
+	/// block and grade.
+	pub fn create_class(&self, subject: &Subject, block: &str, grade: i16) -> IndusResult<ClassId> {
+		if block.trim().is_empty() {
+			return Err(IndusError::Validation("a class needs a block".into()))
+		}
+		self.transaction(|tx| find_or_create_class(tx, subject, block.trim(), grade, None))
+	}
+
+	pub fn class_id(&self, subject: &Subject, block: &str, grade: i16) -> IndusResult<ClassId> {
+		let conn = try!(self.conn());
+		let subject = try!(subjects::resolve(&*conn, subject.code()));
+		match try!(class_id(&*conn, &subject, block, grade)) {
+			Some(id) => Ok(id),
+			None => Err(IndusError::NotFound(format!("class {} {} {}", subject, block, grade))),
+		}
+	}
+
+	/// Enrolls a student in a class, at a level for Diploma Programme classes.
+	pub fn enroll(&self, student_id: i32, class_id: ClassId, level: Option<Level>) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		try!( conn.execute(
+			"INSERT INTO enrollments (class_id, student_id, level) VALUES ($1, $2, $3)",
+			&[&class_id, &student_id, &level_text(level)]
+		).map_err(|e| missing(e, format!("student {} or class {}", student_id, class_id))) );
+		Ok(())
+	}
+
+	/// Changes the level a student takes a class at.
+	pub fn set_level(&self, student_id: i32, class_id: ClassId, level: Option<Level>) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		match try!( conn.execute(
+			"UPDATE enrollments SET level = $3 WHERE class_id = $1 AND student_id = $2",
+			&[&class_id, &student_id, &level_text(level)]
+		) ) {
+			0 => Err(IndusError::NotFound(format!("enrollment of student {} in class {}", student_id, class_id))),
+			_ => Ok(()),
+		}
+	}
+
+	/// Makes a class count towards another IB group than its subject's.
+	pub fn set_class_group(&self, class_id: ClassId, group: Option<i16>) -> IndusResult<()> {
+		match group {
+			Some(g) if g < 1 || g > 6 => return Err(IndusError::Validation(format!("there is no IB group {}", g))),
+			_ => {}
+		}
+		let conn = try!(self.conn());
+		match try!( conn.execute("UPDATE classes SET ib_group = $2 WHERE id = $1", &[&class_id, &group]) ) {
+			0 => Err(IndusError::NotFound(format!("class {}", class_id))),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn unenroll(&self, student_id: i32, class_id: ClassId) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		match try!( conn.execute("DELETE FROM enrollments WHERE class_id = $1 AND student_id = $2", &[&class_id, &student_id]) ) {
+			0 => Err(IndusError::NotFound(format!("enrollment of student {} in class {}", student_id, class_id))),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn assign_teacher(&self, teacher_id: i32, class_id: ClassId) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		try!( conn.execute("INSERT INTO teaching_assignments (class_id, teacher_id) VALUES ($1, $2)", &[&class_id, &teacher_id])
+			.map_err(|e| missing(e, format!("teacher {} or class {}", teacher_id, class_id))) );
+		Ok(())
+	}
+
+	pub fn unassign_teacher(&self, teacher_id: i32, class_id: ClassId) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		match try!( conn.execute("DELETE FROM teaching_assignments WHERE class_id = $1 AND teacher_id = $2", &[&class_id, &teacher_id]) ) {
+			0 => Err(IndusError::NotFound(format!("teacher {} of class {}", teacher_id, class_id))),
+			_ => Ok(()),
+		}
+	}
+
+	/// The students enrolled in a class, by name.
+	pub fn roster(&self, class_id: ClassId) -> IndusResult<Vec<RosterEntry>> {
+		let conn = try!(self.conn());
+		let stmt = try!( conn.prepare(
+			"SELECT u.id, u.first_name, u.last_name, u.username, s.grade, s.section
+			FROM enrollments e
+			JOIN students s ON s.id = e.student_id
+			JOIN users u ON u.id = s.id
+			WHERE e.class_id = $1
+			ORDER BY u.last_name, u.first_name"
+		) );
+		let rows = try!( stmt.query(&[&class_id]) );
+		Ok( rows.iter().map(|row| RosterEntry {
+			id: row.get(0), first_name: row.get(1), last_name: row.get(2), username: row.get(3),
+			grade: row.get(4), section: row.get(5),
+		}).collect() )
+	}
+
+	/// Class entries that could not be imported from the old text columns.
+	pub fn class_import_failures(&self) -> IndusResult<Vec<ImportFailure>> {
+		let conn = try!(self.conn());
+		let stmt = try!( conn.prepare("SELECT user_id, entry, reason FROM class_import_failures ORDER BY user_id, entry") );
+		let rows = try!( stmt.query(&[]) );
+		Ok( rows.iter().map(|row| ImportFailure {
+			user_id: row.get(0), entry: row.get(1), reason: row.get(2),
+		}).collect() )
+	}
+}
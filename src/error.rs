@@ -0,0 +1,128 @@
+use postgres::error::{Error as pgError, ConnectError, SqlState};
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use config::ConfigError;
+use db::LoginFailure;
+
+#[derive(Debug)]
+pub enum IndusError {
+	Config(ConfigError),
+	/// Could not reach the database at all.
+	Connection(ConnectError),
+	/// Every pooled connection stayed busy for the whole checkout timeout.
+	PoolTimeout,
+	Query(pgError),
+	NotFound(String),
+	/// A row with the same unique key already exists.
+	Duplicate(String),
+	Validation(String),
+	Auth(LoginFailure),
+	/// The user is logged in but may not do the action named.
+	Forbidden(String),
+	/// The request does not fit the current state of what it changes, such
+	/// as a chunk of an upload sent at the wrong offset.
+	Conflict(String),
+	/// Reading or writing stored files failed.
+	Io(io::Error),
+}
+
+pub type IndusResult<T> = Result<T, IndusError>;
+
+impl fmt::Display for IndusError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			IndusError::Config(ref e) => write!(f, "configuration error: {}", e),
+			IndusError::Connection(ref e) => write!(f, "could not connect to the database: {}", e),
+			IndusError::PoolTimeout => write!(f, "timed out waiting for a database connection"),
+			IndusError::Query(ref e) => write!(f, "query failed: {}", e),
+			IndusError::NotFound(ref what) => write!(f, "{} not found", what),
+			IndusError::Duplicate(ref what) => write!(f, "duplicate {}", what),
+			IndusError::Validation(ref why) => write!(f, "invalid input: {}", why),
+			// Whether the account exists is left out, so as not to tell which
+			// usernames are taken.
+			IndusError::Auth(LoginFailure::NoAccount) | IndusError::Auth(LoginFailure::PasswordMismatch) =>
+				write!(f, "authentication failed: WrongCredentials"),
+			IndusError::Auth(f2) => write!(f, "authentication failed: {:?}", f2),
+			IndusError::Forbidden(ref action) => write!(f, "not allowed to {}", action),
+			IndusError::Conflict(ref why) => write!(f, "conflict: {}", why),
+			IndusError::Io(ref e) => write!(f, "file storage error: {}", e),
+		}
+	}
+}
+
+impl Error for IndusError {
+	fn description(&self) -> &str {
+		match *self {
+			IndusError::Config(_) => "configuration error",
+			IndusError::Connection(_) => "could not connect to the database",
+			IndusError::PoolTimeout => "timed out waiting for a database connection",
+			IndusError::Query(_) => "query failed",
+			IndusError::NotFound(_) => "not found",
+			IndusError::Duplicate(_) => "duplicate",
+			IndusError::Validation(_) => "invalid input",
+			IndusError::Auth(_) => "authentication failed",
+			IndusError::Forbidden(_) => "not allowed",
+			IndusError::Conflict(_) => "conflict",
+			IndusError::Io(_) => "file storage error",
+		}
+	}
+
+	fn cause(&self) -> Option<&Error> {
+		match *self {
+			IndusError::Config(ref e) => Some(e),
+			IndusError::Connection(ref e) => Some(e),
+			IndusError::Query(ref e) => Some(e),
+			IndusError::Io(ref e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<pgError> for IndusError {
+	fn from(e: pgError) -> IndusError {
+		match e {
+			pgError::DbError(ref db) if db.code() == &SqlState::UniqueViolation =>
+				return IndusError::Duplicate(db.detail().unwrap_or(db.message()).into()),
+			_ => {}
+		}
+		IndusError::Query(e)
+	}
+}
+
+/// Like `IndusError::from`, but reports a foreign key violation as
+/// `NotFound(what)`, for writes whose only foreign keys are ids from the caller.
+pub fn missing(e: pgError, what: String) -> IndusError {
+	match e {
+		pgError::DbError(ref db) if db.code() == &SqlState::ForeignKeyViolation =>
+			return IndusError::NotFound(what),
+		_ => {}
+	}
+	IndusError::from(e)
+}
+
+impl From<ConnectError> for IndusError {
+	#[inline] fn from(e: ConnectError) -> IndusError {
+		IndusError::Connection(e)
+	}
+}
+
+impl From<io::Error> for IndusError {
+	#[inline] fn from(e: io::Error) -> IndusError {
+		IndusError::Io(e)
+	}
+}
+
+impl From<ConfigError> for IndusError {
+	#[inline] fn from(e: ConfigError) -> IndusError {
+		IndusError::Config(e)
+	}
+}
+
+impl From<LoginFailure> for IndusError {
+	#[inline] fn from(f: LoginFailure) -> IndusError {
+		IndusError::Auth(f)
+	}
+}
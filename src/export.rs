@@ -0,0 +1,350 @@
+use csv;
+use postgres::GenericConnection;
+
+use rustc_serialize::Encodable;
+use rustc_serialize::json::{self, Json};
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use db::IndusDatabase;
+use error::{IndusError, IndusResult};
+use subjects;
+use users::{self, UserQuery};
+
+/// What can be exported. Nothing exported ever holds a password hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Export {
+	/// Every user with their profile.
+	Users,
+	/// Every student of every class, one row each.
+	Rosters,
+	/// The classes of every teacher, by block.
+	Timetables,
+	/// How many students and teachers there are.
+	Counts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidExport;
+
+impl FromStr for Export {
+	type Err = InvalidExport;
+	fn from_str(s: &str) -> Result<Export, InvalidExport> {
+		match s {
+			"users" => Ok(Export::Users),
+			"rosters" => Ok(Export::Rosters),
+			"timetables" => Ok(Export::Timetables),
+			"counts" => Ok(Export::Counts),
+			_ => Err(InvalidExport),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+	Csv,
+	Json,
+}
+
+impl FromStr for Format {
+	type Err = InvalidExport;
+	fn from_str(s: &str) -> Result<Format, InvalidExport> {
+		match &*s.to_lowercase() {
+			"csv" => Ok(Format::Csv),
+			"json" => Ok(Format::Json),
+			_ => Err(InvalidExport),
+		}
+	}
+}
+
+/// CSV, over HTTP and on the command line alike.
+impl Default for Format {
+	fn default() -> Format {
+		Format::Csv
+	}
+}
+
+impl Format {
+	pub fn mime(&self) -> &'static str {
+		match *self {
+			Format::Csv => "text/csv; charset=utf-8",
+			Format::Json => "application/json",
+		}
+	}
+}
+
+/// Narrows an export down. Students are filtered by their grade and section,
+/// classes by their grade and subject; a class matches a section if one of
+/// its students is in it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExportFilter {
+	pub grade: Option<i16>,
+	pub section: Option<String>,
+	/// A subject code, name or alias.
+	pub subject: Option<String>,
+}
+
+pub static USER_FIELDS: &'static [&'static str] = &[
+	"id", "username", "first_name", "last_name", "gender", "active", "deactivated", "grade", "section", "subject", "hod",
+];
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct RosterRow {
+	pub class_id: i32,
+	pub subject: String,
+	pub block: String,
+	pub class_grade: i16,
+	pub student_id: i32,
+	pub username: String,
+	pub first_name: String,
+	pub last_name: String,
+	pub grade: i16,
+	pub section: String,
+	pub level: Option<String>,
+}
+
+pub static ROSTER_FIELDS: &'static [&'static str] = &[
+	"class_id", "subject", "block", "class_grade", "student_id", "username", "first_name", "last_name", "grade", "section", "level",
+];
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct TimetableRow {
+	pub teacher_id: i32,
+	pub username: String,
+	pub first_name: String,
+	pub last_name: String,
+	pub block: String,
+	pub class_id: i32,
+	pub subject: String,
+	pub grade: i16,
+	pub students: i64,
+}
+
+pub static TIMETABLE_FIELDS: &'static [&'static str] = &[
+	"teacher_id", "username", "first_name", "last_name", "block", "class_id", "subject", "grade", "students",
+];
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct CountRow {
+	pub users: i64,
+	pub students: i64,
+	pub teachers: i64,
+}
+
+pub static COUNT_FIELDS: &'static [&'static str] = &["users", "students", "teachers"];
+
+/// The rows of an export, with their fields in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+	pub fields: Vec<String>,
+	pub rows: Vec<BTreeMap<String, Json>>,
+}
+
+impl Table {
+	pub fn new<T: Encodable>(fields: &[&str], items: &[T]) -> Table {
+		Table {
+			fields: fields.iter().map(|f| f.to_string()).collect(),
+			rows: items.iter().map(|item| {
+				match Json::from_str(&json::encode(item).unwrap()) {
+					Ok(Json::Object(row)) => row,
+					_ => panic!("export rows must encode as objects"),
+				}
+			}).collect(),
+		}
+	}
+
+	/// Keeps only `fields`, in that order. All fields are kept if it is empty.
+	pub fn select(mut self, fields: &[String]) -> IndusResult<Table> {
+		if fields.is_empty() {
+			return Ok(self)
+		}
+		for field in fields {
+			if !self.fields.contains(field) {
+				return Err(IndusError::Validation(format!(
+					"there is no field {:?}, only {}", field, self.fields.connect(", "))))
+			}
+		}
+		for row in self.rows.iter_mut() {
+			*row = row.iter().filter(|f| fields.contains(f.0)).map(|(k, v)| (k.clone(), v.clone())).collect();
+		}
+		self.fields = fields.to_vec();
+		Ok(self)
+	}
+
+	/// A header row and a row per record, with CRLF line endings as
+	/// spreadsheets expect. Missing and null values are empty.
+	pub fn csv(&self) -> String {
+		let mut writer = csv::Writer::from_memory().record_terminator(csv::RecordTerminator::CRLF);
+		writer.encode(&self.fields).unwrap();
+		for row in &self.rows {
+			let record = self.fields.iter().map(|f| match row.get(f) {
+				None | Some(&Json::Null) => String::new(),
+				Some(&Json::String(ref s)) => s.clone(),
+				Some(value) => value.to_string(),
+			}).collect::<Vec<_>>();
+			writer.encode(record).unwrap();
+		}
+		writer.as_string().into()
+	}
+
+	/// An array of objects.
+	pub fn json(&self) -> String {
+		json::encode(&self.rows).unwrap()
+	}
+
+	pub fn render(&self, format: Format) -> String {
+		match format {
+			Format::Csv => self.csv(),
+			Format::Json => self.json(),
+		}
+	}
+}
+
+fn subject_code(conn: &GenericConnection, filter: &ExportFilter) -> IndusResult<Option<String>> {
+	match filter.subject {
+		Some(ref name) => Ok(Some(try!(subjects::resolve(conn, name)).code().into())),
+		None => Ok(None),
+	}
+}
+
+/// Whether a class `c` matches `$1` to `$3`: grade, section and subject.
+const CLASS_FILTERS: &'static str =
+	"($1::SMALLINT IS NULL OR c.grade = $1)
+	AND ($2::VARCHAR IS NULL OR EXISTS (
+		SELECT 1 FROM enrollments f JOIN students fs ON fs.id = f.student_id WHERE f.class_id = c.id AND fs.section = $2))
+	AND ($3::VARCHAR IS NULL OR c.subject = $3)";
+
+fn rosters(conn: &GenericConnection, filter: &ExportFilter, subject: &Option<String>) -> IndusResult<Vec<RosterRow>> {
+	let stmt = try!( conn.prepare(
+		"SELECT c.id, c.subject, c.block, c.grade, u.id, u.username, u.first_name, u.last_name, s.grade, s.section, e.level
+		FROM classes c
+		JOIN enrollments e ON e.class_id = c.id
+		JOIN students s ON s.id = e.student_id
+		JOIN users u ON u.id = s.id
+		WHERE u.active AND ($1::SMALLINT IS NULL OR c.grade = $1) AND ($2::VARCHAR IS NULL OR s.section = $2)
+		AND ($3::VARCHAR IS NULL OR c.subject = $3)
+		ORDER BY c.subject, c.grade, c.block, lower(u.last_name), lower(u.first_name), u.id"
+	) );
+	let rows = try!( stmt.query(&[&filter.grade, &filter.section, subject]) );
+	Ok( rows.iter().map(|row| {
+		let (first_name, last_name, section): (String, String, String) = (row.get(6), row.get(7), row.get(9));
+		RosterRow {
+			class_id: row.get(0), subject: row.get(1), block: row.get(2), class_grade: row.get(3),
+			student_id: row.get(4), username: row.get(5),
+			first_name: first_name.trim().into(), last_name: last_name.trim().into(),
+			grade: row.get(8), section: section.trim().into(), level: row.get(10),
+		}
+	}).collect() )
+}
+
+fn timetables(conn: &GenericConnection, filter: &ExportFilter, subject: &Option<String>) -> IndusResult<Vec<TimetableRow>> {
+	let stmt = try!( conn.prepare(&format!(
+		"SELECT u.id, u.username, u.first_name, u.last_name, c.block, c.id, c.subject, c.grade,
+			(SELECT COUNT(*) FROM enrollments e WHERE e.class_id = c.id)
+		FROM teaching_assignments a
+		JOIN classes c ON c.id = a.class_id
+		JOIN users u ON u.id = a.teacher_id
+		WHERE u.active AND {}
+		ORDER BY lower(u.last_name), lower(u.first_name), u.id, c.block, c.grade, c.subject", CLASS_FILTERS
+	)) );
+	let rows = try!( stmt.query(&[&filter.grade, &filter.section, subject]) );
+	Ok( rows.iter().map(|row| {
+		let (first_name, last_name): (String, String) = (row.get(2), row.get(3));
+		TimetableRow {
+			teacher_id: row.get(0), username: row.get(1),
+			first_name: first_name.trim().into(), last_name: last_name.trim().into(),
+			block: row.get(4), class_id: row.get(5), subject: row.get(6), grade: row.get(7), students: row.get(8),
+		}
+	}).collect() )
+}
+
+/// Active students matching the filter, and active teachers of a class
+/// matching it.
+fn counts(conn: &GenericConnection, filter: &ExportFilter, subject: &Option<String>) -> IndusResult<CountRow> {
+	let stmt = try!( conn.prepare(&format!(
+		"SELECT
+			(SELECT COUNT(*) FROM students s JOIN users u ON u.id = s.id
+			WHERE u.active AND ($1::SMALLINT IS NULL OR s.grade = $1) AND ($2::VARCHAR IS NULL OR s.section = $2)
+			AND ($3::VARCHAR IS NULL OR EXISTS (
+				SELECT 1 FROM enrollments e JOIN classes c ON c.id = e.class_id WHERE e.student_id = s.id AND c.subject = $3))),
+			(SELECT COUNT(*) FROM teachers t JOIN users u ON u.id = t.id
+			WHERE u.active AND (($1::SMALLINT IS NULL AND $2::VARCHAR IS NULL AND $3::VARCHAR IS NULL) OR EXISTS (
+				SELECT 1 FROM teaching_assignments a JOIN classes c ON c.id = a.class_id WHERE a.teacher_id = t.id AND {})))",
+		CLASS_FILTERS
+	)) );
+	let rows = try!( stmt.query(&[&filter.grade, &filter.section, subject]) );
+	let row = rows.get(0);
+	let (students, teachers): (i64, i64) = (row.get(0), row.get(1));
+	Ok(CountRow { users: students + teachers, students: students, teachers: teachers })
+}
+
+impl IndusDatabase {
+	pub fn export(&self, export: Export, filter: &ExportFilter) -> IndusResult<Table> {
+		let conn = try!(self.conn());
+		let subject = try!(subject_code(&*conn, filter));
+		Ok( match export {
+			Export::Users => {
+				// Like the other exports, only those who have not left.
+				let query = UserQuery {
+					grade: filter.grade, section: filter.section.clone(), subject: subject, active: Some(true),
+					..Default::default()
+				};
+				Table::new(USER_FIELDS, &try!(users::list(&*conn, &query)))
+			}
+			Export::Rosters => Table::new(ROSTER_FIELDS, &try!(rosters(&*conn, filter, &subject))),
+			Export::Timetables => Table::new(TIMETABLE_FIELDS, &try!(timetables(&*conn, filter, &subject))),
+			Export::Counts => Table::new(COUNT_FIELDS, &[try!(counts(&*conn, filter, &subject))]),
+		} )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(RustcEncodable)]
+	struct Row {
+		name: String,
+		grade: Option<i16>,
+		hod: bool,
+	}
+
+	fn table() -> Table {
+		Table::new(&["name", "grade", "hod"], &[
+			Row { name: "Medhi, Anshuman".into(), grade: Some(11), hod: false },
+			Row { name: "Hari \"the lion\" Prasad".into(), grade: None, hod: true },
+		])
+	}
+
+	#[test]
+	fn csv() {
+		assert_eq!( table().csv(), "name,grade,hod\r\n\"Medhi, Anshuman\",11,false\r\n\"Hari \"\"the lion\"\" Prasad\",,true\r\n" );
+		let selected = table().select(&["hod".into(), "name".into()]).unwrap();
+		assert_eq!( selected.csv().lines().next(), Some("hod,name") );
+		assert!( table().select(&["password".into()]).is_err() );
+	}
+
+	#[test]
+	fn json() {
+		assert_eq!( table().select(&["grade".into()]).unwrap().json(), "[{\"grade\":11},{\"grade\":null}]" );
+		assert_eq!( Table::new(&["name"], &[] as &[Row]).json(), "[]" );
+	}
+
+	#[test]
+	fn fields_match_rows() {
+		use data::Gender;
+		use users::UserSummary;
+		let user = UserSummary {
+			id: 1, username: "a.b".into(), first_name: "A".into(), last_name: "B".into(), gender: Gender::Female,
+			active: true, deactivated: None, grade: None, section: None, subject: None, hod: false,
+		};
+		let count = CountRow { users: 0, students: 0, teachers: 0 };
+		for &(fields, ref row) in &[(USER_FIELDS, Table::new(USER_FIELDS, &[user]).rows[0].clone()),
+			(COUNT_FIELDS, Table::new(COUNT_FIELDS, &[count]).rows[0].clone())] {
+			assert_eq!( row.len(), fields.len() );
+			assert!( fields.iter().all(|f| row.contains_key(*f)) );
+			assert!( !row.contains_key("password") );
+		}
+	}
+}
@@ -0,0 +1,310 @@
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use postgres::types::ToSql;
+
+use chrono::UTC;
+
+use data::Subject;
+use db::IndusDatabase;
+use enrollment::ClassId;
+use error::{self, IndusError, IndusResult};
+use roles::{Grants, Scope, Target};
+use storage::{self, FileInfo, FileQuery};
+use subjects;
+
+/// The name of every personal folder.
+pub const PERSONAL: &'static str = "Personal";
+
+const FOLDER_COLUMNS: &'static str = "SELECT id, parent_id, name, class_id, owner_id, subject, created FROM folders";
+
+/// The tree a folder belongs to, which decides who may use it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Space {
+	/// Written by the teachers of the class, read by its students.
+	Class(ClassId),
+	/// Only for the user.
+	Personal(i32),
+	/// Written by the head of the department, read by its teachers.
+	Department(Subject),
+}
+
+/// What a user may do in a space. Each level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+	None,
+	/// List and download files.
+	Read,
+	/// Also upload and delete files and make and remove folders.
+	Write,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct FolderInfo {
+	pub id: i32,
+	/// `None` for the root of a space.
+	pub parent: Option<i32>,
+	pub name: String,
+	/// Exactly one of `class`, `owner` and `subject` is set.
+	pub class: Option<ClassId>,
+	pub owner: Option<i32>,
+	pub subject: Option<Subject>,
+	pub created: i64,
+}
+
+impl FolderInfo {
+	pub fn space(&self) -> Space {
+		match (self.class, self.owner, self.subject.as_ref()) {
+			(Some(class), _, _) => Space::Class(class),
+			(_, Some(owner), _) => Space::Personal(owner),
+			(_, _, Some(subject)) => Space::Department(subject.clone()),
+			_ => unreachable!("folders belong to exactly one space"),
+		}
+	}
+}
+
+/// A folder with what is in it.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct FolderListing {
+	pub folder: FolderInfo,
+	/// Whether the user may change what is in the folder.
+	pub writable: bool,
+	pub folders: Vec<FolderInfo>,
+	pub files: Vec<FileInfo>,
+}
+
+fn info(row: &Row) -> FolderInfo {
+	let subject: Option<String> = row.get(5);
+	FolderInfo {
+		id: row.get(0),
+		parent: row.get(1),
+		name: row.get(2),
+		class: row.get(3),
+		owner: row.get(4),
+		subject: subject.and_then(|s| s.parse().ok()),
+		created: row.get(6),
+	}
+}
+
+fn fetch(conn: &GenericConnection, condition: &str, args: &[&ToSql]) -> IndusResult<Vec<FolderInfo>> {
+	let stmt = try!( conn.prepare(&format!("{} WHERE {} ORDER BY lower(name), id", FOLDER_COLUMNS, condition)) );
+	let rows = try!( stmt.query(args) );
+	Ok( rows.iter().map(|row| info(&row)).collect() )
+}
+
+pub fn get(conn: &GenericConnection, id: i32) -> IndusResult<FolderInfo> {
+	try!(fetch(conn, "id = $1", &[&id])).pop().ok_or(IndusError::NotFound(format!("folder {}", id)))
+}
+
+/// The class, owner and subject columns of a space.
+fn columns(space: &Space) -> (Option<ClassId>, Option<i32>, Option<&str>) {
+	match *space {
+		Space::Class(class) => (Some(class), None, None),
+		Space::Personal(owner) => (None, Some(owner), None),
+		Space::Department(ref subject) => (None, None, Some(subject.code())),
+	}
+}
+
+fn describe(space: &Space) -> String {
+	match *space {
+		Space::Class(class) => format!("class {}", class),
+		Space::Personal(owner) => format!("user {}", owner),
+		Space::Department(ref subject) => format!("subject {}", subject),
+	}
+}
+
+fn find_root(conn: &GenericConnection, space: &Space) -> IndusResult<Option<FolderInfo>> {
+	let (class, owner, subject) = columns(space);
+	Ok( try!(fetch(conn,
+		"parent_id IS NULL AND (class_id = $1 OR owner_id = $2 OR subject = $3)",
+		&[&class, &owner, &subject])).pop() )
+}
+
+/// The top folder of a space, made the first time something is uploaded to
+/// it.
+pub fn root(conn: &GenericConnection, space: &Space) -> IndusResult<FolderInfo> {
+	if let Some(folder) = try!(find_root(conn, space)) {
+		return Ok(folder)
+	}
+	let name = match *space {
+		Space::Class(class) => {
+			let stmt = try!( conn.prepare("SELECT subject || ' ' || block || ' grade ' || grade FROM classes WHERE id = $1") );
+			let rows = try!( stmt.query(&[&class]) );
+			if rows.is_empty() {
+				return Err(IndusError::NotFound(describe(space)))
+			}
+			rows.get(0).get(0)
+		}
+		Space::Personal(_) => PERSONAL.to_string(),
+		Space::Department(ref subject) => subject.code().to_string(),
+	};
+	let (class, owner, subject) = columns(space);
+	// Made in a savepoint, as another request may be making the same root.
+	let sp = try!(conn.transaction());
+	match sp.execute(
+		"INSERT INTO folders (name, class_id, owner_id, subject, created) VALUES ($1, $2, $3, $4, $5)",
+		&[&name, &class, &owner, &subject, &UTC::now().timestamp()]
+	).map_err(|e| error::missing(e, describe(space))) {
+		Ok(_) => try!(sp.commit()),
+		Err(IndusError::Duplicate(_)) => {}
+		Err(e) => return Err(e),
+	}
+	try!(find_root(conn, space)).ok_or(IndusError::NotFound(describe(space)))
+}
+
+/// The department of the subject a teacher teaches.
+fn teacher_department(conn: &GenericConnection, user_id: i32) -> IndusResult<Option<Subject>> {
+	let stmt = try!( conn.prepare("SELECT subject FROM teachers WHERE id = $1") );
+	let rows = try!( stmt.query(&[&user_id]) );
+	if rows.is_empty() {
+		return Ok(None)
+	}
+	match subjects::resolve(conn, &rows.get(0).get::<_, String>(0)) {
+		Ok(subject) => Ok(Some(subject)),
+		Err(IndusError::NotFound(_)) => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+/// What a user may do in a space. Write access to a class comes from
+/// teaching it and read access from being enrolled in it. Heads of
+/// department may read the classes of their department, and those who may
+/// view or create resources everywhere may read or write everything.
+pub fn access(conn: &GenericConnection, grants: &Grants, space: &Space) -> IndusResult<Access> {
+	if grants.scope("resource.create") == Some(Scope::All) {
+		return Ok(Access::Write)
+	}
+	let user = grants.user_id;
+	let (access, target) = match *space {
+		Space::Personal(owner) => (if owner == user { Access::Write } else { Access::None }, None),
+		Space::Class(class) => {
+			let stmt = try!( conn.prepare(
+				"SELECT 2 FROM teaching_assignments WHERE class_id = $1 AND teacher_id = $2
+				UNION ALL SELECT 1 FROM enrollments WHERE class_id = $1 AND student_id = $2
+				ORDER BY 1 DESC LIMIT 1"
+			) );
+			let rows = try!( stmt.query(&[&class, &user]) );
+			let access = match rows.iter().next().map(|row| row.get::<_, i32>(0)) {
+				Some(2) => Access::Write,
+				Some(_) => Access::Read,
+				None => Access::None,
+			};
+			(access, Some(Target::Class(class)))
+		}
+		Space::Department(ref subject) => {
+			let access = if grants.department.as_ref() == Some(subject) {
+				Access::Write
+			} else if try!(teacher_department(conn, user)).as_ref() == Some(subject) {
+				Access::Read
+			} else {
+				Access::None
+			};
+			(access, Some(Target::Subject(subject.clone())))
+		}
+	};
+	if access != Access::None {
+		return Ok(access)
+	}
+	let viewable = match target {
+		Some(target) => try!(grants.allows(conn, "resource.view", &target)),
+		None => grants.scope("resource.view") == Some(Scope::All),
+	};
+	Ok( if viewable { Access::Read } else { Access::None } )
+}
+
+/// The spaces a user takes part in: their own, the classes they teach or
+/// take and the department they teach in or head.
+pub fn spaces(conn: &GenericConnection, user_id: i32) -> IndusResult<Vec<Space>> {
+	let mut spaces = vec![Space::Personal(user_id)];
+	let stmt = try!( conn.prepare(
+		"SELECT class_id FROM teaching_assignments WHERE teacher_id = $1
+		UNION SELECT class_id FROM enrollments WHERE student_id = $1 ORDER BY 1"
+	) );
+	spaces.extend(try!(stmt.query(&[&user_id])).iter().map(|row| Space::Class(row.get(0))));
+	let stmt = try!( conn.prepare("SELECT subject FROM teachers WHERE id = $1 AND hod") );
+	let rows = try!( stmt.query(&[&user_id]) );
+	let headed = if rows.is_empty() {
+		None
+	} else {
+		subjects::resolve(conn, &rows.get(0).get::<_, String>(0)).ok()
+	};
+	for subject in try!(teacher_department(conn, user_id)).into_iter().chain(headed.into_iter()) {
+		let space = Space::Department(subject);
+		if !spaces.contains(&space) {
+			spaces.push(space);
+		}
+	}
+	Ok(spaces)
+}
+
+impl IndusDatabase {
+	pub fn folder(&self, id: i32) -> IndusResult<FolderInfo> {
+		let conn = try!(self.conn());
+		get(&*conn, id)
+	}
+
+	pub fn root_folder(&self, space: &Space) -> IndusResult<FolderInfo> {
+		let conn = try!(self.conn());
+		root(&*conn, space)
+	}
+
+	pub fn access(&self, grants: &Grants, space: &Space) -> IndusResult<Access> {
+		let conn = try!(self.conn());
+		access(&*conn, grants, space)
+	}
+
+	/// The top folders of the spaces a user takes part in. Spaces nothing has
+	/// been uploaded to yet have none.
+	pub fn root_folders(&self, user_id: i32) -> IndusResult<Vec<FolderInfo>> {
+		let conn = try!(self.conn());
+		let mut roots = Vec::new();
+		for space in try!(spaces(&*conn, user_id)) {
+			roots.extend(try!(find_root(&*conn, &space)).into_iter());
+		}
+		Ok(roots)
+	}
+
+	/// What is in a folder, as `grants` sees it.
+	pub fn folder_listing(&self, grants: &Grants, id: i32) -> IndusResult<FolderListing> {
+		let conn = try!(self.conn());
+		let folder = try!(get(&*conn, id));
+		Ok(FolderListing {
+			writable: try!(access(&*conn, grants, &folder.space())) == Access::Write,
+			folders: try!(fetch(&*conn, "parent_id = $1", &[&id])),
+			files: try!(storage::list(&*conn, &FileQuery { folder: Some(id), ..Default::default() })),
+			folder: folder,
+		})
+	}
+
+	/// Makes a folder inside another, in the same space.
+	pub fn create_folder(&self, parent: i32, name: &str) -> IndusResult<FolderInfo> {
+		try!(storage::check_name(name));
+		self.transaction(|tx| {
+			let parent = try!(get(tx, parent));
+			let stmt = try!( tx.prepare(
+				"INSERT INTO folders (parent_id, name, class_id, owner_id, subject, created)
+				VALUES ($1, $2, $3, $4, $5, $6) RETURNING id"
+			) );
+			let rows = try!( stmt.query(&[&parent.id, &name, &parent.class, &parent.owner,
+				&parent.subject.as_ref().map(|s| s.code()), &UTC::now().timestamp()]) );
+			get(tx, rows.get(0).get(0))
+		})
+	}
+
+	/// Removes an empty folder. The top folders of spaces stay.
+	pub fn delete_folder(&self, id: i32) -> IndusResult<()> {
+		self.transaction(|tx| {
+			let folder = try!(get(tx, id));
+			if folder.parent.is_none() {
+				return Err(IndusError::Validation(format!("folder {} is the top folder of its space", id)))
+			}
+			let stmt = try!( tx.prepare(
+				"SELECT 1 FROM files WHERE folder_id = $1 UNION ALL SELECT 1 FROM folders WHERE parent_id = $1 LIMIT 1"
+			) );
+			if !try!(stmt.query(&[&id])).is_empty() {
+				return Err(IndusError::Validation(format!("folder {} is not empty", id)))
+			}
+			try!( tx.execute("DELETE FROM folders WHERE id = $1", &[&id]) );
+			Ok(())
+		})
+	}
+}
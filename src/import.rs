@@ -0,0 +1,426 @@
+use csv;
+use postgres::Transaction;
+
+use std::io::Read;
+use std::str::FromStr;
+
+use crypt;
+use data::{Class, Classes, Gender, IndusStudent, IndusTeacher, IndusUser, InvalidClass, StudentTeacher};
+use db::IndusDatabase;
+use error::{IndusError, IndusResult};
+
+/// Rows inserted per transaction when the options do not say.
+pub const BATCH_SIZE: usize = 100;
+
+/// What a column of the file holds. Fields are named after those of
+/// `IndusUser`, plus `type`, `password` and `classes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+	/// `student` or `teacher`. Without this column a row is a student if it
+	/// has a grade and a teacher if it has a subject.
+	Kind,
+	FirstName,
+	LastName,
+	Gender,
+	Grade,
+	Section,
+	Subject,
+	Hod,
+	/// Classes in the text form of `Class`, or in the old comma separated
+	/// forms.
+	Classes,
+	/// The initial password. One is generated where this is empty.
+	Password,
+}
+
+static FIELDS: &'static [(Field, &'static str)] = &[
+	(Field::Kind, "type"),
+	(Field::FirstName, "first_name"),
+	(Field::LastName, "last_name"),
+	(Field::Gender, "gender"),
+	(Field::Grade, "grade"),
+	(Field::Section, "section"),
+	(Field::Subject, "subject"),
+	(Field::Hod, "hod"),
+	(Field::Classes, "classes"),
+	(Field::Password, "password"),
+];
+
+/// Lowercase without spaces, dashes and underscores, so that `First Name`
+/// is the same header as `first_name`.
+fn normalize(header: &str) -> String {
+	header.trim().chars()
+		.filter(|c| !c.is_whitespace() && *c != '_' && *c != '-')
+		.flat_map(char::to_lowercase)
+		.collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidField;
+
+impl FromStr for Field {
+	type Err = InvalidField;
+	fn from_str(s: &str) -> Result<Field, InvalidField> {
+		let name = normalize(s);
+		FIELDS.iter().find(|f| normalize(f.1) == name).map(|f| f.0).ok_or(InvalidField)
+	}
+}
+
+impl Field {
+	pub fn name(&self) -> &'static str {
+		FIELDS.iter().find(|f| f.0 == *self).map(|f| f.1).expect("every field has a name")
+	}
+}
+
+/// Which column of the file holds each field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+	columns: Vec<(Field, usize)>,
+}
+
+impl Mapping {
+	/// Maps every column whose header names a field. `overrides` maps fields
+	/// to columns with other headers, such as `(Field::FirstName, "Given name")`.
+	pub fn from_headers(headers: &[String], overrides: &[(Field, String)]) -> IndusResult<Mapping> {
+		let find = |header: &str| headers.iter().position(|h| normalize(h) == normalize(header));
+		let mut columns = Vec::new();
+		for &(field, ref header) in overrides {
+			match find(header) {
+				Some(i) => columns.push((field, i)),
+				None => return Err(IndusError::Validation(format!("there is no column {:?}", header))),
+			}
+		}
+		for (i, header) in headers.iter().enumerate() {
+			if let Ok(field) = header.parse::<Field>() {
+				if !columns.iter().any(|c| c.0 == field || c.1 == i) {
+					columns.push((field, i));
+				}
+			}
+		}
+		for field in &[Field::FirstName, Field::LastName, Field::Gender] {
+			if !columns.iter().any(|c| c.0 == *field) {
+				return Err(IndusError::Validation(format!("no column for {}", field.name())))
+			}
+		}
+		Ok(Mapping { columns: columns })
+	}
+
+	/// The trimmed value of a field in a record, unless it is empty.
+	fn get<'a>(&self, record: &'a [String], field: Field) -> Option<&'a str> {
+		self.columns.iter()
+			.find(|c| c.0 == field)
+			.and_then(|c| record.get(c.1))
+			.map(|v| v.trim())
+			.and_then(|v| if v.is_empty() { None } else { Some(v) })
+	}
+}
+
+/// A user read from a row of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewUser {
+	pub user: IndusUser,
+	pub password: Option<String>,
+}
+
+fn required<'a>(mapping: &Mapping, record: &'a [String], field: Field) -> Result<&'a str, String> {
+	mapping.get(record, field).ok_or_else(|| format!("{} is missing", field.name()))
+}
+
+fn classes<F>(src: Option<&str>, entry: F) -> Result<Vec<Class>, String>
+	where F: Fn(&str) -> Result<Class, InvalidClass> {
+	let src = match src {
+		Some(s) => s,
+		None => return Ok(Vec::new()),
+	};
+	if Classes::is_canonical(src) {
+		return Classes::parse(src).map_err(|e| format!("classes: {}", e))
+	}
+	src.split(',').map(str::trim).filter(|e| !e.is_empty())
+		.map(|e| entry(e).map_err(|err| format!("classes: {:?}: {}", e, err)))
+		.collect()
+}
+
+/// Reads and checks a record, without touching the database.
+pub fn parse_row(mapping: &Mapping, record: &[String]) -> Result<NewUser, String> {
+	let first_name = try!(required(mapping, record, Field::FirstName));
+	let last_name = try!(required(mapping, record, Field::LastName));
+	let gender = match &*try!(required(mapping, record, Field::Gender)).to_lowercase() {
+		"m" | "male" => Gender::Male,
+		"f" | "female" => Gender::Female,
+		g => return Err(format!("{:?} is not a gender", g)),
+	};
+	let kind = match mapping.get(record, Field::Kind) {
+		Some(k) => k.to_lowercase(),
+		None if mapping.get(record, Field::Grade).is_some() => "student".into(),
+		None if mapping.get(record, Field::Subject).is_some() => "teacher".into(),
+		None => return Err("type is missing, and there is no grade or subject to tell".into()),
+	};
+	let teacher = format!("{} {}", first_name, last_name);
+	let role = match &*kind {
+		"student" => {
+			let grade = try!(required(mapping, record, Field::Grade));
+			let grade: i16 = try!( grade.parse().map_err(|_| format!("{:?} is not a grade", grade)) );
+			let section = try!(required(mapping, record, Field::Section));
+			let mut chars = section.chars();
+			let section = match (chars.next(), chars.next()) {
+				(Some(c), None) => c,
+				_ => return Err(format!("section must be a single letter, not {:?}", section)),
+			};
+			StudentTeacher::Student(IndusStudent {
+				classes: try!(classes(mapping.get(record, Field::Classes), |e| Classes::student_entry(grade, e))),
+				grade: grade,
+				section: section,
+			})
+		}
+		"teacher" => {
+			let subject = try!(required(mapping, record, Field::Subject));
+			let hod = match mapping.get(record, Field::Hod).map(|h| h.to_lowercase()) {
+				None => false,
+				Some(h) => match &*h {
+					"yes" | "y" | "true" | "1" => true,
+					"no" | "n" | "false" | "0" => false,
+					_ => return Err(format!("{:?} is not yes or no", h)),
+				},
+			};
+			StudentTeacher::Teacher(IndusTeacher {
+				classes: try!(classes(mapping.get(record, Field::Classes), |e| Classes::teacher_entry(&teacher, subject, e))),
+				subject: subject.into(),
+				hod: hod,
+			})
+		}
+		k => return Err(format!("{:?} is not student or teacher", k)),
+	};
+	Ok(NewUser {
+		user: IndusUser { first_name: first_name.into(), last_name: last_name.into(), gender: gender, role: role },
+		password: mapping.get(record, Field::Password).map(String::from),
+	})
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+	/// Check every row, and insert them to see what the database makes of
+	/// them, but roll everything back.
+	pub dry_run: bool,
+	/// Rows per transaction; `BATCH_SIZE` if 0.
+	pub batch_size: usize,
+	pub overrides: Vec<(Field, String)>,
+}
+
+impl ImportOptions {
+	#[inline] pub fn new() -> ImportOptions {
+		ImportOptions { dry_run: false, batch_size: BATCH_SIZE, overrides: Vec::new() }
+	}
+}
+
+/// A row that was not imported. Rows are counted from 1, after the header.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct RowError {
+	pub row: usize,
+	pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct Created {
+	pub row: usize,
+	pub id: i32,
+	pub username: String,
+	pub first_name: String,
+	pub last_name: String,
+	/// The generated password, if the row had none. Never set on a dry run,
+	/// as those users are not kept.
+	pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct ImportReport {
+	pub dry_run: bool,
+	pub rows: usize,
+	/// On a dry run, the users that would have been created. Their ids and
+	/// usernames may differ on the real run.
+	pub created: Vec<Created>,
+	pub errors: Vec<RowError>,
+	/// Why the import stopped partway, if it did. The batches before were
+	/// kept and are in `created`; the rest of the rows were not imported.
+	pub failed: Option<String>,
+}
+
+impl ImportReport {
+	/// The generated passwords as CSV, for handing out.
+	pub fn passwords_csv(&self) -> String {
+		let mut writer = csv::Writer::from_memory().record_terminator(csv::RecordTerminator::CRLF);
+		writer.encode(("username", "first_name", "last_name", "password")).unwrap();
+		for c in self.created.iter().filter(|c| c.password.is_some()) {
+			writer.encode((&c.username, &c.first_name, &c.last_name, c.password.as_ref().unwrap())).unwrap();
+		}
+		writer.as_string().into()
+	}
+}
+
+/// Creates one user inside a savepoint of `tx`. Class entries that can't be
+/// imported fail the row rather than end up in `class_import_failures`.
+fn create(tx: &Transaction, new: &NewUser, password: &str) -> IndusResult<(i32, String)> {
+	let sp = try!(tx.transaction());
+	let user = &new.user;
+	let (id, failures) = try!( match user.role {
+		StudentTeacher::Student(ref s) => IndusDatabase::add_student(&sp, &user.first_name, &user.last_name,
+			user.gender.into(), &Classes::format(&s.classes), s.grade, &s.section.to_string(), password),
+		StudentTeacher::Teacher(ref t) => IndusDatabase::add_teacher(&sp, &user.first_name, &user.last_name,
+			user.gender.into(), &t.subject, &Classes::format(&t.classes), t.hod, password),
+	} );
+	if let Some(failure) = failures.first() {
+		return Err(IndusError::Validation(format!("classes: {:?}: {}", failure.entry, failure.reason)))
+	}
+	let username: String = {
+		let stmt = try!( sp.prepare("SELECT username FROM users WHERE id = $1") );
+		let rows = try!( stmt.query(&[&id]) );
+		rows.get(0).get(0)
+	};
+	try!(sp.commit());
+	Ok((id, username))
+}
+
+impl IndusDatabase {
+	/// Creates users from a CSV file with a header row. Every row is checked
+	/// first; rows with errors are reported and skipped, the others are
+	/// inserted in batches, each in its own transaction. If a batch cannot be
+	/// committed, the report of the batches before it is still returned, with
+	/// `failed` set, so that their generated passwords are not lost.
+	pub fn import_users<R: Read>(&self, src: R, options: &ImportOptions) -> IndusResult<ImportReport> {
+		let mut reader = csv::Reader::from_reader(src);
+		let headers = try!( reader.headers().map_err(|e| IndusError::Validation(format!("could not read the header: {}", e))) );
+		let mapping = try!(Mapping::from_headers(&headers, &options.overrides));
+
+		let mut report = ImportReport {
+			dry_run: options.dry_run, rows: 0, created: Vec::new(), errors: Vec::new(), failed: None,
+		};
+		let mut valid = Vec::new();
+		for (i, record) in reader.records().enumerate() {
+			report.rows += 1;
+			let row = i + 1;
+			let record = match record {
+				Ok(r) => r,
+				Err(e) => {
+					// the rest of the file can't be trusted to line up
+					report.errors.push(RowError { row: row, message: e.to_string() });
+					break
+				}
+			};
+			let checked = match parse_row(&mapping, &record) {
+				Ok(new) => {
+					let policy = new.password.as_ref().map_or(Ok(()), |p| self.policy.check(p));
+					policy.map(|_| new).map_err(|e| e.to_string())
+				}
+				Err(message) => Err(message),
+			};
+			match checked {
+				Ok(new) => valid.push((row, new)),
+				Err(message) => report.errors.push(RowError { row: row, message: message }),
+			}
+		}
+
+		let batch_size = if options.batch_size == 0 { BATCH_SIZE } else { options.batch_size };
+		let conn = try!(self.conn());
+		for batch in valid.chunks(batch_size) {
+			let tx = match conn.transaction() {
+				Ok(tx) => tx,
+				Err(e) => {
+					report.failed = Some(format!("stopped at row {}: {}", batch[0].0, e));
+					break
+				}
+			};
+			let (mut created, mut errors) = (Vec::new(), Vec::new());
+			for &(row, ref new) in batch {
+				let generated = match new.password {
+					Some(_) => None,
+					None => Some(crypt::random_password(&self.policy)),
+				};
+				let password = new.password.as_ref().or(generated.as_ref()).unwrap();
+				match create(&tx, new, password) {
+					Ok((id, username)) => created.push(Created {
+						row: row, id: id, username: username,
+						first_name: new.user.first_name.clone(), last_name: new.user.last_name.clone(),
+						password: if options.dry_run { None } else { generated },
+					}),
+					Err(e) => errors.push(RowError { row: row, message: e.to_string() }),
+				}
+			}
+			if !options.dry_run {
+				if let Err(e) = tx.commit() {
+					report.failed = Some(format!("stopped at row {}: {}", batch[0].0, e));
+					break
+				}
+			}
+			report.created.extend(created.into_iter());
+			report.errors.extend(errors.into_iter());
+		}
+		report.errors.sort_by(|a, b| a.row.cmp(&b.row));
+		Ok(report)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use data::{Gender, StudentTeacher};
+
+	fn record(fields: &[&str]) -> Vec<String> {
+		fields.iter().map(|f| f.to_string()).collect()
+	}
+
+	fn mapping(headers: &[&str]) -> Mapping {
+		Mapping::from_headers(&record(headers), &[]).unwrap()
+	}
+
+	#[test]
+	fn headers() {
+		assert_eq!( "First Name".parse(), Ok(Field::FirstName) );
+		assert_eq!( "LAST_NAME".parse(), Ok(Field::LastName) );
+		assert_eq!( "type".parse(), Ok(Field::Kind) );
+		assert!( "email".parse::<Field>().is_err() );
+		assert!( Mapping::from_headers(&record(&["first_name", "last_name"]), &[]).is_err() );
+		let m = Mapping::from_headers(
+			&record(&["Given name", "Surname", "Sex", "first_name"]),
+			&[(Field::FirstName, "given name".into()), (Field::LastName, "Surname".into()), (Field::Gender, "Sex".into())]
+		).unwrap();
+		assert_eq!( m.get(&record(&["Anshuman", "Medhi", "M", "Rohan"]), Field::FirstName), Some("Anshuman") );
+		assert!( Mapping::from_headers(&record(&["a"]), &[(Field::FirstName, "b".into())]).is_err() );
+	}
+
+	#[test]
+	fn rows() {
+		let m = mapping(&["first_name", "last_name", "gender", "grade", "section", "subject", "hod", "classes", "password"]);
+		let student = parse_row(&m, &record(&["Anshuman", "Medhi", "m", "11", "B", "", "", "C3 Math HL, C4 Economics SL", ""])).unwrap();
+		assert_eq!( student.user.gender, Gender::Male );
+		assert_eq!( student.password, None );
+		match student.user.role {
+			StudentTeacher::Student(ref s) => {
+				assert_eq!( (s.grade, s.section, s.classes.len()), (11, 'B', 2) );
+				assert_eq!( s.classes[0].block, "C3" );
+			}
+			_ => panic!("not a student"),
+		}
+		let teacher = parse_row(&m, &record(&[" Hari ", "Prasad", "Male", "", "", "Economics", "yes", "C4 - 11", "killthelion1"])).unwrap();
+		assert_eq!( teacher.user.first_name, "Hari" );
+		assert_eq!( teacher.password, Some("killthelion1".into()) );
+		match teacher.user.role {
+			StudentTeacher::Teacher(ref t) => {
+				assert!( t.hod );
+				assert_eq!( t.classes[0].teacher, "Hari Prasad" );
+				assert_eq!( t.classes[0].grade, 11 );
+			}
+			_ => panic!("not a teacher"),
+		}
+
+		let fails = |fields: &[&str]| parse_row(&m, &record(fields)).is_err();
+		assert!( fails(&["", "Medhi", "M", "11", "B", "", "", "", ""]) );
+		assert!( fails(&["Anshuman", "Medhi", "X", "11", "B", "", "", "", ""]) );
+		assert!( fails(&["Anshuman", "Medhi", "M", "eleven", "B", "", "", "", ""]) );
+		assert!( fails(&["Anshuman", "Medhi", "M", "11", "BC", "", "", "", ""]) );
+		assert!( fails(&["Anshuman", "Medhi", "M", "", "", "", "", "", ""]) );
+		assert!( fails(&["Anshuman", "Medhi", "M", "11", "B", "", "", "C3", ""]) );
+		assert!( fails(&["Hari", "Prasad", "M", "", "", "Economics", "maybe", "", ""]) );
+		assert!( fails(&["Hari", "Prasad", "M", "", "", "Economics", "", "C4 - eleven", ""]) );
+		// a short row is missing its last fields
+		assert!( !fails(&["Anshuman", "Medhi", "M", "11", "B"]) );
+	}
+}
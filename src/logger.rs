@@ -1,10 +1,12 @@
 use log::{LogRecord, LogLevel, LogMetadata, self, SetLoggerError, LogLevelFilter};
 
-struct SimpleLogger;
+struct SimpleLogger {
+    level: LogLevelFilter,
+}
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &LogMetadata) -> bool {
-        metadata.level() <= LogLevel::Info
+        metadata.level() <= self.level
     }
 
     fn log(&self, record: &LogRecord) {
@@ -16,9 +18,9 @@ impl log::Log for SimpleLogger {
 
 
 
-fn init() -> Result<(), SetLoggerError> {
+pub fn init(level: LogLevelFilter) -> Result<(), SetLoggerError> {
    	log::set_logger(|lvl| {
-   		lvl.set(LogLevelFilter::Info);
-   		Box::new(SimpleLogger)
+   		lvl.set(level);
+   		Box::new(SimpleLogger { level: level })
   	})
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-#![feature(plugin, test, str_char)]
+#![feature(plugin, test, str_char, static_mutex)]
 #![plugin(maud_macros)]
 // #![plugin(postgres_macros)]
 
@@ -6,17 +6,21 @@
 extern crate log;
 
 extern crate test;
+#[cfg(test)]
+extern crate quickcheck;
 
 extern crate postgres;
 
 extern crate iron;
-//extern crate router;
+extern crate router;
+extern crate hyper;
 //extern crate plugin;
 extern crate maud;
 
 extern crate rustc_serialize;
 
 extern crate crypto;
+extern crate openssl;
 
 // extern crate mio;
 
@@ -24,23 +28,265 @@ extern crate scoped_threadpool;
 extern crate threadpool;
 
 extern crate chrono;
+extern crate rand;
+extern crate cookie;
+extern crate csv;
 
 pub mod server;
 pub mod db;
 pub mod crypt;
 pub mod data;
+pub mod session;
+pub mod config;
+pub mod migrations;
+pub mod error;
+pub mod pool;
+pub mod usernames;
+pub mod enrollment;
+pub mod subjects;
+pub mod programme;
+pub mod roles;
+pub mod users;
+pub mod import;
+pub mod export;
+pub mod storage;
+pub mod folders;
+pub mod uploads;
+pub mod versions;
+pub mod s3;
 mod logger;
 
 use db::{IndusDatabase};
+use import::ImportOptions;
+use export::{Export, ExportFilter, Format};
+use config::{Backend, Config};
+use crypt::random_secret;
+use storage::Storage;
+use versions::Retention;
 use data::{Counts, Gender};
 use postgres::{Connection, IntoConnectParams, ConnectParams, UserInfo, ConnectTarget, SslMode};
 use rustc_serialize::json;
-use std::fs::File;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
 use std::io::{Write, self};
+use std::os::unix::fs::OpenOptionsExt;
+
+fn usage(text: &str) -> ! {
+	let _ = writeln!(&mut io::stderr(), "usage: {}", text);
+	::std::process::exit(2);
+}
+
+fn fail<E: Display>(e: E) -> ! {
+	let _ = writeln!(&mut io::stderr(), "indus: {}", e);
+	::std::process::exit(1);
+}
+
+fn connect(config: &Config) -> IndusDatabase {
+	match IndusDatabase::with_config(config) {
+		Ok(database) => database,
+		Err(e) => {
+			let _ = writeln!(&mut io::stderr(), "indus: {}", e);
+			::std::process::exit(1);
+		}
+	}
+}
+
+fn migrate(config: &Config, status_only: bool) {
+	let database = connect(config);
+	let result = if status_only {
+		database.pending_migrations()
+	} else {
+		database.migrate()
+	};
+	match result {
+		Ok(ref migrations) if migrations.is_empty() => println!("Schema is up to date"),
+		Ok(migrations) => for m in migrations {
+			println!("{} {:>4} {}", if status_only { "pending" } else { "applied" }, m.version, m.name);
+		},
+		Err(e) => {
+			let _ = writeln!(&mut io::stderr(), "indus: migration failed: {}", e);
+			::std::process::exit(1);
+		}
+	}
+}
+
+/// Prints a password reset token for a user, to be handed to them in person.
+fn reset_password(config: &Config, username: Option<&String>) {
+	let username = match username {
+		Some(u) => u,
+		None => {
+			let _ = writeln!(&mut io::stderr(), "usage: indus reset-password <username>");
+			::std::process::exit(2);
+		}
+	};
+	let database = connect(config);
+	match database.user_id(username).and_then(|id| database.issue_reset_token(id)) {
+		Ok(token) => println!("{}", token),
+		Err(e) => {
+			let _ = writeln!(&mut io::stderr(), "indus: {}", e);
+			::std::process::exit(1);
+		}
+	}
+}
+
+/// Gives a role to a user or takes it away, mostly to make the first admin.
+fn role(config: &Config, grant: bool, username: Option<&String>, role: Option<&String>) {
+	let (username, role) = match (username, role) {
+		(Some(u), Some(r)) => (u, r),
+		_ => usage(if grant { "indus grant-role <username> <role>" } else { "indus revoke-role <username> <role>" }),
+	};
+	let database = connect(config);
+	let result = database.user_id(username).and_then(|id| if grant {
+		database.grant_role(id, role)
+	} else {
+		database.revoke_role(id, role)
+	});
+	if let Err(e) = result {
+		fail(e);
+	}
+}
+
+/// Creates users from a CSV file. Row errors and the summary go to stderr,
+/// generated passwords to `--passwords <file>` or else to stdout.
+fn import(config: &Config, args: &[String]) {
+	const USAGE: &'static str =
+		"indus import <file.csv> [--dry-run] [--batch-size <n>] [--map <field>=<column>]... [--passwords <file>]";
+	let mut options = ImportOptions::new();
+	let (mut path, mut passwords) = (None, None);
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match &**arg {
+			"--dry-run" => options.dry_run = true,
+			"--batch-size" => options.batch_size = match args.next().and_then(|n| n.parse().ok()) {
+				Some(n) => n,
+				None => usage(USAGE),
+			},
+			"--map" => {
+				let map = args.next().map(|m| m.splitn(2, '=').collect::<Vec<_>>()).unwrap_or(Vec::new());
+				match (map.get(0).and_then(|f| f.parse().ok()), map.get(1)) {
+					(Some(field), Some(column)) => options.overrides.push((field, column.to_string())),
+					_ => usage(USAGE),
+				}
+			}
+			"--passwords" => passwords = Some(args.next().unwrap_or_else(|| usage(USAGE))),
+			_ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+			_ => usage(USAGE),
+		}
+	}
+	let path = path.unwrap_or_else(|| usage(USAGE));
+	let file = File::open(path).unwrap_or_else(|e| fail(e));
+	let database = connect(config);
+	let report = database.import_users(file, &options).unwrap_or_else(|e| fail(e));
+	for error in &report.errors {
+		let _ = writeln!(&mut io::stderr(), "row {}: {}", error.row, error.message);
+	}
+	let _ = writeln!(&mut io::stderr(), "{} of {} rows {}", report.created.len(), report.rows,
+		if report.dry_run { "would be imported" } else { "imported" });
+	if report.created.iter().any(|c| c.password.is_some()) {
+		let csv = report.passwords_csv();
+		match passwords {
+			Some(out) => {
+				// Only the one running the import should be able to read them.
+				let written = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(out)
+					.and_then(|mut f| f.write_all(csv.as_bytes()));
+				if let Err(e) = written {
+					fail(e);
+				}
+			}
+			None => print!("{}", csv),
+		}
+	}
+	if let Some(ref why) = report.failed {
+		let _ = writeln!(&mut io::stderr(), "indus: import {}", why);
+	}
+	if !report.errors.is_empty() || report.failed.is_some() {
+		::std::process::exit(1);
+	}
+}
+
+/// Writes an export to `--output <file>` or stdout, as CSV unless told
+/// otherwise.
+fn export(config: &Config, args: &[String]) {
+	const USAGE: &'static str = "indus export <users|rosters|timetables|counts> [--format csv|json] \
+		[--fields <field,...>] [--grade <n>] [--section <s>] [--subject <subject>] [--output <file>]";
+	let export: Export = args.get(0).and_then(|e| e.parse().ok()).unwrap_or_else(|| usage(USAGE));
+	let (mut format, mut fields, mut output) = (Format::default(), Vec::new(), None);
+	let mut filter = ExportFilter::default();
+	let mut args = args[1..].iter();
+	while let Some(arg) = args.next() {
+		let value = args.next().unwrap_or_else(|| usage(USAGE));
+		match &**arg {
+			"--format" => format = value.parse().unwrap_or_else(|_| usage(USAGE)),
+			"--fields" => fields = value.split(',').map(|f| f.trim().to_string()).collect(),
+			"--grade" => filter.grade = Some(value.parse().unwrap_or_else(|_| usage(USAGE))),
+			"--section" => filter.section = Some(value.clone()),
+			"--subject" => filter.subject = Some(value.clone()),
+			"--output" => output = Some(value),
+			_ => usage(USAGE),
+		}
+	}
+	let database = connect(config);
+	let table = database.export(export, &filter).and_then(|t| t.select(&fields)).unwrap_or_else(|e| fail(e));
+	let rendered = table.render(format);
+	match output {
+		Some(out) => if let Err(e) = File::create(out).and_then(|mut f| f.write_all(rendered.as_bytes())) {
+			fail(e);
+		},
+		None => print!("{}", rendered),
+	}
+}
+
+/// Copies every stored object from one backend to another, checking each
+/// copy, so that `uploads.backend` can then be switched over.
+fn copy_objects(config: &Config, args: &[String]) {
+	const USAGE: &'static str = "indus copy-objects <local|s3> <local|s3>";
+	let kind = |i: usize| args.get(i).and_then(|a| a.parse::<Backend>().ok());
+	let (from, to) = match (kind(0), kind(1)) {
+		(Some(from), Some(to)) if from != to => (from, to),
+		_ => usage(USAGE),
+	};
+	let open = |kind| storage::backend(&config.uploads, kind, random_secret()).unwrap_or_else(|e| fail(e));
+	let (from, to) = (open(from), open(to));
+	let database = connect(config);
+	let report = database.copy_objects(&*from, &*to, &config.uploads.temp).unwrap_or_else(|e| fail(e));
+	for &(ref key, ref why) in &report.failed {
+		let _ = writeln!(&mut io::stderr(), "{}: {}", key, why);
+	}
+	let _ = writeln!(&mut io::stderr(), "{} copied, {} already there, {} failed",
+		report.copied.len(), report.skipped.len(), report.failed.len());
+	if !report.failed.is_empty() {
+		::std::process::exit(1);
+	}
+}
+
+/// Throws away resumable uploads that have been abandoned, for running from
+/// cron; starting an upload does the same.
+fn purge_uploads(config: &Config) {
+	let storage = Storage::new(&config.uploads, random_secret()).unwrap_or_else(|e| fail(e));
+	let database = connect(config);
+	let purged = database.purge_uploads(&storage).unwrap_or_else(|e| fail(e));
+	let _ = writeln!(&mut io::stderr(), "{} abandoned uploads removed", purged);
+}
+
+/// Deletes old versions of every file, keeping the newest `--keep <n>` or
+/// those made in the last `--days <n>`.
+fn prune_versions(config: &Config, args: &[String]) {
+	const USAGE: &'static str = "indus prune-versions (--keep <n> | --days <n>)";
+	let n = args.get(1).and_then(|n| n.parse().ok()).unwrap_or_else(|| usage(USAGE));
+	let retention = match args.get(0).map(|a| &**a) {
+		Some("--keep") if args.len() == 2 => Retention::Last(n),
+		Some("--days") if args.len() == 2 => Retention::Days(n),
+		_ => usage(USAGE),
+	};
+	let storage = Storage::new(&config.uploads, random_secret()).unwrap_or_else(|e| fail(e));
+	let database = connect(config);
+	let pruned = database.prune_versions(&storage, None, retention).unwrap_or_else(|e| fail(e));
+	let _ = writeln!(&mut io::stderr(), "{} old versions removed", pruned.len());
+}
 
 fn main() {
 	fn db_test() {
-		let mut database = IndusDatabase::new();
+		let mut database = IndusDatabase::new().unwrap();
 		database.insert_student("Anshuman", "Medhi", Gender::Male, 
 			"C1 English SL, C2 Spanish SL, C3 Math HL, C4 Economics SL, C5 Chemistry HL, C6 Physics HL",
 			11, "B", "2cool4uuu");
@@ -56,6 +302,29 @@ fn main() {
 		};
 		markup.render(&mut io::stdout()).unwrap();
 	}
-	db_test();
-	maud_test();
+	let config = match Config::load() {
+		Ok(c) => c,
+		Err(e) => {
+			let _ = writeln!(&mut io::stderr(), "indus: {}", e);
+			::std::process::exit(1);
+		}
+	};
+	logger::init(config.log_level).unwrap();
+	let args = ::std::env::args().skip(1).collect::<Vec<_>>();
+	match args.get(0).map(|a| &**a) {
+		Some("--test-db") => {
+			db_test();
+			maud_test();
+		},
+		Some("migrate") => migrate(&config, args.iter().any(|a| a == "--status")),
+		Some("reset-password") => reset_password(&config, args.get(1)),
+		Some("grant-role") => role(&config, true, args.get(1), args.get(2)),
+		Some("revoke-role") => role(&config, false, args.get(1), args.get(2)),
+		Some("import") => import(&config, &args[1..]),
+		Some("export") => export(&config, &args[1..]),
+		Some("copy-objects") => copy_objects(&config, &args[1..]),
+		Some("purge-uploads") => purge_uploads(&config),
+		Some("prune-versions") => prune_versions(&config, &args[1..]),
+		_ => server::run(&config, connect(&config)),
+	}
 }
\ No newline at end of file
@@ -0,0 +1,105 @@
+use postgres::{Connection, Transaction};
+use postgres::error::Error as pgError;
+
+use chrono::UTC;
+
+use enrollment;
+use error::IndusResult;
+
+/// A schema change, applied at most once and in order of `version`.
+pub struct Migration {
+	pub version: i32,
+	pub name: &'static str,
+	pub sql: &'static str,
+	/// Data changes that need Rust, run in the same transaction after `sql`.
+	/// They are written against the schema of their own migration, so they
+	/// must not use queries that later migrations may change.
+	pub after: Option<fn(&Transaction) -> IndusResult<()>>,
+}
+
+macro_rules! migration {
+	($version:expr, $name:expr) => {
+		migration!($version, $name, None)
+	};
+	($version:expr, $name:expr, $after:expr) => {
+		Migration {
+			version: $version, name: $name,
+			sql: include_str!(concat!("../migrations/", $name, ".sql")),
+			after: $after,
+		}
+	};
+}
+
+/// Every migration, oldest first. Never edit one that has been released; add
+/// a new one instead.
+pub static MIGRATIONS: &'static [Migration] = &[
+	migration!(1, "0001_users"),
+	migration!(2, "0002_sessions"),
+	migration!(3, "0003_user_ids"),
+	migration!(4, "0004_unique_usernames"),
+	migration!(5, "0005_password_resets"),
+	migration!(6, "0006_classes", Some(enrollment::import_legacy)),
+	migration!(7, "0007_subject_catalogue"),
+	migration!(8, "0008_levels_and_groups"),
+	migration!(9, "0009_roles"),
+	migration!(10, "0010_deactivated_users"),
+	migration!(11, "0011_files"),
+	migration!(12, "0012_folders"),
+	migration!(13, "0013_upload_sessions"),
+	migration!(14, "0014_file_versions"),
+];
+
+fn ensure_table(conn: &Connection) -> Result<(), pgError> {
+	conn.batch_execute(
+		"CREATE TABLE IF NOT EXISTS schema_migrations (
+			version INT PRIMARY KEY,
+			name TEXT NOT NULL,
+			applied BIGINT NOT NULL
+		)")
+}
+
+/// The version of the newest applied migration, or 0 for an empty database.
+pub fn current_version(conn: &Connection) -> Result<i32, pgError> {
+	try!(ensure_table(conn));
+	let stmt = try!(conn.prepare("SELECT COALESCE(MAX(version), 0) FROM schema_migrations"));
+	let rows = try!(stmt.query(&[]));
+	Ok(rows.get(0).get(0))
+}
+
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, pgError> {
+	let version = try!(current_version(conn));
+	Ok( MIGRATIONS.iter().filter(|m| m.version > version).collect() )
+}
+
+/// Applies every pending migration, each in its own transaction, and
+/// returns the ones that ran.
+pub fn migrate(conn: &Connection) -> IndusResult<Vec<&'static Migration>> {
+	let pending = try!(pending(conn));
+	for migration in &pending {
+		info!("Applying migration {} ({})", migration.version, migration.name);
+		let tx = try!(conn.transaction());
+		try!(tx.batch_execute(migration.sql));
+		if let Some(after) = migration.after {
+			try!(after(&tx));
+		}
+		try!(tx.execute(
+			"INSERT INTO schema_migrations (version, name, applied) VALUES ($1, $2, $3)",
+			&[&migration.version, &migration.name, &UTC::now().timestamp()]
+		));
+		try!(tx.commit());
+	}
+	Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ordered() {
+		for (i, migration) in MIGRATIONS.iter().enumerate() {
+			assert_eq!(migration.version, i as i32 + 1);
+			assert!(migration.name.starts_with(&format!("{:04}_", migration.version)));
+		}
+	}
+}
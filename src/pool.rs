@@ -0,0 +1,115 @@
+use postgres::Connection;
+
+use std::ops::Deref;
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+use config::DatabaseConfig;
+use error::{IndusError, IndusResult};
+
+struct State {
+	idle: Vec<Connection>,
+	/// Connections handed out or idle, including ones being opened.
+	open: usize,
+}
+
+/// A fixed-size pool of Postgres connections that can be shared between
+/// threads. Connections are opened lazily, checked before being handed out and
+/// replaced when they have gone bad.
+pub struct Pool {
+	config: DatabaseConfig,
+	size: usize,
+	timeout: Duration,
+	state: Mutex<State>,
+	available: Condvar,
+}
+
+/// A connection checked out of a `Pool`, returned to it on drop.
+pub struct PooledConnection<'a> {
+	pool: &'a Pool,
+	conn: Option<Connection>,
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+	type Target = Connection;
+	#[inline] fn deref(&self) -> &Connection {
+		self.conn.as_ref().unwrap()
+	}
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+	fn drop(&mut self) {
+		let conn = self.conn.take().unwrap();
+		let mut state = self.pool.state.lock().unwrap();
+		if conn.is_desynchronized() {
+			state.open -= 1;
+		} else {
+			state.idle.push(conn);
+		}
+		self.pool.available.notify_one();
+	}
+}
+
+#[inline]
+fn healthy(conn: &Connection) -> bool {
+	!conn.is_desynchronized() && conn.batch_execute("SELECT 1").is_ok()
+}
+
+impl Pool {
+	/// Creates a pool and opens its first connection, so that bad settings
+	/// are reported straight away.
+	pub fn new(config: DatabaseConfig, size: usize, timeout: Duration) -> IndusResult<Pool> {
+		let first = try!( Connection::connect(config.connect_params(), &try!(config.ssl_mode())) );
+		Ok( Pool {
+			config: config,
+			size: if size == 0 { 1 } else { size },
+			timeout: timeout,
+			state: Mutex::new(State { idle: vec![first], open: 1 }),
+			available: Condvar::new(),
+		} )
+	}
+
+	/// Checks out a connection, waiting up to the pool's timeout for one to
+	/// come free.
+	pub fn get(&self) -> IndusResult<PooledConnection> {
+		let deadline = Instant::now() + self.timeout;
+		let mut state = self.state.lock().unwrap();
+		loop {
+			if let Some(conn) = state.idle.pop() {
+				drop(state);
+				if healthy(&conn) {
+					return Ok(PooledConnection { pool: self, conn: Some(conn) })
+				}
+				warn!("Dropping broken database connection");
+				state = self.state.lock().unwrap();
+				state.open -= 1;
+				continue
+			}
+			if state.open < self.size {
+				state.open += 1;
+				drop(state);
+				let connected = self.config.ssl_mode().map_err(IndusError::from)
+					.and_then(|ssl| Connection::connect(self.config.connect_params(), &ssl).map_err(IndusError::from));
+				return match connected {
+					Ok(conn) => Ok(PooledConnection { pool: self, conn: Some(conn) }),
+					Err(e) => {
+						let mut state = self.state.lock().unwrap();
+						state.open -= 1;
+						self.available.notify_one();
+						Err(e)
+					}
+				}
+			}
+			let now = Instant::now();
+			if now >= deadline {
+				return Err(IndusError::PoolTimeout)
+			}
+			state = self.available.wait_timeout(state, deadline - now).unwrap().0;
+		}
+	}
+
+	#[inline]
+	pub fn size(&self) -> usize {
+		self.size
+	}
+}
@@ -0,0 +1,158 @@
+use data::{Class, Level, Subject};
+use db::IndusDatabase;
+use enrollment;
+use error::{IndusError, IndusResult};
+use subjects;
+
+/// Fewest subjects a Diploma Programme student takes at HL.
+pub const MIN_HL: usize = 3;
+
+/// How far a student's classes make up a full Diploma Programme.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct ProgrammeReport {
+	pub student_id: i32,
+	/// Groups 1 to 6 with no subject. Group 6 also counts as covered by a
+	/// second subject from groups 1 to 4, as the IB allows.
+	pub missing_groups: Vec<i16>,
+	/// Subjects taken at each level.
+	pub hl: usize,
+	pub sl: usize,
+	/// Group subjects the student has no level for.
+	pub no_level: Vec<Subject>,
+	/// Core subjects the student is not enrolled in.
+	pub missing_core: Vec<Subject>,
+	pub complete: bool,
+}
+
+/// Checks a student's classes against the programme requirements: a subject
+/// from each of the six groups, at least `MIN_HL` of them at HL and the whole
+/// `core`.
+pub fn check(student_id: i32, classes: &[Class], core: &[Subject]) -> ProgrammeReport {
+	let mut taken: Vec<&Class> = Vec::new();
+	for class in classes.iter().filter(|c| c.group.is_some()) {
+		if !taken.iter().any(|t| t.subject == class.subject) {
+			taken.push(class);
+		}
+	}
+	let in_group = |g: i16| taken.iter().filter(|c| c.group == Some(g)).count();
+	let mut missing_groups = (1..6).filter(|&g| in_group(g) == 0).collect::<Vec<i16>>();
+	let extra = (1..5).map(|g| in_group(g).saturating_sub(1)).fold(0, |a, b| a + b);
+	if in_group(6) == 0 && extra == 0 {
+		missing_groups.push(6);
+	}
+	let hl = taken.iter().filter(|c| c.level == Some(Level::HL)).count();
+	let sl = taken.iter().filter(|c| c.level == Some(Level::SL)).count();
+	let no_level = taken.iter().filter(|c| c.level.is_none()).map(|c| c.subject.clone()).collect::<Vec<_>>();
+	let missing_core = core.iter()
+		.filter(|s| !classes.iter().any(|c| c.subject == **s))
+		.cloned()
+		.collect::<Vec<_>>();
+	ProgrammeReport {
+		student_id: student_id,
+		complete: missing_groups.is_empty() && hl >= MIN_HL && no_level.is_empty() && missing_core.is_empty(),
+		missing_groups: missing_groups,
+		hl: hl,
+		sl: sl,
+		no_level: no_level,
+		missing_core: missing_core,
+	}
+}
+
+impl IndusDatabase {
+	/// Reports how complete a student's programme is.
+	pub fn programme(&self, student_id: i32) -> IndusResult<ProgrammeReport> {
+		let conn = try!(self.conn());
+		let stmt = try!( conn.prepare("SELECT 1 FROM students WHERE id = $1") );
+		if try!(stmt.query(&[&student_id])).is_empty() {
+			return Err(IndusError::NotFound(format!("student {}", student_id)))
+		}
+		let core = try!(subjects::core(&*conn));
+		let classes = try!(enrollment::student_classes(&*conn, student_id));
+		Ok(check(student_id, &classes, &core))
+	}
+
+	/// Reports on the programme of every student in a grade, or in the whole
+	/// school.
+	pub fn programmes(&self, grade: Option<i16>) -> IndusResult<Vec<ProgrammeReport>> {
+		let conn = try!(self.conn());
+		let stmt = try!( conn.prepare(
+			"SELECT id FROM students WHERE $1::SMALLINT IS NULL OR grade = $1 ORDER BY id"
+		) );
+		let ids = try!(stmt.query(&[&grade])).iter().map(|row| row.get(0)).collect::<Vec<i32>>();
+		let core = try!(subjects::core(&*conn));
+		let mut reports = Vec::with_capacity(ids.len());
+		for id in ids {
+			let classes = try!(enrollment::student_classes(&*conn, id));
+			reports.push(check(id, &classes, &core));
+		}
+		Ok(reports)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use data::{Class, Level, Subject};
+
+	fn subject(code: &str) -> Subject {
+		code.parse().unwrap()
+	}
+
+	fn class(code: &str, group: Option<i16>, level: Option<Level>) -> Class {
+		Class {
+			subject: subject(code), teacher: "".into(), block: "C1".into(), grade: 11,
+			level: level, group: group,
+		}
+	}
+
+	fn core() -> Vec<Subject> {
+		vec![subject("CAS"), subject("EE"), subject("TOK")]
+	}
+
+	fn full() -> Vec<Class> {
+		vec![
+			class("English", Some(1), Some(Level::SL)),
+			class("Spanish", Some(2), Some(Level::SL)),
+			class("Economics", Some(3), Some(Level::SL)),
+			class("Chemistry", Some(4), Some(Level::HL)),
+			class("Math", Some(5), Some(Level::HL)),
+			class("Physics", Some(4), Some(Level::HL)),
+			class("TOK", None, None),
+			class("EE", None, None),
+			class("CAS", None, None),
+		]
+	}
+
+	#[test]
+	fn complete() {
+		let report = check(1, &full(), &core());
+		assert!( report.complete );
+		assert!( report.missing_groups.is_empty() );
+		assert_eq!( (report.hl, report.sl), (3, 3) );
+	}
+
+	#[test]
+	fn group_six() {
+		let mut classes = full();
+		classes.remove(5);
+		assert_eq!( check(1, &classes, &core()).missing_groups, vec![6] );
+		classes.push(class("Art", Some(6), Some(Level::HL)));
+		assert!( check(1, &classes, &core()).complete );
+	}
+
+	#[test]
+	fn incomplete() {
+		let mut classes = full();
+		classes[0].group = None;
+		classes[3].level = Some(Level::SL);
+		classes[4].level = None;
+		classes.retain(|c| c.subject != subject("EE"));
+		let report = check(1, &classes, &core());
+		assert!( !report.complete );
+		// the second group 4 subject stands in for group 6
+		assert_eq!( report.missing_groups, vec![1] );
+		assert_eq!( report.hl, 1 );
+		assert_eq!( report.no_level, vec![subject("Math")] );
+		assert_eq!( report.missing_core, vec![subject("EE")] );
+	}
+}
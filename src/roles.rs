@@ -0,0 +1,406 @@
+use postgres::GenericConnection;
+
+use std::fmt;
+use std::str::FromStr;
+
+use data::Subject;
+use db::IndusDatabase;
+use enrollment::ClassId;
+use error::{missing, IndusError, IndusResult};
+use subjects;
+
+/// Roles every user of a kind has without being given them.
+pub static IMPLIED_ROLES: &'static [&'static str] = &["student", "teacher", "hod"];
+
+/// Longest name of a role.
+pub const ROLE_MAX_LEN: usize = 32;
+
+/// The names of the roles of a user, both given and implied by their type.
+const USER_ROLES: &'static str =
+	"SELECT role FROM user_roles WHERE user_id = $1
+	UNION SELECT 'student' FROM students WHERE id = $1
+	UNION SELECT 'teacher' FROM teachers WHERE id = $1
+	UNION SELECT 'hod' FROM teachers WHERE id = $1 AND hod";
+
+/// How much of what it is about a permission covers. Each scope includes the
+/// narrower ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+	/// Things of the user: themselves, what they own and the classes they
+	/// teach or take.
+	Own,
+	/// Things of the department the user heads.
+	Department,
+	All,
+}
+
+/// A permission such as `user.create` or `resource.delete:department`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permission {
+	/// The action, or a pattern of them: `*` or `class.*`.
+	pub action: String,
+	pub scope: Scope,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidPermission;
+
+impl Permission {
+	/// Whether this permission is about `action`, at any scope.
+	pub fn matches(&self, action: &str) -> bool {
+		if self.action == "*" {
+			return true
+		}
+		if self.action.ends_with(".*") {
+			let prefix = &self.action[..self.action.len() - 1];
+			return action.starts_with(prefix)
+		}
+		self.action == action
+	}
+}
+
+impl FromStr for Permission {
+	type Err = InvalidPermission;
+	fn from_str(s: &str) -> Result<Permission, InvalidPermission> {
+		let mut parts = s.splitn(2, ':');
+		let action = parts.next().unwrap_or("");
+		let scope = match parts.next() {
+			None | Some("all") => Scope::All,
+			Some("department") => Scope::Department,
+			Some("own") => Scope::Own,
+			Some(_) => return Err(InvalidPermission),
+		};
+		let valid = action == "*" || action.split('.').enumerate().all(|(i, part)| {
+			(part == "*" && i > 0 && action.ends_with(".*"))
+				|| (!part.is_empty() && part.chars().all(|c| match c { 'a'...'z' | '_' => true, _ => false }))
+		});
+		if !valid {
+			return Err(InvalidPermission)
+		}
+		Ok(Permission { action: action.into(), scope: scope })
+	}
+}
+
+impl fmt::Display for Permission {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.scope {
+			Scope::All => write!(f, "{}", self.action),
+			Scope::Department => write!(f, "{}:department", self.action),
+			Scope::Own => write!(f, "{}:own", self.action),
+		}
+	}
+}
+
+/// What a permission is checked against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+	/// Nothing in particular; only permissions for all of it allow this.
+	Any,
+	User(i32),
+	Class(ClassId),
+	Subject(Subject),
+	/// Something a user owns, possibly shared with a class.
+	Resource { owner: i32, class: Option<ClassId> },
+}
+
+impl Target {
+	/// The subject whose department the target belongs to: that of the
+	/// class, or the subject a teacher teaches.
+	fn department(&self, conn: &GenericConnection) -> IndusResult<Option<Subject>> {
+		let (query, id) = match *self {
+			Target::Any => return Ok(None),
+			Target::Subject(ref s) => return Ok(Some(s.clone())),
+			Target::Class(id) | Target::Resource { class: Some(id), .. } =>
+				("SELECT subject FROM classes WHERE id = $1", id),
+			Target::User(id) | Target::Resource { owner: id, class: None } =>
+				("SELECT subject FROM teachers WHERE id = $1", id),
+		};
+		let stmt = try!( conn.prepare(query) );
+		let rows = try!( stmt.query(&[&id]) );
+		if rows.is_empty() {
+			return Ok(None)
+		}
+		let name: String = rows.get(0).get(0);
+		match subjects::resolve(conn, &name) {
+			Ok(subject) => Ok(Some(subject)),
+			Err(IndusError::NotFound(_)) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Whether the target is the user's own.
+	fn belongs_to(&self, conn: &GenericConnection, user_id: i32) -> IndusResult<bool> {
+		match *self {
+			Target::Any | Target::Subject(_) => Ok(false),
+			Target::User(id) | Target::Resource { owner: id, .. } => Ok(id == user_id),
+			Target::Class(id) => {
+				let stmt = try!( conn.prepare(
+					"SELECT 1 FROM teaching_assignments WHERE class_id = $1 AND teacher_id = $2
+					UNION ALL SELECT 1 FROM enrollments WHERE class_id = $1 AND student_id = $2"
+				) );
+				Ok( !try!(stmt.query(&[&id, &user_id])).is_empty() )
+			}
+		}
+	}
+}
+
+/// Everything a user is allowed to do, from all of their roles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grants {
+	pub user_id: i32,
+	pub roles: Vec<String>,
+	pub permissions: Vec<Permission>,
+	/// The subject of the department the user heads.
+	pub department: Option<Subject>,
+}
+
+impl Grants {
+	pub fn load(conn: &GenericConnection, user_id: i32) -> IndusResult<Grants> {
+		let stmt = try!( conn.prepare(&format!("{} ORDER BY 1", USER_ROLES)) );
+		let roles = try!( stmt.query(&[&user_id]) ).iter().map(|row| row.get(0)).collect::<Vec<String>>();
+		let stmt = try!( conn.prepare(&format!(
+			"SELECT DISTINCT permission FROM role_permissions WHERE role IN ({}) ORDER BY 1", USER_ROLES
+		)) );
+		let mut permissions = Vec::new();
+		for row in try!(stmt.query(&[&user_id])).iter() {
+			let permission: String = row.get(0);
+			match permission.parse() {
+				Ok(p) => permissions.push(p),
+				Err(_) => warn!("Ignoring invalid permission {:?}", permission),
+			}
+		}
+		let stmt = try!( conn.prepare("SELECT subject FROM teachers WHERE id = $1 AND hod") );
+		let rows = try!( stmt.query(&[&user_id]) );
+		let department = if rows.is_empty() {
+			None
+		} else {
+			subjects::resolve(conn, &rows.get(0).get::<_, String>(0)).ok()
+		};
+		Ok(Grants { user_id: user_id, roles: roles, permissions: permissions, department: department })
+	}
+
+	/// The widest scope at which the user may do `action`.
+	pub fn scope(&self, action: &str) -> Option<Scope> {
+		self.permissions.iter().filter(|p| p.matches(action)).map(|p| p.scope).max()
+	}
+
+	/// Whether the user may do `action` to anything at all.
+	#[inline]
+	pub fn has(&self, action: &str) -> bool {
+		self.scope(action).is_some()
+	}
+
+	pub fn allows(&self, conn: &GenericConnection, action: &str, target: &Target) -> IndusResult<bool> {
+		let scope = match self.scope(action) {
+			Some(scope) => scope,
+			None => return Ok(false),
+		};
+		if scope == Scope::All {
+			return Ok(true)
+		}
+		if let (Scope::Department, Some(department)) = (scope, self.department.as_ref()) {
+			if try!(target.department(conn)).as_ref() == Some(department) {
+				return Ok(true)
+			}
+		}
+		target.belongs_to(conn, self.user_id)
+	}
+
+	/// Like `allows`, but fails with `IndusError::Forbidden`.
+	pub fn require(&self, conn: &GenericConnection, action: &str, target: &Target) -> IndusResult<()> {
+		if try!(self.allows(conn, action, target)) {
+			Ok(())
+		} else {
+			Err(IndusError::Forbidden(action.into()))
+		}
+	}
+}
+
+/// A role and the permissions it grants.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Role {
+	pub name: String,
+	pub description: String,
+	pub permissions: Vec<String>,
+}
+
+fn validate(role: &Role) -> IndusResult<()> {
+	let name = &role.name;
+	if name.is_empty() || name.len() > ROLE_MAX_LEN
+		|| !name.chars().all(|c| match c { 'a'...'z' | '0'...'9' | '-' | '_' => true, _ => false }) {
+		return Err(IndusError::Validation(format!(
+			"{:?} is not a valid role name: use up to {} lowercase letters, digits, - and _", name, ROLE_MAX_LEN)))
+	}
+	for permission in &role.permissions {
+		if permission.parse::<Permission>().is_err() {
+			return Err(IndusError::Validation(format!("{:?} is not a valid permission", permission)))
+		}
+	}
+	Ok(())
+}
+
+fn write_permissions(conn: &GenericConnection, role: &Role) -> IndusResult<()> {
+	try!( conn.execute("DELETE FROM role_permissions WHERE role = $1", &[&role.name]) );
+	let mut seen = Vec::new();
+	for permission in &role.permissions {
+		// stored in canonical form, so "x:all" and "x" are the same permission
+		let permission = permission.parse::<Permission>().ok().expect("validated").to_string();
+		if seen.contains(&permission) {
+			continue
+		}
+		try!( conn.execute(
+			"INSERT INTO role_permissions (role, permission) VALUES ($1, $2)", &[&role.name, &permission]
+		) );
+		seen.push(permission);
+	}
+	Ok(())
+}
+
+impl IndusDatabase {
+	pub fn roles(&self) -> IndusResult<Vec<Role>> {
+		let conn = try!(self.conn());
+		let stmt = try!( conn.prepare("SELECT name, description FROM roles ORDER BY name") );
+		let perms = try!( conn.prepare("SELECT role, permission FROM role_permissions ORDER BY permission") );
+		let perms = try!( perms.query(&[]) ).iter()
+			.map(|row| (row.get(0), row.get(1)))
+			.collect::<Vec<(String, String)>>();
+		let rows = try!( stmt.query(&[]) );
+		Ok( rows.iter().map(|row| {
+			let name: String = row.get(0);
+			Role {
+				permissions: perms.iter().filter(|p| p.0 == name).map(|p| p.1.clone()).collect(),
+				name: name,
+				description: row.get(1),
+			}
+		}).collect() )
+	}
+
+	pub fn create_role(&self, role: &Role) -> IndusResult<()> {
+		try!(validate(role));
+		self.transaction(|tx| {
+			try!( tx.execute(
+				"INSERT INTO roles (name, description) VALUES ($1, $2)", &[&role.name, &role.description]
+			) );
+			write_permissions(tx, role)
+		})
+	}
+
+	/// Replaces the description and permissions of a role.
+	pub fn update_role(&self, role: &Role) -> IndusResult<()> {
+		try!(validate(role));
+		self.transaction(|tx| {
+			let updated = try!( tx.execute(
+				"UPDATE roles SET description = $2 WHERE name = $1", &[&role.name, &role.description]
+			) );
+			if updated == 0 {
+				return Err(IndusError::NotFound(format!("role {}", role.name)))
+			}
+			write_permissions(tx, role)
+		})
+	}
+
+	/// Deletes a role, taking it away from everyone who has it. The implied
+	/// roles cannot be deleted.
+	pub fn delete_role(&self, name: &str) -> IndusResult<()> {
+		if IMPLIED_ROLES.iter().any(|r| *r == name) {
+			return Err(IndusError::Validation(format!("role {} is given to users by their type", name)))
+		}
+		let conn = try!(self.conn());
+		match try!( conn.execute("DELETE FROM roles WHERE name = $1", &[&name]) ) {
+			0 => Err(IndusError::NotFound(format!("role {}", name))),
+			_ => Ok(()),
+		}
+	}
+
+	/// Gives a user a role. Giving it again does nothing.
+	pub fn grant_role(&self, user_id: i32, role: &str) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		try!( conn.execute(
+			"INSERT INTO user_roles (user_id, role) SELECT $1, $2
+			WHERE NOT EXISTS (SELECT 1 FROM user_roles WHERE user_id = $1 AND role = $2)",
+			&[&user_id, &role]
+		).map_err(|e| missing(e, format!("user {} or role {}", user_id, role))) );
+		Ok(())
+	}
+
+	/// Takes a given role away from a user. Implied roles go with the type of
+	/// the user, or the HOD flag.
+	pub fn revoke_role(&self, user_id: i32, role: &str) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		match try!( conn.execute("DELETE FROM user_roles WHERE user_id = $1 AND role = $2", &[&user_id, &role]) ) {
+			0 => Err(IndusError::NotFound(format!("role {} of user {}", role, user_id))),
+			_ => Ok(()),
+		}
+	}
+
+	/// Gives a role to a user or takes it away on behalf of `grants`. A role
+	/// can carry any permission, so this takes `role.assign` everywhere; a
+	/// department scope is not enough.
+	pub fn assign_role(&self, grants: &Grants, user_id: i32, role: &str, grant: bool) -> IndusResult<()> {
+		if grants.scope("role.assign") != Some(Scope::All) {
+			return Err(IndusError::Forbidden("role.assign".into()))
+		}
+		if grant {
+			self.grant_role(user_id, role)
+		} else {
+			self.revoke_role(user_id, role)
+		}
+	}
+
+	pub fn grants(&self, user_id: i32) -> IndusResult<Grants> {
+		let conn = try!(self.conn());
+		Grants::load(&*conn, user_id)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn permission(s: &str) -> Permission {
+		s.parse().unwrap()
+	}
+
+	fn grants(permissions: &[&str]) -> Grants {
+		Grants {
+			user_id: 1, roles: vec![], department: None,
+			permissions: permissions.iter().map(|p| permission(p)).collect(),
+		}
+	}
+
+	#[test]
+	fn parse() {
+		assert_eq!( permission("user.create"), Permission { action: "user.create".into(), scope: Scope::All } );
+		assert_eq!( permission("resource.delete:department").scope, Scope::Department );
+		assert_eq!( permission("class.edit:own").scope, Scope::Own );
+		assert_eq!( permission("class.edit:all"), permission("class.edit") );
+		for p in &["*", "class.*", "user.create", "resource.delete:department", "class.edit:own"] {
+			assert_eq!( permission(p).to_string(), *p );
+		}
+		for p in &["", ":own", "user.", ".user", "User.create", "user.create:mine", "*.create", "class.*.edit", "a:b:c"] {
+			assert!( p.parse::<Permission>().is_err(), "{:?}", p );
+		}
+	}
+
+	#[test]
+	fn matching() {
+		assert!( permission("*").matches("user.create") );
+		assert!( permission("class.*").matches("class.edit") );
+		assert!( !permission("class.*").matches("classes.edit") );
+		assert!( !permission("class.*").matches("user.edit") );
+		assert!( permission("user.create").matches("user.create") );
+		assert!( !permission("user.create").matches("user.create.bulk") );
+	}
+
+	#[test]
+	fn scopes() {
+		let g = grants(&["class.edit:own", "class.*:department", "user.view"]);
+		assert_eq!( g.scope("class.edit"), Some(Scope::Department) );
+		assert_eq!( g.scope("class.view"), Some(Scope::Department) );
+		assert_eq!( g.scope("user.view"), Some(Scope::All) );
+		assert_eq!( g.scope("user.edit"), None );
+		assert!( g.has("class.edit") );
+		assert!( !grants(&[]).has("class.edit") );
+		assert_eq!( grants(&["*"]).scope("anything"), Some(Scope::All) );
+	}
+}
@@ -0,0 +1,1123 @@
+use iron::prelude::*;
+use iron::{status, Handler, BeforeMiddleware};
+use iron::headers::{Cookie, SetCookie, ContentType, ContentLength};
+use iron::mime::Mime;
+use iron::response::BodyReader;
+use iron::status::Status;
+use iron::typemap::Key;
+use router::Router;
+use cookie::Cookie as CookiePair;
+
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, encode};
+
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use db::{IndusDatabase, LoginFailure};
+use error::IndusError;
+use data::{IndusUser, Level};
+use enrollment::ClassId;
+use export::{Export, ExportFilter, Format};
+use roles::{Grants, Scope, Target};
+use storage::{FileInfo, FileQuery, NewFile, Storage, DEFAULT_MIME};
+use folders::{Access, FolderInfo, Space};
+use uploads::UploadSession;
+use versions::Retention;
+use crypt::{derive_key, random_secret};
+use subjects::SubjectInfo;
+use users::{UserQuery, UserUpdate};
+use session::{self, Sessions};
+use config::Config;
+
+#[derive(Debug, Clone, RustcDecodable)]
+pub struct LoginRequest {
+	pub username: String,
+	pub password: String,
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+pub struct PasswordChange {
+	pub old: String,
+	pub new: String,
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+pub struct PasswordReset {
+	pub token: String,
+	pub password: String,
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct LoginResponse {
+	pub id: i32,
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+pub struct EnrollmentRequest {
+	pub level: Option<Level>,
+}
+
+#[derive(Debug, Clone, RustcDecodable)]
+pub struct FolderRequest {
+	pub name: String,
+}
+
+/// Starts a resumable upload. Where the file goes is chosen as for
+/// `POST /files`.
+#[derive(Debug, Clone, RustcDecodable)]
+pub struct UploadRequest {
+	pub name: String,
+	pub mime: Option<String>,
+	pub size: u64,
+	pub sha256: Option<String>,
+	pub folder: Option<i32>,
+	pub class: Option<ClassId>,
+}
+
+/// Everything a handler needs, shared between Iron's worker threads.
+pub struct Context {
+	pub db: IndusDatabase,
+	pub sessions: Sessions,
+	pub storage: Storage,
+}
+
+pub type Shared = Arc<Context>;
+
+/// The user behind the session cookie of the current request.
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+	pub id: i32,
+	pub token: String,
+	pub user: IndusUser,
+	pub grants: Grants,
+}
+
+pub struct CurrentUser;
+impl Key for CurrentUser { type Value = Authenticated; }
+
+impl From<LoginFailure> for Status {
+	#[inline] fn from(f: LoginFailure) -> Status {
+		match f {
+			LoginFailure::NoAccount | LoginFailure::PasswordMismatch => status::Unauthorized,
+			LoginFailure::Deactivated => status::Forbidden,
+		}
+	}
+}
+
+#[inline]
+fn json_mime() -> Mime {
+	"application/json".parse().unwrap()
+}
+
+impl<'a> From<&'a IndusError> for Status {
+	fn from(e: &'a IndusError) -> Status {
+		match *e {
+			IndusError::Config(_) | IndusError::Query(_) | IndusError::Io(_) => status::InternalServerError,
+			IndusError::Connection(_) | IndusError::PoolTimeout => status::ServiceUnavailable,
+			IndusError::NotFound(_) => status::NotFound,
+			IndusError::Duplicate(_) => status::Conflict,
+			IndusError::Validation(_) => status::BadRequest,
+			IndusError::Auth(f) => Status::from(f),
+			IndusError::Forbidden(_) => status::Forbidden,
+			IndusError::Conflict(_) => status::Conflict,
+		}
+	}
+}
+
+impl From<IndusError> for IronError {
+	fn from(e: IndusError) -> IronError {
+		let code = Status::from(&e);
+		if code == status::InternalServerError || code == status::ServiceUnavailable {
+			error!("{}", e);
+		}
+		if let IndusError::Auth(ref f) = e {
+			info!("login failed: {:?}", f);
+		}
+		let body = format!("{{\"error\":{}}}", encode(&e.to_string()).unwrap());
+		IronError::new(e, (code, json_mime(), body))
+	}
+}
+
+fn read_json<T: Decodable>(req: &mut Request) -> Result<T, IndusError> {
+	let mut body = String::new();
+	try!( req.body.read_to_string(&mut body)
+		.map_err(|e| IndusError::Validation(format!("could not read request body: {}", e))) );
+	json::decode(&body).map_err(|e| IndusError::Validation(format!("malformed request body: {}", e)))
+}
+
+fn session_cookie(value: String, max_age: Option<u64>) -> SetCookie {
+	let mut cookie = CookiePair::new(session::COOKIE_NAME.into(), value);
+	cookie.path = Some("/".into());
+	cookie.httponly = true;
+	cookie.max_age = max_age;
+	SetCookie(vec![cookie])
+}
+
+#[inline]
+fn current(req: &Request) -> Option<&Authenticated> {
+	req.extensions.get::<CurrentUser>()
+}
+
+/// The grants of the current user, for handlers behind `require`.
+fn grants(req: &Request) -> Grants {
+	current(req).map(|auth| auth.grants.clone()).expect("handler is behind require")
+}
+
+/// Parses a parameter of the route.
+fn param<T: FromStr>(req: &Request, name: &str) -> Result<T, IndusError> {
+	let value = req.extensions.get::<Router>().and_then(|params| params.find(name)).unwrap_or("");
+	value.parse().map_err(|_| IndusError::Validation(format!("{:?} is not a valid {}", value, name)))
+}
+
+/// The parameters of the query string, in order.
+fn query_params(req: &Request) -> Vec<(String, String)> {
+	req.url.clone().into_generic_url().query_pairs().unwrap_or(Vec::new())
+}
+
+/// Parses the last value of a parameter of the query string.
+fn query_param<T: FromStr>(params: &[(String, String)], name: &str) -> Result<Option<T>, IndusError> {
+	match params.iter().rev().find(|p| p.0 == name) {
+		None => Ok(None),
+		Some(&(_, ref value)) if value.is_empty() => Ok(None),
+		Some(&(_, ref value)) => value.parse().map(Some)
+			.map_err(|_| IndusError::Validation(format!("{:?} is not a valid {}", value, name))),
+	}
+}
+
+/// Checks that the current user may do `action` to `target`. Handlers call
+/// this once they know what the request is about; `require` only checks that
+/// the user may do the action to anything.
+fn authorize(ctx: &Context, grants: &Grants, action: &str, target: &Target) -> Result<(), IndusError> {
+	let conn = try!(ctx.db.conn());
+	grants.require(&*conn, action, target)
+}
+
+/// Lets requests through to `handler` only if the user is logged in and has
+/// some permission for `action`.
+pub struct Require<H> {
+	action: &'static str,
+	handler: H,
+}
+
+pub fn require<H: Handler>(action: &'static str, handler: H) -> Require<H> {
+	Require { action: action, handler: handler }
+}
+
+impl<H: Handler> Handler for Require<H> {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		match current(req).map(|auth| auth.grants.has(self.action)) {
+			None => return Ok(Response::with(status::Unauthorized)),
+			Some(false) => return Err(IronError::from(IndusError::Forbidden(self.action.into()))),
+			Some(true) => {}
+		}
+		self.handler.handle(req)
+	}
+}
+
+/// Resolves the session cookie, if any, into a `CurrentUser`.
+pub struct SessionMiddleware {
+	ctx: Shared,
+}
+
+impl BeforeMiddleware for SessionMiddleware {
+	fn before(&self, req: &mut Request) -> IronResult<()> {
+		let token = match req.headers.get::<Cookie>() {
+			Some(cookies) => cookies.iter()
+				.find(|c| c.name == session::COOKIE_NAME)
+				.and_then(|c| self.ctx.sessions.verify_cookie(&c.value).map(String::from)),
+			None => None,
+		};
+		let token = match token {
+			Some(t) => t,
+			None => return Ok(()),
+		};
+		let resolved = self.ctx.db.conn().and_then(|conn| {
+			self.ctx.sessions.resolve(&conn, &token).map_err(IndusError::from)
+		});
+		let session = match resolved {
+			Ok(Some(s)) => s,
+			Ok(None) => return Ok(()),
+			Err(e) => {
+				warn!("Could not resolve session: {}", e);
+				return Ok(())
+			}
+		};
+		match (self.ctx.db.profile(session.user_id), self.ctx.db.grants(session.user_id)) {
+			(Ok(user), Ok(grants)) => {
+				req.extensions.insert::<CurrentUser>(Authenticated {
+					id: session.user_id, token: session.token, user: user, grants: grants,
+				});
+			}
+			(Err(e), _) | (_, Err(e)) => warn!("Could not load user {}: {}", session.user_id, e),
+		}
+		Ok(())
+	}
+}
+
+pub struct LoginHandler {
+	ctx: Shared,
+}
+
+impl Handler for LoginHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let login: LoginRequest = try!(read_json(req));
+		let id = try!(self.ctx.db.login(&login.username, &login.password));
+		let conn = try!(self.ctx.db.conn());
+		if let Err(e) = self.ctx.sessions.purge_expired(&conn) {
+			warn!("Could not purge expired sessions: {}", e);
+		}
+		let session = try!( self.ctx.sessions.create(&conn, id).map_err(IndusError::from) );
+		let mut res = Response::with((status::Ok, json_mime(), encode(&LoginResponse { id: id }).unwrap()));
+		res.headers.set(session_cookie(self.ctx.sessions.cookie_value(&session), None));
+		Ok(res)
+	}
+}
+
+pub struct ProfileHandler;
+
+impl Handler for ProfileHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		Ok( match current(req) {
+			Some(auth) => Response::with((status::Ok, json_mime(), encode(&auth.user).unwrap())),
+			None => Response::with(status::Unauthorized),
+		} )
+	}
+}
+
+/// How complete the programme of the logged in student is.
+pub struct ProgrammeHandler {
+	ctx: Shared,
+}
+
+impl Handler for ProgrammeHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id = match current(req) {
+			Some(auth) => auth.id,
+			None => return Ok(Response::with(status::Unauthorized)),
+		};
+		let report = try!(self.ctx.db.programme(id));
+		Ok( Response::with((status::Ok, json_mime(), encode(&report).unwrap())) )
+	}
+}
+
+pub struct LogoutHandler {
+	ctx: Shared,
+	/// End every session of the user, not just the current one.
+	everywhere: bool,
+}
+
+impl Handler for LogoutHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let (id, token) = match current(req) {
+			Some(auth) => (auth.id, auth.token.clone()),
+			None => return Ok(Response::with(status::Unauthorized)),
+		};
+		let conn = try!(self.ctx.db.conn());
+		try!( if self.everywhere {
+			self.ctx.sessions.destroy_all(&conn, id)
+		} else {
+			self.ctx.sessions.destroy(&conn, &token)
+		}.map_err(IndusError::from) );
+		let mut res = Response::with(status::Ok);
+		res.headers.set(session_cookie(String::new(), Some(0)));
+		Ok(res)
+	}
+}
+
+/// Changes the current user's password. This ends all of their sessions,
+/// including the one making the request.
+pub struct PasswordHandler {
+	ctx: Shared,
+}
+
+impl Handler for PasswordHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id = match current(req) {
+			Some(auth) => auth.id,
+			None => return Ok(Response::with(status::Unauthorized)),
+		};
+		let change: PasswordChange = try!(read_json(req));
+		try!(self.ctx.db.change_password(id, &change.old, &change.new));
+		let mut res = Response::with(status::Ok);
+		res.headers.set(session_cookie(String::new(), Some(0)));
+		Ok(res)
+	}
+}
+
+/// Sets a new password with a token issued by an admin.
+pub struct ResetHandler {
+	ctx: Shared,
+}
+
+impl Handler for ResetHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let reset: PasswordReset = try!(read_json(req));
+		try!(self.ctx.db.reset_password(&reset.token, &reset.password));
+		Ok(Response::with(status::Ok))
+	}
+}
+
+/// Every role and its permissions.
+pub struct RolesHandler {
+	ctx: Shared,
+}
+
+impl Handler for RolesHandler {
+	fn handle(&self, _: &mut Request) -> IronResult<Response> {
+		let roles = try!(self.ctx.db.roles());
+		Ok( Response::with((status::Ok, json_mime(), encode(&roles).unwrap())) )
+	}
+}
+
+/// Gives a role to a user, or takes it away. This takes `role.assign`
+/// everywhere, not just in a department.
+pub struct UserRoleHandler {
+	ctx: Shared,
+	grant: bool,
+}
+
+impl Handler for UserRoleHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let role: String = try!(param(req, "role"));
+		try!(self.ctx.db.assign_role(&grants(req), id, &role, self.grant));
+		Ok(Response::with(status::Ok))
+	}
+}
+
+/// Updates a subject of the catalogue. Heads of department may update their
+/// own subject, but only those who may edit every subject can change its head.
+pub struct SubjectHandler {
+	ctx: Shared,
+}
+
+impl Handler for SubjectHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let code: String = try!(param(req, "code"));
+		let current = try!(self.ctx.db.subject(&code));
+		try!(authorize(&self.ctx, &grants, "subject.edit", &Target::Subject(current.subject.clone())));
+		let mut info: SubjectInfo = try!(read_json(req));
+		info.subject = current.subject;
+		if grants.scope("subject.edit") != Some(Scope::All) {
+			info.head = current.head;
+		}
+		try!(self.ctx.db.update_subject(&info));
+		Ok(Response::with(status::Ok))
+	}
+}
+
+pub struct RosterHandler {
+	ctx: Shared,
+}
+
+impl Handler for RosterHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let class: ClassId = try!(param(req, "id"));
+		try!(authorize(&self.ctx, &grants(req), "class.view", &Target::Class(class)));
+		let roster = try!(self.ctx.db.roster(class));
+		Ok( Response::with((status::Ok, json_mime(), encode(&roster).unwrap())) )
+	}
+}
+
+/// Enrolls a student in a class, or takes them out of it.
+pub struct EnrollmentHandler {
+	ctx: Shared,
+	enroll: bool,
+}
+
+impl Handler for EnrollmentHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let class: ClassId = try!(param(req, "id"));
+		let student: i32 = try!(param(req, "student"));
+		try!(authorize(&self.ctx, &grants(req), "class.edit", &Target::Class(class)));
+		try!( if self.enroll {
+			let request: EnrollmentRequest = try!(read_json(req));
+			self.ctx.db.enroll(student, class, request.level)
+		} else {
+			self.ctx.db.unenroll(student, class)
+		} );
+		Ok(Response::with(status::Ok))
+	}
+}
+
+/// Searches the users. Heads of department only see the teachers of their
+/// department.
+pub struct UsersHandler {
+	ctx: Shared,
+}
+
+impl Handler for UsersHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let params = query_params(req);
+		let mut query = UserQuery {
+			name: try!(query_param(&params, "name")),
+			role: try!(query_param(&params, "role")),
+			grade: try!(query_param(&params, "grade")),
+			section: try!(query_param(&params, "section")),
+			subject: try!(query_param(&params, "subject")),
+			active: try!(query_param(&params, "active")),
+			page: try!(query_param(&params, "page")).unwrap_or(1),
+			per_page: try!(query_param(&params, "per_page")).unwrap_or(0),
+		};
+		match (grants.scope("user.view"), grants.department) {
+			(Some(Scope::All), _) => {}
+			(Some(Scope::Department), Some(department)) => {
+				query.role = Some("teacher".into());
+				query.subject = Some(department.code().into());
+			}
+			_ => return Err(IronError::from(IndusError::Forbidden("user.view".into()))),
+		}
+		let page = try!(self.ctx.db.users(&query));
+		Ok( Response::with((status::Ok, json_mime(), encode(&page).unwrap())) )
+	}
+}
+
+pub struct UserHandler {
+	ctx: Shared,
+}
+
+impl Handler for UserHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		try!(authorize(&self.ctx, &grants(req), "user.view", &Target::User(id)));
+		let user = try!(self.ctx.db.user(id));
+		Ok( Response::with((status::Ok, json_mime(), encode(&user).unwrap())) )
+	}
+}
+
+/// Edits a profile. Only those who may edit every user can move a teacher to
+/// another subject or make them head of department.
+pub struct UserUpdateHandler {
+	ctx: Shared,
+}
+
+impl Handler for UserUpdateHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let id: i32 = try!(param(req, "id"));
+		try!(authorize(&self.ctx, &grants, "user.edit", &Target::User(id)));
+		let update: UserUpdate = try!(read_json(req));
+		if grants.scope("user.edit") != Some(Scope::All) && (update.subject.is_some() || update.hod.is_some()) {
+			return Err(IronError::from(IndusError::Forbidden("user.edit".into())))
+		}
+		let user = try!(self.ctx.db.update_user(id, &update));
+		Ok( Response::with((status::Ok, json_mime(), encode(&user).unwrap())) )
+	}
+}
+
+/// Deactivates or reactivates a user.
+pub struct ActivationHandler {
+	ctx: Shared,
+	active: bool,
+}
+
+impl Handler for ActivationHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let id: i32 = try!(param(req, "id"));
+		try!(authorize(&self.ctx, &grants, "user.deactivate", &Target::User(id)));
+		if id == grants.user_id && !self.active {
+			return Err(IronError::from(IndusError::Validation("you cannot deactivate yourself".into())))
+		}
+		try!( if self.active {
+			self.ctx.db.reactivate_user(id)
+		} else {
+			self.ctx.db.deactivate_user(id)
+		} );
+		Ok(Response::with(status::Ok))
+	}
+}
+
+/// Deletes a user for good. The `confirm` parameter must be their username.
+pub struct DeleteUserHandler {
+	ctx: Shared,
+}
+
+impl Handler for DeleteUserHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let id: i32 = try!(param(req, "id"));
+		try!(authorize(&self.ctx, &grants, "user.delete", &Target::User(id)));
+		let confirm: String = try!(query_param(&query_params(req), "confirm")).unwrap_or(String::new());
+		try!(self.ctx.db.delete_user(id, &confirm));
+		Ok(Response::with(status::Ok))
+	}
+}
+
+/// Exports users, rosters, timetables or counts. `format` is `csv` (the
+/// default) or `json`, `fields` a comma separated selection, and `grade`,
+/// `section` and `subject` filter the rows. Those who may only export their
+/// department's data get just the rows of its subject.
+pub struct ExportHandler {
+	ctx: Shared,
+}
+
+impl Handler for ExportHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let export: Export = try!(param(req, "kind"));
+		let params = query_params(req);
+		let format = try!(query_param(&params, "format")).unwrap_or(Format::default());
+		let fields = try!(query_param::<String>(&params, "fields"))
+			.map(|f| f.split(',').map(|f| f.trim().to_string()).collect())
+			.unwrap_or(Vec::new());
+		let mut filter = ExportFilter {
+			grade: try!(query_param(&params, "grade")),
+			section: try!(query_param(&params, "section")),
+			subject: try!(query_param(&params, "subject")),
+		};
+		match (grants.scope("data.export"), grants.department.as_ref()) {
+			(Some(Scope::All), _) => {}
+			(Some(Scope::Department), Some(department)) => {
+				if let Some(ref asked) = filter.subject {
+					if try!(self.ctx.db.subject(asked)).subject != *department {
+						return Err(IronError::from(IndusError::Forbidden("data.export".into())))
+					}
+				}
+				filter.subject = Some(department.code().to_string());
+			}
+			_ => return Err(IronError::from(IndusError::Forbidden("data.export".into()))),
+		}
+		let table = try!( self.ctx.db.export(export, &filter).and_then(|t| t.select(&fields)) );
+		Ok( Response::with((status::Ok, format.mime().parse::<Mime>().unwrap(), table.render(format))) )
+	}
+}
+
+/// Checks that the current user has `needed` access to a space, failing as
+/// forbidden to do `action` if not.
+fn authorize_space(ctx: &Context, grants: &Grants, needed: Access, space: &Space, action: &str)
+	-> Result<(), IndusError> {
+	if try!(ctx.db.access(grants, space)) >= needed {
+		Ok(())
+	} else {
+		Err(IndusError::Forbidden(action.into()))
+	}
+}
+
+/// Checks that the current user has `needed` access to the folder of a file.
+/// Files can also be deleted by those who may delete them as resources,
+/// such as heads of department.
+fn authorize_file(ctx: &Context, grants: &Grants, needed: Access, action: &str, file: &FileInfo)
+	-> Result<(), IndusError> {
+	let folder = try!(ctx.db.folder(file.folder));
+	match authorize_space(ctx, grants, needed, &folder.space(), action) {
+		Err(IndusError::Forbidden(_)) if action == "resource.delete" =>
+			authorize(ctx, grants, action, &Target::Resource { owner: file.owner, class: file.class }),
+		result => result,
+	}
+}
+
+/// Lists the files in a folder (`folder`), in the folders of a class
+/// (`class`) or of a user (`owner`, by default the current user).
+pub struct FilesHandler {
+	ctx: Shared,
+}
+
+impl Handler for FilesHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let params = query_params(req);
+		let mut query = FileQuery {
+			owner: try!(query_param(&params, "owner")),
+			class: try!(query_param(&params, "class")),
+			folder: try!(query_param(&params, "folder")),
+		};
+		match (query.folder, query.class) {
+			(Some(folder), _) => {
+				let folder = try!(self.ctx.db.folder(folder));
+				try!(authorize_space(&self.ctx, &grants, Access::Read, &folder.space(), "resource.view"));
+			}
+			(None, Some(class)) => try!(authorize_space(&self.ctx, &grants, Access::Read, &Space::Class(class), "resource.view")),
+			(None, None) => {
+				let owner = query.owner.unwrap_or(grants.user_id);
+				if owner != grants.user_id && grants.scope("resource.view") != Some(Scope::All) {
+					return Err(IronError::from(IndusError::Forbidden("resource.view".into())))
+				}
+				query.owner = Some(owner);
+			}
+		}
+		let files = try!(self.ctx.db.files(&query));
+		Ok( Response::with((status::Ok, json_mime(), encode(&files).unwrap())) )
+	}
+}
+
+/// The folder an upload goes in: `folder`, the top folder of `class`, or
+/// else the user's personal folder. They must be able to write to it, which
+/// is checked before a top folder is made for it.
+fn upload_folder(ctx: &Context, grants: &Grants, folder: Option<i32>, class: Option<ClassId>)
+	-> Result<FolderInfo, IndusError> {
+	let space = match (folder, class) {
+		(Some(folder), _) => {
+			let folder = try!(ctx.db.folder(folder));
+			try!(authorize_space(ctx, grants, Access::Write, &folder.space(), "resource.create"));
+			return Ok(folder)
+		}
+		(None, Some(class)) => Space::Class(class),
+		(None, None) => Space::Personal(grants.user_id),
+	};
+	try!(authorize_space(ctx, grants, Access::Write, &space, "resource.create"));
+	ctx.db.root_folder(&space)
+}
+
+/// Stores the request body as a new file of the current user. `name` names
+/// it; it goes in `folder`, the folder of `class` or else the user's personal
+/// folder.
+pub struct UploadHandler {
+	ctx: Shared,
+}
+
+impl Handler for UploadHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let params = query_params(req);
+		let folder = try!(upload_folder(&self.ctx, &grants,
+			try!(query_param(&params, "folder")), try!(query_param(&params, "class"))));
+		let file = NewFile {
+			name: try!(query_param(&params, "name")).unwrap_or(String::new()),
+			mime: req.headers.get::<ContentType>().map(|mime| mime.to_string()),
+			folder: folder.id,
+		};
+		let info = try!(self.ctx.db.upload(&self.ctx.storage, grants.user_id, &file, &mut req.body));
+		Ok( Response::with((status::Created, json_mime(), encode(&info).unwrap())) )
+	}
+}
+
+/// Offers to save a download under the name of its file: in `filename` as
+/// plain ASCII for older clients, and exactly in `filename*` as RFC 5987
+/// encodes it.
+fn content_disposition(name: &str) -> String {
+	let ascii = name.chars().map(|c| match c {
+		' ' ... '~' if c != '"' && c != '\\' => c,
+		_ => '_',
+	}).collect::<String>();
+	let mut encoded = String::new();
+	for b in name.bytes() {
+		match b {
+			b'A' ... b'Z' | b'a' ... b'z' | b'0' ... b'9' |
+			b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(b as char),
+			_ => encoded.push_str(&format!("%{:02X}", b)),
+		}
+	}
+	format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii, encoded)
+}
+
+/// Streams the contents of a file.
+pub struct DownloadHandler {
+	ctx: Shared,
+}
+
+impl Handler for DownloadHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants(req), Access::Read, "resource.view", &info));
+		let (info, contents) = try!(self.ctx.db.download(&self.ctx.storage, id));
+		Ok(file_response(&info, contents))
+	}
+}
+
+/// Hands out a link to the contents of a file that works without logging
+/// in, for a while.
+pub struct LinkHandler {
+	ctx: Shared,
+}
+
+impl Handler for LinkHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants(req), Access::Read, "resource.view", &info));
+		let link = try!(self.ctx.db.file_link(&self.ctx.storage, id));
+		Ok( Response::with((status::Ok, json_mime(), encode(&link).unwrap())) )
+	}
+}
+
+/// Serves the contents behind links signed by this server, to anyone who
+/// has one.
+pub struct ObjectHandler {
+	ctx: Shared,
+}
+
+impl Handler for ObjectHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let key: String = try!(param(req, "key"));
+		let params = query_params(req);
+		let expires: i64 = try!(query_param(&params, "expires")).unwrap_or(0);
+		let signature: String = try!(query_param(&params, "signature")).unwrap_or(String::new());
+		if !self.ctx.storage.signer().verify(&key, expires, &signature) {
+			return Ok(Response::with(status::Forbidden))
+		}
+		let info = try!(self.ctx.db.file_by_key(&key));
+		let contents = try!(self.ctx.storage.backend().get(&key));
+		Ok(file_response(&info, contents))
+	}
+}
+
+/// A response streaming the contents of a file as a download.
+fn file_response(info: &FileInfo, contents: Box<Read + Send>) -> Response {
+	let mime = info.mime.parse::<Mime>().unwrap_or(DEFAULT_MIME.parse().unwrap());
+	let mut res = Response::with((status::Ok, mime, BodyReader(contents)));
+	res.headers.set(ContentLength(info.size as u64));
+	res.headers.set_raw("Content-Disposition", vec![content_disposition(&info.name).into_bytes()]);
+	res
+}
+
+/// Stores the request body as the next version of a file.
+pub struct ReplaceFileHandler {
+	ctx: Shared,
+}
+
+impl Handler for ReplaceFileHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants, Access::Write, "resource.create", &info));
+		let mime = req.headers.get::<ContentType>().map(|mime| mime.to_string());
+		let info = try!(self.ctx.db.replace_file(&self.ctx.storage, id, grants.user_id,
+			mime.as_ref().map(|m| &**m), &mut req.body));
+		Ok( Response::with((status::Ok, json_mime(), encode(&info).unwrap())) )
+	}
+}
+
+/// Lists the versions of a file, newest first.
+pub struct VersionsHandler {
+	ctx: Shared,
+}
+
+impl Handler for VersionsHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants(req), Access::Read, "resource.view", &info));
+		let versions = try!(self.ctx.db.file_versions(id));
+		Ok( Response::with((status::Ok, json_mime(), encode(&versions).unwrap())) )
+	}
+}
+
+/// Streams a file as it was at a version.
+pub struct VersionHandler {
+	ctx: Shared,
+}
+
+impl Handler for VersionHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants(req), Access::Read, "resource.view", &info));
+		let (info, contents) = try!(self.ctx.db.download_version(&self.ctx.storage, id, try!(param(req, "number"))));
+		Ok(file_response(&info, contents))
+	}
+}
+
+/// Makes an earlier version of a file the current one again.
+pub struct RestoreHandler {
+	ctx: Shared,
+}
+
+impl Handler for RestoreHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants(req), Access::Write, "resource.create", &info));
+		let info = try!(self.ctx.db.restore_version(id, try!(param(req, "number"))));
+		Ok( Response::with((status::Ok, json_mime(), encode(&info).unwrap())) )
+	}
+}
+
+/// Deletes the old versions of a file but the newest `keep`, or those made
+/// in the last `days`, and lists them.
+pub struct PruneHandler {
+	ctx: Shared,
+}
+
+impl Handler for PruneHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants(req), Access::Write, "resource.delete", &info));
+		let params = query_params(req);
+		let retention = match (try!(query_param(&params, "keep")), try!(query_param(&params, "days"))) {
+			(Some(n), None) => Retention::Last(n),
+			(None, Some(n)) => Retention::Days(n),
+			_ => return Err(IronError::from(IndusError::Validation("give either keep or days".into()))),
+		};
+		let pruned = try!(self.ctx.db.prune_versions(&self.ctx.storage, Some(id), retention));
+		Ok( Response::with((status::Ok, json_mime(), encode(&pruned).unwrap())) )
+	}
+}
+
+pub struct DeleteFileHandler {
+	ctx: Shared,
+}
+
+impl Handler for DeleteFileHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let info = try!(self.ctx.db.file(id));
+		try!(authorize_file(&self.ctx, &grants(req), Access::Write, "resource.delete", &info));
+		try!(self.ctx.db.delete_file(&self.ctx.storage, id));
+		Ok(Response::with(status::Ok))
+	}
+}
+
+/// Lists the top folders of the current user's spaces, of those that have
+/// had anything uploaded to them.
+pub struct FoldersHandler {
+	ctx: Shared,
+}
+
+impl Handler for FoldersHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let folders = try!(self.ctx.db.root_folders(grants(req).user_id));
+		Ok( Response::with((status::Ok, json_mime(), encode(&folders).unwrap())) )
+	}
+}
+
+/// Lists the folders and files in a folder.
+pub struct FolderHandler {
+	ctx: Shared,
+}
+
+impl Handler for FolderHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let id: i32 = try!(param(req, "id"));
+		let folder = try!(self.ctx.db.folder(id));
+		try!(authorize_space(&self.ctx, &grants, Access::Read, &folder.space(), "resource.view"));
+		let listing = try!(self.ctx.db.folder_listing(&grants, id));
+		Ok( Response::with((status::Ok, json_mime(), encode(&listing).unwrap())) )
+	}
+}
+
+/// Makes a folder inside another.
+pub struct NewFolderHandler {
+	ctx: Shared,
+}
+
+impl Handler for NewFolderHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let id: i32 = try!(param(req, "id"));
+		let request: FolderRequest = try!(read_json(req));
+		let parent = try!(self.ctx.db.folder(id));
+		try!(authorize_space(&self.ctx, &grants, Access::Write, &parent.space(), "resource.create"));
+		let folder = try!(self.ctx.db.create_folder(id, &request.name));
+		Ok( Response::with((status::Created, json_mime(), encode(&folder).unwrap())) )
+	}
+}
+
+/// Removes an empty folder.
+pub struct DeleteFolderHandler {
+	ctx: Shared,
+}
+
+impl Handler for DeleteFolderHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		let folder = try!(self.ctx.db.folder(id));
+		try!(authorize_space(&self.ctx, &grants(req), Access::Write, &folder.space(), "resource.delete"));
+		try!(self.ctx.db.delete_folder(id));
+		Ok(Response::with(status::Ok))
+	}
+}
+
+/// A resumable upload of the current user. Those of others are not found.
+fn own_upload(ctx: &Context, grants: &Grants, id: i32) -> Result<UploadSession, IndusError> {
+	let session = try!(ctx.db.upload_session(&ctx.storage, id));
+	if session.owner != grants.user_id {
+		return Err(IndusError::NotFound(format!("upload {}", id)))
+	}
+	Ok(session)
+}
+
+/// Lists the current user's unfinished uploads, so that they can be resumed.
+pub struct UploadsHandler {
+	ctx: Shared,
+}
+
+impl Handler for UploadsHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let sessions = try!(self.ctx.db.upload_sessions(&self.ctx.storage, grants(req).user_id));
+		Ok( Response::with((status::Ok, json_mime(), encode(&sessions).unwrap())) )
+	}
+}
+
+/// Starts a resumable upload. Abandoned uploads are thrown away first.
+pub struct NewUploadHandler {
+	ctx: Shared,
+}
+
+impl Handler for NewUploadHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let request: UploadRequest = try!(read_json(req));
+		let folder = try!(upload_folder(&self.ctx, &grants, request.folder, request.class));
+		if let Err(e) = self.ctx.db.purge_uploads(&self.ctx.storage) {
+			warn!("Could not purge abandoned uploads: {}", e);
+		}
+		let file = NewFile { name: request.name, mime: request.mime, folder: folder.id };
+		let session = try!(self.ctx.db.create_upload(&self.ctx.storage, grants.user_id, &file,
+			request.size, request.sha256.as_ref().map(|s| &**s)));
+		Ok( Response::with((status::Created, json_mime(), encode(&session).unwrap())) )
+	}
+}
+
+/// Tells how far an upload has got.
+pub struct UploadSessionHandler {
+	ctx: Shared,
+}
+
+impl Handler for UploadSessionHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let session = try!(own_upload(&self.ctx, &grants(req), try!(param(req, "id"))));
+		Ok( Response::with((status::Ok, json_mime(), encode(&session).unwrap())) )
+	}
+}
+
+/// Adds the request body to an upload at `offset`, which must be where the
+/// upload is up to.
+pub struct ChunkHandler {
+	ctx: Shared,
+}
+
+impl Handler for ChunkHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		try!(own_upload(&self.ctx, &grants(req), id));
+		let offset: u64 = try!( try!(query_param(&query_params(req), "offset"))
+			.ok_or(IndusError::Validation("a chunk needs an offset".into())) );
+		let session = try!(self.ctx.db.upload_chunk(&self.ctx.storage, id, offset, &mut req.body));
+		Ok( Response::with((status::Ok, json_mime(), encode(&session).unwrap())) )
+	}
+}
+
+/// Turns a complete upload into a file, checked against `sha256` or the
+/// checksum given when it was started.
+pub struct FinishUploadHandler {
+	ctx: Shared,
+}
+
+impl Handler for FinishUploadHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let grants = grants(req);
+		let id: i32 = try!(param(req, "id"));
+		let session = try!(own_upload(&self.ctx, &grants, id));
+		let folder = try!(self.ctx.db.folder(session.folder));
+		try!(authorize_space(&self.ctx, &grants, Access::Write, &folder.space(), "resource.create"));
+		let sha256: Option<String> = try!(query_param(&query_params(req), "sha256"));
+		let info = try!(self.ctx.db.finish_upload(&self.ctx.storage, id, sha256.as_ref().map(|s| &**s)));
+		Ok( Response::with((status::Created, json_mime(), encode(&info).unwrap())) )
+	}
+}
+
+/// Throws away an unfinished upload.
+pub struct CancelUploadHandler {
+	ctx: Shared,
+}
+
+impl Handler for CancelUploadHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let id: i32 = try!(param(req, "id"));
+		try!(own_upload(&self.ctx, &grants(req), id));
+		try!(self.ctx.db.cancel_upload(&self.ctx.storage, id));
+		Ok(Response::with(status::Ok))
+	}
+}
+
+pub fn router(ctx: Shared) -> Router {
+	let mut router = Router::new();
+	router.post("/login", LoginHandler { ctx: ctx.clone() });
+	router.get("/me", ProfileHandler);
+	router.get("/me/programme", ProgrammeHandler { ctx: ctx.clone() });
+	router.post("/logout", LogoutHandler { ctx: ctx.clone(), everywhere: false });
+	router.post("/logout/all", LogoutHandler { ctx: ctx.clone(), everywhere: true });
+	router.post("/password", PasswordHandler { ctx: ctx.clone() });
+	router.post("/password/reset", ResetHandler { ctx: ctx.clone() });
+	router.get("/users", require("user.view", UsersHandler { ctx: ctx.clone() }));
+	router.get("/users/:id", require("user.view", UserHandler { ctx: ctx.clone() }));
+	router.put("/users/:id", require("user.edit", UserUpdateHandler { ctx: ctx.clone() }));
+	router.delete("/users/:id", require("user.delete", DeleteUserHandler { ctx: ctx.clone() }));
+	router.post("/users/:id/deactivate", require("user.deactivate", ActivationHandler { ctx: ctx.clone(), active: false }));
+	router.post("/users/:id/reactivate", require("user.deactivate", ActivationHandler { ctx: ctx.clone(), active: true }));
+	router.get("/export/:kind", require("data.export", ExportHandler { ctx: ctx.clone() }));
+	router.get("/roles", require("role.view", RolesHandler { ctx: ctx.clone() }));
+	router.put("/users/:id/roles/:role", require("role.assign", UserRoleHandler { ctx: ctx.clone(), grant: true }));
+	router.delete("/users/:id/roles/:role", require("role.assign", UserRoleHandler { ctx: ctx.clone(), grant: false }));
+	router.put("/subjects/:code", require("subject.edit", SubjectHandler { ctx: ctx.clone() }));
+	router.get("/classes/:id/roster", require("class.view", RosterHandler { ctx: ctx.clone() }));
+	router.put("/classes/:id/students/:student", require("class.edit", EnrollmentHandler { ctx: ctx.clone(), enroll: true }));
+	router.delete("/classes/:id/students/:student", require("class.edit", EnrollmentHandler { ctx: ctx.clone(), enroll: false }));
+	router.get("/files", require("resource.view", FilesHandler { ctx: ctx.clone() }));
+	router.post("/files", require("resource.create", UploadHandler { ctx: ctx.clone() }));
+	router.get("/files/:id", require("resource.view", DownloadHandler { ctx: ctx.clone() }));
+	router.get("/files/:id/link", require("resource.view", LinkHandler { ctx: ctx.clone() }));
+	router.put("/files/:id", require("resource.create", ReplaceFileHandler { ctx: ctx.clone() }));
+	router.get("/files/:id/versions", require("resource.view", VersionsHandler { ctx: ctx.clone() }));
+	router.get("/files/:id/versions/:number", require("resource.view", VersionHandler { ctx: ctx.clone() }));
+	router.post("/files/:id/versions/:number/restore", require("resource.create", RestoreHandler { ctx: ctx.clone() }));
+	router.delete("/files/:id/versions", require("resource.delete", PruneHandler { ctx: ctx.clone() }));
+	router.get("/uploads", require("resource.create", UploadsHandler { ctx: ctx.clone() }));
+	router.post("/uploads", require("resource.create", NewUploadHandler { ctx: ctx.clone() }));
+	router.get("/uploads/:id", require("resource.create", UploadSessionHandler { ctx: ctx.clone() }));
+	router.put("/uploads/:id", require("resource.create", ChunkHandler { ctx: ctx.clone() }));
+	router.post("/uploads/:id/finish", require("resource.create", FinishUploadHandler { ctx: ctx.clone() }));
+	router.delete("/uploads/:id", require("resource.create", CancelUploadHandler { ctx: ctx.clone() }));
+	router.get("/objects/:key", ObjectHandler { ctx: ctx.clone() });
+	router.delete("/files/:id", require("resource.delete", DeleteFileHandler { ctx: ctx.clone() }));
+	router.get("/folders", require("resource.view", FoldersHandler { ctx: ctx.clone() }));
+	router.get("/folders/:id", require("resource.view", FolderHandler { ctx: ctx.clone() }));
+	router.post("/folders/:id/folders", require("resource.create", NewFolderHandler { ctx: ctx.clone() }));
+	router.delete("/folders/:id", require("resource.delete", DeleteFolderHandler { ctx: ctx.clone() }));
+	router
+}
+
+pub fn run(config: &Config, database: IndusDatabase) {
+	let sessions = match config.session_secret {
+		Some(ref secret) => Sessions::new(secret.clone(), config.session_timeouts),
+		None => {
+			warn!("No session.secret configured, sessions will not survive a restart");
+			Sessions::ephemeral(config.session_timeouts)
+		}
+	};
+	if let Err(e) = database.migrate() {
+		error!("Could not migrate the database: {}", e);
+		return
+	}
+	// Not the session secret itself, so that a link signature can never pass
+	// for anything signed for sessions.
+	let secret = config.session_secret.as_ref().map(|s| derive_key(s, "file-links")).unwrap_or_else(random_secret);
+	let storage = match Storage::new(&config.uploads, secret) {
+		Ok(storage) => storage,
+		Err(e) => {
+			error!("Could not open file storage: {}", e);
+			return
+		}
+	};
+	let ctx = Arc::new(Context {
+		db: database, sessions: sessions, storage: storage,
+	});
+	let mut chain = Chain::new(router(ctx.clone()));
+	chain.link_before(SessionMiddleware { ctx: ctx });
+	match Iron::new(chain).http(&*config.bind) {
+		Ok(_) => info!("Indus server listening on {}", config.bind),
+		Err(e) => error!("Could not start Indus server: {}", e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::content_disposition;
+
+	#[test]
+	fn download_names() {
+		assert_eq!( content_disposition("Worksheet 3.pdf"),
+			"attachment; filename=\"Worksheet 3.pdf\"; filename*=UTF-8''Worksheet%203.pdf" );
+		assert_eq!( content_disposition("\"Économie\".txt"),
+			"attachment; filename=\"__conomie_.txt\"; filename*=UTF-8''%22%C3%89conomie%22.txt" );
+	}
+}
@@ -0,0 +1,151 @@
+use postgres::{Connection, GenericConnection};
+use postgres::error::Error as pgError;
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+use crypt::{random_token, random_secret, hash_token};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use chrono::UTC;
+
+pub const COOKIE_NAME: &'static str = "indus_session";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+	/// Seconds a session may go unused before it expires.
+	pub idle: i64,
+	/// Seconds after login at which a session expires regardless of use.
+	pub absolute: i64,
+}
+
+impl Timeouts {
+	#[inline] pub fn new() -> Timeouts {
+		Timeouts {
+			idle: 30 * 60, absolute: 12 * 60 * 60,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+	pub token: String,
+	pub user_id: i32,
+	pub created: i64,
+	pub last_seen: i64,
+}
+
+/// Issues session tokens, signs them for use as cookies and keeps them in the
+/// `sessions` table. Only a hash of each token is stored.
+pub struct Sessions {
+	secret: Vec<u8>,
+	pub timeouts: Timeouts,
+}
+
+#[inline]
+fn now() -> i64 {
+	UTC::now().timestamp()
+}
+
+/// Ends every session of a user. Unlike `Sessions::destroy_all` this needs no
+/// session store, so it can run inside any transaction.
+pub fn revoke_all(conn: &GenericConnection, user_id: i32) -> Result<u64, pgError> {
+	conn.execute("DELETE FROM sessions WHERE user_id = $1", &[&user_id])
+}
+
+impl Sessions {
+	#[inline] pub fn new(secret: Vec<u8>, timeouts: Timeouts) -> Sessions {
+		Sessions {
+			secret: secret, timeouts: timeouts,
+		}
+	}
+
+	/// A session store with a freshly generated secret. Cookies issued by it do
+	/// not survive a restart.
+	pub fn ephemeral(timeouts: Timeouts) -> Sessions {
+		Sessions::new(random_secret(), timeouts)
+	}
+
+	fn sign(&self, token: &str) -> String {
+		let mut mac = Hmac::new(Sha256::new(), &self.secret);
+		mac.input(token.as_bytes());
+		mac.result().code().to_hex()
+	}
+
+	/// The cookie value for a session: the token followed by its signature.
+	pub fn cookie_value(&self, session: &Session) -> String {
+		format!("{}.{}", session.token, self.sign(&session.token))
+	}
+
+	/// Checks the signature on a cookie value and returns the token it carries.
+	pub fn verify_cookie<'a>(&self, value: &'a str) -> Option<&'a str> {
+		let mut parts = value.splitn(2, '.');
+		let (token, sig) = match (parts.next(), parts.next()) {
+			(Some(t), Some(s)) => (t, s),
+			_ => return None,
+		};
+		let sig = match sig.from_hex() {
+			Ok(s) => s,
+			Err(_) => return None,
+		};
+		let expected = self.sign(token).from_hex().unwrap();
+		if fixed_time_eq(&sig, &expected) { Some(token) } else { None }
+	}
+
+	pub fn create(&self, conn: &Connection, user_id: i32) -> Result<Session, pgError> {
+		let session = Session {
+			token: random_token(),
+			user_id: user_id,
+			created: now(), last_seen: now(),
+		};
+		try!( conn.execute(
+			"INSERT INTO sessions (token, user_id, created, last_seen) VALUES ($1, $2, $3, $4)",
+			&[&hash_token(&session.token), &session.user_id, &session.created, &session.last_seen]
+		) );
+		Ok(session)
+	}
+
+	/// Looks up a live session and marks it as used. Sessions past either
+	/// timeout are removed and `None` is returned.
+	pub fn resolve(&self, conn: &Connection, token: &str) -> Result<Option<Session>, pgError> {
+		let hashed = hash_token(token);
+		let stmt = try!( conn.prepare("SELECT user_id, created, last_seen FROM sessions WHERE token = $1") );
+		let rows = try!( stmt.query(&[&hashed]) );
+		if rows.is_empty() {
+			return Ok(None)
+		}
+		let row = rows.get(0);
+		let session = Session {
+			token: token.into(),
+			user_id: row.get(0), created: row.get(1), last_seen: row.get(2),
+		};
+		let time = now();
+		if time - session.last_seen > self.timeouts.idle || time - session.created > self.timeouts.absolute {
+			try!( conn.execute("DELETE FROM sessions WHERE token = $1", &[&hashed]) );
+			return Ok(None)
+		}
+		try!( conn.execute("UPDATE sessions SET last_seen = $1 WHERE token = $2", &[&time, &hashed]) );
+		Ok(Some(Session { last_seen: time, ..session }))
+	}
+
+	pub fn destroy(&self, conn: &Connection, token: &str) -> Result<u64, pgError> {
+		conn.execute("DELETE FROM sessions WHERE token = $1", &[&hash_token(token)])
+	}
+
+	/// Logs a user out of every session they have open.
+	#[inline]
+	pub fn destroy_all(&self, conn: &Connection, user_id: i32) -> Result<u64, pgError> {
+		revoke_all(conn, user_id)
+	}
+
+	pub fn purge_expired(&self, conn: &Connection) -> Result<u64, pgError> {
+		let time = now();
+		conn.execute(
+			"DELETE FROM sessions WHERE last_seen < $1 OR created < $2",
+			&[&(time - self.timeouts.idle), &(time - self.timeouts.absolute)]
+		)
+	}
+}
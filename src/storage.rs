@@ -0,0 +1,721 @@
+use postgres::GenericConnection;
+use postgres::rows::Row;
+
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rustc_serialize::hex::{FromHex, ToHex};
+use chrono::UTC;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use config::{Backend, UploadConfig};
+use crypt::random_token;
+use db::IndusDatabase;
+use enrollment::ClassId;
+use error::{self, IndusError, IndusResult};
+use s3::S3Backend;
+
+/// The type of uploads that do not say what they are.
+pub const DEFAULT_MIME: &'static str = "application/octet-stream";
+/// Longest file name or MIME type, in characters.
+pub const NAME_MAX_LEN: usize = 255;
+/// Bytes read from an upload at a time.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Suffix of objects the local backend is still writing.
+const PARTIAL: &'static str = ".partial";
+
+const FILE_COLUMNS: &'static str =
+	"SELECT f.id, f.owner_id, d.class_id, f.name, f.size, f.mime, f.sha256, f.created, f.modified, f.key, f.folder_id,
+	f.version FROM files f JOIN folders d ON d.id = f.folder_id";
+
+/// Somewhere to keep the contents of files, each under a key made by
+/// `random_token`.
+pub trait StorageBackend: Send + Sync {
+	/// Stores `size` bytes read from `src` under `key`, replacing whatever
+	/// was there.
+	fn put(&self, key: &str, src: &mut Read, size: u64) -> IndusResult<()>;
+
+	/// Like `put`, for contents in a temporary file that may be moved away.
+	fn put_file(&self, key: &str, path: &Path, size: u64) -> IndusResult<()> {
+		try!( self.put(key, &mut try!(File::open(path)), size) );
+		let _ = fs::remove_file(path);
+		Ok(())
+	}
+
+	fn get(&self, key: &str) -> IndusResult<Box<Read + Send>>;
+
+	/// Deleting a key that is not there is not an error.
+	fn delete(&self, key: &str) -> IndusResult<()>;
+
+	/// Every key starting with `prefix`, in no particular order.
+	fn list(&self, prefix: &str) -> IndusResult<Vec<String>>;
+
+	/// A link to the contents of `key` that works without logging in, for
+	/// `ttl` seconds.
+	fn presigned_url(&self, key: &str, ttl: i64) -> IndusResult<String>;
+}
+
+/// Signs links to `/objects/:key` on this server, for backends that cannot
+/// sign their own.
+#[derive(Debug, Clone)]
+pub struct UrlSigner {
+	secret: Vec<u8>,
+	/// The address of the server, without a trailing slash.
+	base: String,
+}
+
+impl UrlSigner {
+	pub fn new(secret: Vec<u8>, base: &str) -> UrlSigner {
+		UrlSigner { secret: secret, base: base.trim_right_matches('/').into() }
+	}
+
+	fn signature(&self, key: &str, expires: i64) -> String {
+		let mut mac = Hmac::new(Sha256::new(), &self.secret);
+		mac.input(format!("{}\n{}", key, expires).as_bytes());
+		mac.result().code().to_hex()
+	}
+
+	pub fn url(&self, key: &str, ttl: i64) -> String {
+		let expires = UTC::now().timestamp() + ttl;
+		format!("{}/objects/{}?expires={}&signature={}", self.base, key, expires, self.signature(key, expires))
+	}
+
+	/// Whether a link was signed by this signer and has not expired.
+	pub fn verify(&self, key: &str, expires: i64, signature: &str) -> bool {
+		if expires < UTC::now().timestamp() {
+			return false
+		}
+		match signature.from_hex() {
+			Ok(sig) => fixed_time_eq(&sig, &self.signature(key, expires).from_hex().unwrap()),
+			Err(_) => false,
+		}
+	}
+}
+
+/// Keys become file names on disk, so only letters, digits, `-` and `_`
+/// are allowed in them.
+pub fn check_key(key: &str) -> IndusResult<()> {
+	let valid = |c: char| match c {
+		'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+		_ => false,
+	};
+	if key.len() < 2 || !key.chars().all(valid) {
+		return Err(IndusError::Validation(format!("{:?} is not a valid object key", key)))
+	}
+	Ok(())
+}
+
+/// Keeps objects on disk, under a directory named by the first two
+/// characters of their key.
+pub struct LocalDisk {
+	root: PathBuf,
+	signer: UrlSigner,
+}
+
+impl LocalDisk {
+	pub fn new<P: Into<PathBuf>>(root: P, signer: UrlSigner) -> LocalDisk {
+		LocalDisk { root: root.into(), signer: signer }
+	}
+
+	fn path(&self, key: &str) -> IndusResult<PathBuf> {
+		try!(check_key(key));
+		Ok( self.root.join(&key[..2]).join(key) )
+	}
+
+	/// Where to write `key` before moving it into place, so that nobody reads
+	/// half an object.
+	fn partial(&self, key: &str) -> IndusResult<PathBuf> {
+		let path = try!(self.path(key));
+		if let Some(dir) = path.parent() {
+			try!( fs::create_dir_all(dir) );
+		}
+		Ok( path.with_file_name(format!("{}{}", key, PARTIAL)) )
+	}
+}
+
+impl StorageBackend for LocalDisk {
+	fn put(&self, key: &str, src: &mut Read, _size: u64) -> IndusResult<()> {
+		let (path, partial) = (try!(self.path(key)), try!(self.partial(key)));
+		let result = File::create(&partial)
+			.and_then(|mut out| io::copy(src, &mut out).and_then(|_| out.sync_all()))
+			.and_then(|_| fs::rename(&partial, &path));
+		if result.is_err() {
+			let _ = fs::remove_file(&partial);
+		}
+		Ok( try!(result) )
+	}
+
+	fn put_file(&self, key: &str, path: &Path, size: u64) -> IndusResult<()> {
+		let partial = try!(self.partial(key));
+		if fs::rename(path, &partial).is_err() {
+			// The temporary directory may be on another file system.
+			try!( self.put(key, &mut try!(File::open(path)), size) );
+			let _ = fs::remove_file(path);
+			return Ok(())
+		}
+		Ok( try!(fs::rename(&partial, try!(self.path(key)))) )
+	}
+
+	fn get(&self, key: &str) -> IndusResult<Box<Read + Send>> {
+		match File::open(try!(self.path(key))) {
+			Ok(file) => Ok(Box::new(file)),
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(IndusError::NotFound(format!("object {}", key))),
+			Err(e) => Err(IndusError::from(e)),
+		}
+	}
+
+	fn delete(&self, key: &str) -> IndusResult<()> {
+		match fs::remove_file(try!(self.path(key))) {
+			Err(e) => if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(IndusError::from(e)) },
+			Ok(()) => Ok(()),
+		}
+	}
+
+	fn list(&self, prefix: &str) -> IndusResult<Vec<String>> {
+		let mut keys = Vec::new();
+		let dirs = match fs::read_dir(&self.root) {
+			Ok(dirs) => dirs,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(keys),
+			Err(e) => return Err(IndusError::from(e)),
+		};
+		for dir in dirs {
+			let dir = try!(dir);
+			if !try!(dir.file_type()).is_dir() {
+				continue
+			}
+			for entry in try!(fs::read_dir(dir.path())) {
+				let name = try!(entry).file_name().to_string_lossy().into_owned();
+				if name.starts_with(prefix) && check_key(&name).is_ok() {
+					keys.push(name);
+				}
+			}
+		}
+		Ok(keys)
+	}
+
+	fn presigned_url(&self, key: &str, ttl: i64) -> IndusResult<String> {
+		try!(check_key(key));
+		Ok( self.signer.url(key, ttl) )
+	}
+}
+
+/// Opens one of the configured backends. `secret` signs links to objects
+/// on local disk.
+pub fn backend(config: &UploadConfig, kind: Backend, secret: Vec<u8>) -> IndusResult<Box<StorageBackend>> {
+	match (kind, config.s3.as_ref()) {
+		(Backend::Local, _) => Ok( Box::new(LocalDisk::new(config.root.join("objects"), UrlSigner::new(secret, &config.url))) ),
+		(Backend::S3, Some(s3)) => Ok( Box::new(try!(S3Backend::new(s3.clone()))) ),
+		(Backend::S3, None) => Err(IndusError::Validation("no S3 bucket is configured".into())),
+	}
+}
+
+/// A stored file as users see it.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct FileInfo {
+	pub id: i32,
+	pub owner: i32,
+	pub folder: i32,
+	/// The class whose folder the file is in, if any.
+	pub class: Option<ClassId>,
+	pub name: String,
+	/// In bytes.
+	pub size: i64,
+	pub mime: String,
+	/// Hex encoded SHA-256 of the contents.
+	pub sha256: String,
+	pub created: i64,
+	pub modified: i64,
+	/// The number of the current version.
+	pub version: i32,
+}
+
+/// A link to the contents of a file, for handing to a browser or another
+/// service.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct FileLink {
+	pub url: String,
+	pub expires: i64,
+}
+
+/// What the uploader says about a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewFile {
+	pub name: String,
+	/// `DEFAULT_MIME` if `None`.
+	pub mime: Option<String>,
+	pub folder: i32,
+}
+
+/// Which files to list. Every filter that is set must match.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FileQuery {
+	pub owner: Option<i32>,
+	/// Files in any folder of the class.
+	pub class: Option<ClassId>,
+	/// Files right in the folder, not in its subfolders.
+	pub folder: Option<i32>,
+}
+
+/// Contents written to a temporary file, with their size and checksum.
+pub struct Spooled {
+	pub path: PathBuf,
+	pub size: u64,
+	pub sha256: String,
+}
+
+/// Copies `src` to a new file in `dir` a chunk at a time, hashing it on the
+/// way, so that large files are never held in memory. Fails once more than
+/// `limit` bytes have been read.
+pub fn spool(src: &mut Read, dir: &Path, limit: u64) -> IndusResult<Spooled> {
+	try!( fs::create_dir_all(dir) );
+	let path = dir.join(random_token());
+	match write_spool(src, &path, limit) {
+		Ok((size, sha256)) => Ok(Spooled { path: path, size: size, sha256: sha256 }),
+		Err(e) => {
+			let _ = fs::remove_file(&path);
+			Err(e)
+		}
+	}
+}
+
+fn write_spool(src: &mut Read, path: &Path, limit: u64) -> IndusResult<(u64, String)> {
+	let mut out = try!(File::create(path));
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0u8; CHUNK_SIZE];
+	let mut size = 0u64;
+	loop {
+		let n = match src.read(&mut buf) {
+			Ok(0) => break,
+			Ok(n) => n,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(IndusError::Validation(format!("could not read upload: {}", e))),
+		};
+		size += n as u64;
+		if size > limit {
+			return Err(IndusError::Validation(format!("files may be at most {} bytes", limit)))
+		}
+		hasher.input(&buf[..n]);
+		try!( out.write_all(&buf[..n]) );
+	}
+	try!( out.sync_all() );
+	Ok((size, hasher.result_str()))
+}
+
+/// The hex encoded SHA-256 of an object, read a chunk at a time.
+pub fn checksum(backend: &StorageBackend, key: &str) -> IndusResult<String> {
+	digest(&mut *try!(backend.get(key)))
+}
+
+/// The hex encoded SHA-256 of everything `src` holds, read a chunk at a time.
+pub fn digest(src: &mut Read) -> IndusResult<String> {
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0u8; CHUNK_SIZE];
+	loop {
+		match src.read(&mut buf) {
+			Ok(0) => break,
+			Ok(n) => hasher.input(&buf[..n]),
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(IndusError::from(e)),
+		}
+	}
+	Ok(hasher.result_str())
+}
+
+/// The contents of the files, kept by the configured backend.
+pub struct Storage {
+	backend: Box<StorageBackend>,
+	signer: UrlSigner,
+	temp: PathBuf,
+	max_size: u64,
+	link_ttl: i64,
+	resume_ttl: i64,
+	uploading: Mutex<HashSet<i32>>,
+}
+
+impl Storage {
+	/// `secret` signs links to objects served by this server.
+	pub fn new(config: &UploadConfig, secret: Vec<u8>) -> IndusResult<Storage> {
+		Ok(Storage {
+			backend: try!(backend(config, config.backend, secret.clone())),
+			signer: UrlSigner::new(secret, &config.url),
+			temp: config.temp.clone(),
+			max_size: config.max_size,
+			link_ttl: config.link_ttl,
+			resume_ttl: config.resume_ttl,
+			uploading: Mutex::new(HashSet::new()),
+		})
+	}
+
+	pub fn backend(&self) -> &StorageBackend {
+		&*self.backend
+	}
+
+	pub fn signer(&self) -> &UrlSigner {
+		&self.signer
+	}
+
+	/// Where uploads are written before they are complete.
+	pub fn temp(&self) -> &Path {
+		&self.temp
+	}
+
+	pub fn max_size(&self) -> u64 {
+		self.max_size
+	}
+
+	/// Seconds a resumable upload may go without a chunk.
+	pub fn resume_ttl(&self) -> i64 {
+		self.resume_ttl
+	}
+
+	/// The resumable uploads a chunk is being written to right now.
+	pub fn uploading(&self) -> &Mutex<HashSet<i32>> {
+		&self.uploading
+	}
+
+	/// Removes the contents of a file, warning rather than failing since the
+	/// metadata is already gone by then.
+	pub fn remove(&self, key: &str) {
+		if let Err(e) = self.backend.delete(key) {
+			warn!("Could not remove stored file {}: {}", key, e);
+		}
+	}
+}
+
+/// Checks a file name given by a user: it is used as is in downloads, so it
+/// must not be a path.
+pub fn check_name(name: &str) -> IndusResult<()> {
+	if name.trim().is_empty() || name == "." || name == ".." {
+		return Err(IndusError::Validation("a file needs a name".into()))
+	}
+	if name.chars().count() > NAME_MAX_LEN {
+		return Err(IndusError::Validation(format!("file names may be at most {} characters", NAME_MAX_LEN)))
+	}
+	if name.chars().any(|c| c == '/' || c == '\\' || c.is_control()) {
+		return Err(IndusError::Validation(format!("{:?} is not a valid file name", name)))
+	}
+	Ok(())
+}
+
+pub fn check_mime(mime: &str) -> IndusResult<()> {
+	let essence = mime.split(';').next().unwrap_or("");
+	let parts = essence.split('/').collect::<Vec<_>>();
+	if mime.len() > NAME_MAX_LEN || mime.chars().any(|c| c.is_control()) || parts.len() != 2
+		|| parts.iter().any(|p| p.is_empty() || p.contains(char::is_whitespace)) {
+		return Err(IndusError::Validation(format!("{:?} is not a valid MIME type", mime)))
+	}
+	Ok(())
+}
+
+fn info(row: &Row) -> FileInfo {
+	FileInfo {
+		id: row.get(0),
+		owner: row.get(1),
+		folder: row.get(10),
+		class: row.get(2),
+		name: row.get(3),
+		size: row.get(4),
+		mime: row.get(5),
+		sha256: row.get(6),
+		created: row.get(7),
+		modified: row.get(8),
+		version: row.get(11),
+	}
+}
+
+/// A file and the key of its contents.
+pub fn get(conn: &GenericConnection, id: i32) -> IndusResult<(FileInfo, String)> {
+	let stmt = try!( conn.prepare(&format!("{} WHERE f.id = $1", FILE_COLUMNS)) );
+	let rows = try!( stmt.query(&[&id]) );
+	if rows.is_empty() {
+		return Err(IndusError::NotFound(format!("file {}", id)))
+	}
+	let row = rows.get(0);
+	Ok((info(&row), row.get(9)))
+}
+
+/// The file whose contents are kept under `key`, as it was at the version
+/// they belong to. Links handed out before a file was replaced keep serving
+/// what it held when they were made.
+pub fn find(conn: &GenericConnection, key: &str) -> IndusResult<FileInfo> {
+	let stmt = try!( conn.prepare(
+		"SELECT f.id, f.owner_id, d.class_id, f.name, v.size, v.mime, v.sha256, f.created, v.created, v.key, f.folder_id,
+		v.number FROM file_versions v JOIN files f ON f.id = v.file_id JOIN folders d ON d.id = f.folder_id
+		WHERE v.key = $1"
+	) );
+	let rows = try!( stmt.query(&[&key]) );
+	if rows.is_empty() {
+		return Err(IndusError::NotFound(format!("object {}", key)))
+	}
+	Ok(info(&rows.get(0)))
+}
+
+pub fn list(conn: &GenericConnection, query: &FileQuery) -> IndusResult<Vec<FileInfo>> {
+	let stmt = try!( conn.prepare(&format!(
+		"{} WHERE ($1::INT IS NULL OR f.owner_id = $1) AND ($2::INT IS NULL OR d.class_id = $2)
+		AND ($3::INT IS NULL OR f.folder_id = $3)
+		ORDER BY lower(f.name), f.id", FILE_COLUMNS
+	)) );
+	let rows = try!( stmt.query(&[&query.owner, &query.class, &query.folder]) );
+	Ok( rows.iter().map(|row| info(&row)).collect() )
+}
+
+/// Records `owner`'s new file, as its first version, with contents already
+/// stored under `key`. Deleting the key if that fails is up to the caller.
+pub fn record(conn: &GenericConnection, owner: i32, file: &NewFile, contents: &Spooled, key: &str)
+	-> IndusResult<FileInfo> {
+	let mime = file.mime.clone().unwrap_or(DEFAULT_MIME.into());
+	let now = UTC::now().timestamp();
+	let stmt = try!( conn.prepare(
+		"INSERT INTO files (owner_id, folder_id, name, size, mime, sha256, key, created, modified)
+		VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8) RETURNING id"
+	) );
+	let rows = try!( stmt.query(&[&owner, &file.folder, &file.name, &(contents.size as i64), &mime,
+		&contents.sha256, &key, &now])
+		.map_err(|e| error::missing(e, format!("folder {}", file.folder))) );
+	let id: i32 = rows.get(0).get(0);
+	try!( conn.execute(
+		"INSERT INTO file_versions (file_id, number, author_id, size, mime, sha256, key, created)
+		VALUES ($1, 1, $2, $3, $4, $5, $6, $7)",
+		&[&id, &owner, &(contents.size as i64), &mime, &contents.sha256, &key, &now]
+	) );
+	get(conn, id).map(|(info, _)| info)
+}
+
+/// The checksum recorded for every stored object, by key.
+fn recorded_checksums(conn: &GenericConnection) -> IndusResult<HashMap<String, String>> {
+	let stmt = try!( conn.prepare("SELECT key, sha256 FROM file_versions") );
+	let rows = try!( stmt.query(&[]) );
+	Ok( rows.iter().map(|row| (row.get(0), row.get(1))).collect() )
+}
+
+/// What `copy_objects` did.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CopyReport {
+	pub copied: Vec<String>,
+	/// Objects that were already there with the right contents.
+	pub skipped: Vec<String>,
+	/// Objects that could not be copied, and why.
+	pub failed: Vec<(String, String)>,
+}
+
+/// Copies one object, returning whether it had to be copied at all.
+fn copy_object(from: &StorageBackend, to: &StorageBackend, temp: &Path, key: &str,
+	recorded: Option<&String>, exists: bool) -> IndusResult<bool> {
+	if exists {
+		let expected = match recorded {
+			Some(sum) => sum.clone(),
+			None => try!(checksum(from, key)),
+		};
+		if try!(checksum(to, key)) == expected {
+			return Ok(false)
+		}
+	}
+	let spooled = try!( spool(&mut *try!(from.get(key)), temp, ::std::u64::MAX) );
+	if recorded.map(|sum| *sum != spooled.sha256).unwrap_or(false) {
+		let _ = fs::remove_file(&spooled.path);
+		return Err(IndusError::Validation("the original does not match its recorded checksum".into()))
+	}
+	if let Err(e) = to.put_file(key, &spooled.path, spooled.size) {
+		let _ = fs::remove_file(&spooled.path);
+		return Err(e)
+	}
+	if try!(checksum(to, key)) != spooled.sha256 {
+		let _ = to.delete(key);
+		return Err(IndusError::Validation("the copy does not match the original".into()))
+	}
+	Ok(true)
+}
+
+impl IndusDatabase {
+	/// Stores an upload read from `src` in a folder and records it as
+	/// `owner`'s. Whether they may write to the folder is up to the caller.
+	pub fn upload<R: Read>(&self, storage: &Storage, owner: i32, file: &NewFile, mut src: R) -> IndusResult<FileInfo> {
+		try!(check_name(&file.name));
+		try!(check_mime(file.mime.as_ref().map(|m| &**m).unwrap_or(DEFAULT_MIME)));
+		let spooled = try!(spool(&mut src, &storage.temp, storage.max_size));
+		let key = random_token();
+		// Stored before the transaction, so that no connection is held while
+		// the backend is slow.
+		if let Err(e) = storage.backend.put_file(&key, &spooled.path, spooled.size) {
+			let _ = fs::remove_file(&spooled.path);
+			return Err(e)
+		}
+		let result = self.transaction(|tx| record(tx, owner, file, &spooled, &key));
+		if result.is_err() {
+			let _ = storage.backend.delete(&key);
+		}
+		result
+	}
+
+	pub fn file(&self, id: i32) -> IndusResult<FileInfo> {
+		let conn = try!(self.conn());
+		get(&*conn, id).map(|(info, _)| info)
+	}
+
+	/// The file behind a signed link, as it was when the link was made.
+	pub fn file_by_key(&self, key: &str) -> IndusResult<FileInfo> {
+		let conn = try!(self.conn());
+		find(&*conn, key)
+	}
+
+	pub fn files(&self, query: &FileQuery) -> IndusResult<Vec<FileInfo>> {
+		let conn = try!(self.conn());
+		list(&*conn, query)
+	}
+
+	/// A file and its contents, ready to be streamed.
+	pub fn download(&self, storage: &Storage, id: i32) -> IndusResult<(FileInfo, Box<Read + Send>)> {
+		let conn = try!(self.conn());
+		let (info, key) = try!(get(&*conn, id));
+		let contents = try!(storage.backend.get(&key));
+		Ok((info, contents))
+	}
+
+	/// A link to the contents of a file that works without logging in for
+	/// `uploads.link_ttl` seconds.
+	pub fn file_link(&self, storage: &Storage, id: i32) -> IndusResult<FileLink> {
+		let conn = try!(self.conn());
+		let (_, key) = try!(get(&*conn, id));
+		Ok(FileLink {
+			url: try!(storage.backend.presigned_url(&key, storage.link_ttl)),
+			expires: UTC::now().timestamp() + storage.link_ttl,
+		})
+	}
+
+	/// Deletes a file with every version of it.
+	pub fn delete_file(&self, storage: &Storage, id: i32) -> IndusResult<()> {
+		let keys = try!( self.transaction(|tx| {
+			try!(get(tx, id));
+			let stmt = try!( tx.prepare("DELETE FROM file_versions WHERE file_id = $1 RETURNING key") );
+			let keys = try!( stmt.query(&[&id]) ).iter().map(|row| row.get::<_, String>(0)).collect::<Vec<_>>();
+			try!( tx.execute("DELETE FROM files WHERE id = $1", &[&id]) );
+			Ok(keys)
+		}) );
+		for key in keys {
+			storage.remove(&key);
+		}
+		Ok(())
+	}
+
+	/// Copies every object in `from` to `to`, through files in `temp`. Each
+	/// copy is checked against the checksum recorded for it, or else against
+	/// the original. Objects already in `to` with the right contents are
+	/// skipped, so an interrupted copy can simply be run again.
+	pub fn copy_objects(&self, from: &StorageBackend, to: &StorageBackend, temp: &Path) -> IndusResult<CopyReport> {
+		let recorded = {
+			let conn = try!(self.conn());
+			try!(recorded_checksums(&*conn))
+		};
+		let existing = try!(to.list("")).into_iter().collect::<HashSet<_>>();
+		let mut report = CopyReport::default();
+		for key in try!(from.list("")) {
+			match copy_object(from, to, temp, &key, recorded.get(&key), existing.contains(&key)) {
+				Ok(true) => report.copied.push(key),
+				Ok(false) => report.skipped.push(key),
+				Err(e) => report.failed.push((key, e.to_string())),
+			}
+		}
+		Ok(report)
+	}
+}
+
+/// A directory of its own under the system's temporary one for a test,
+/// removed with everything in it when dropped.
+#[cfg(test)]
+pub struct TempDir(pub PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+	pub fn new() -> TempDir {
+		TempDir(::std::env::temp_dir().join(format!("indus-test-{}", random_token())))
+	}
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crypt::random_token;
+	use std::io::Read;
+
+	#[test]
+	fn names() {
+		assert!(check_name("Worksheet 3.pdf").is_ok());
+		assert!(check_name("..hidden").is_ok());
+		assert!(check_name("").is_err());
+		assert!(check_name("  ").is_err());
+		assert!(check_name("..").is_err());
+		assert!(check_name("../../etc/passwd").is_err());
+		assert!(check_name("C:\\notes.txt").is_err());
+		assert!(check_name("line\nbreak").is_err());
+		assert!(check_name(&::std::iter::repeat("a").take(NAME_MAX_LEN + 1).collect::<String>()).is_err());
+	}
+
+	#[test]
+	fn mimes() {
+		assert!(check_mime(DEFAULT_MIME).is_ok());
+		assert!(check_mime("text/plain; charset=utf-8").is_ok());
+		assert!(check_mime("text").is_err());
+		assert!(check_mime("text/").is_err());
+		assert!(check_mime("/plain").is_err());
+		assert!(check_mime("text/plain/x").is_err());
+		assert!(check_mime("text /plain").is_err());
+	}
+
+	#[test]
+	fn keys() {
+		assert!(check_key(&random_token()).is_ok());
+		assert!(check_key("ab").is_ok());
+		assert!(check_key("a").is_err());
+		assert!(check_key("../ab").is_err());
+		assert!(check_key("ab.partial").is_err());
+	}
+
+	#[test]
+	fn local_disk() {
+		let signer = UrlSigner::new(vec![7; 32], "http://files.iisb/");
+		let dir = TempDir::new();
+		let disk = LocalDisk::new(dir.0.clone(), signer.clone());
+		assert_eq!( disk.list("").unwrap(), Vec::<String>::new() );
+
+		disk.put("abc1", &mut &b"supply"[..], 6).unwrap();
+		disk.put("abd2", &mut &b"demand"[..], 6).unwrap();
+		disk.put("abc1", &mut &b"elasticity"[..], 10).unwrap();
+		let mut contents = String::new();
+		disk.get("abc1").unwrap().read_to_string(&mut contents).unwrap();
+		assert_eq!( contents, "elasticity" );
+		let mut keys = disk.list("").unwrap();
+		keys.sort();
+		assert_eq!( keys, vec!["abc1".to_string(), "abd2".to_string()] );
+		assert_eq!( disk.list("abc").unwrap(), vec!["abc1".to_string()] );
+
+		disk.delete("abc1").unwrap();
+		disk.delete("abc1").unwrap();
+		assert!( disk.get("abc1").is_err() );
+		assert_eq!( disk.list("").unwrap(), vec!["abd2".to_string()] );
+
+		let url = disk.presigned_url("abd2", 60).unwrap();
+		assert!( url.starts_with("http://files.iisb/objects/abd2?expires=") );
+		let query = url.splitn(2, '?').nth(1).unwrap().split('&')
+			.map(|p| p.splitn(2, '=').nth(1).unwrap().to_string()).collect::<Vec<_>>();
+		let expires: i64 = query[0].parse().unwrap();
+		assert!( signer.verify("abd2", expires, &query[1]) );
+		assert!( !signer.verify("abc1", expires, &query[1]) );
+		assert!( !signer.verify("abd2", expires + 1, &query[1]) );
+		assert!( !UrlSigner::new(vec![8; 32], "http://files.iisb").verify("abd2", expires, &query[1]) );
+	}
+}
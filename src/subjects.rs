@@ -0,0 +1,212 @@
+use postgres::GenericConnection;
+use postgres::error::{Error as pgError, SqlState};
+
+use data::{Level, Subject};
+use db::IndusDatabase;
+use error::{missing, IndusError, IndusResult};
+
+/// Longest subject code; aliases may be up to `data::SUBJECT_MAX_LEN`.
+pub const CODE_MAX_LEN: usize = 32;
+
+/// A subject of the catalogue with everything known about it.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct SubjectInfo {
+	pub subject: Subject,
+	pub name: String,
+	/// IB group 1 to 6, or `None` outside the Diploma Programme.
+	pub group: Option<i16>,
+	/// The levels the subject is offered at.
+	pub levels: Vec<Level>,
+	/// Id of the teacher heading the department.
+	pub head: Option<i32>,
+	/// Part of the Diploma Programme core (TOK, EE and CAS).
+	pub core: bool,
+	/// Other names the subject goes by, lowercase.
+	pub aliases: Vec<String>,
+}
+
+/// Turns a code, display name or alias, in any case, into the subject it
+/// names.
+pub fn resolve(conn: &GenericConnection, name: &str) -> IndusResult<Subject> {
+	let stmt = try!( conn.prepare(
+		"SELECT code, 0 FROM subjects WHERE code = $1
+		UNION ALL SELECT code, 1 FROM subjects WHERE lower(code) = lower($1) OR lower(name) = lower($1)
+		UNION ALL SELECT code, 2 FROM subject_aliases WHERE alias = lower($1)
+		ORDER BY 2 LIMIT 1"
+	) );
+	let rows = try!( stmt.query(&[&name.trim()]) );
+	if rows.is_empty() {
+		return Err(IndusError::NotFound(format!("subject {}", name)))
+	}
+	let code: String = rows.get(0).get(0);
+	Ok( code.parse().ok().expect("subject codes in the database are valid") )
+}
+
+fn load(conn: &GenericConnection, code: Option<&str>) -> IndusResult<Vec<SubjectInfo>> {
+	let stmt = try!( conn.prepare(
+		"SELECT code, name, ib_group, hl, sl, head_id, core FROM subjects
+		WHERE $1::VARCHAR IS NULL OR code = $1 ORDER BY ib_group NULLS LAST, code"
+	) );
+	let aliases = try!( conn.prepare(
+		"SELECT code, alias FROM subject_aliases WHERE $1::VARCHAR IS NULL OR code = $1 ORDER BY alias"
+	) );
+	let aliases = try!( aliases.query(&[&code]) ).iter()
+		.map(|row| (row.get(0), row.get(1)))
+		.collect::<Vec<(String, String)>>();
+	let rows = try!( stmt.query(&[&code]) );
+	Ok( rows.iter().map(|row| {
+		let code: String = row.get(0);
+		let (hl, sl): (bool, bool) = (row.get(3), row.get(4));
+		let mut levels = Vec::new();
+		if hl { levels.push(Level::HL) }
+		if sl { levels.push(Level::SL) }
+		SubjectInfo {
+			aliases: aliases.iter().filter(|a| a.0 == code).map(|a| a.1.clone()).collect(),
+			subject: code.parse().ok().expect("subject codes in the database are valid"),
+			name: row.get(1),
+			group: row.get(2),
+			levels: levels,
+			head: row.get(5),
+			core: row.get(6),
+		}
+	}).collect() )
+}
+
+/// Every subject, by group.
+pub fn list(conn: &GenericConnection) -> IndusResult<Vec<SubjectInfo>> {
+	load(conn, None)
+}
+
+/// The subjects of the Diploma Programme core.
+pub fn core(conn: &GenericConnection) -> IndusResult<Vec<Subject>> {
+	let stmt = try!( conn.prepare("SELECT code FROM subjects WHERE core ORDER BY code") );
+	let rows = try!( stmt.query(&[]) );
+	Ok( rows.iter().filter_map(|row| row.get::<_, String>(0).parse().ok()).collect() )
+}
+
+pub fn get(conn: &GenericConnection, subject: &Subject) -> IndusResult<SubjectInfo> {
+	match load(conn, Some(subject.code())).map(|mut found| found.pop()) {
+		Ok(Some(info)) => Ok(info),
+		Ok(None) => Err(IndusError::NotFound(format!("subject {}", subject))),
+		Err(e) => Err(e),
+	}
+}
+
+fn validate(info: &SubjectInfo) -> IndusResult<()> {
+	let code = info.subject.code();
+	if code.len() > CODE_MAX_LEN || !code.chars().all(|c| match c { 'a'...'z' | 'A'...'Z' | '0'...'9' => true, _ => false }) {
+		return Err(IndusError::Validation(format!(
+			"{:?} is not a valid subject code: use up to {} letters and digits", code, CODE_MAX_LEN)))
+	}
+	if info.name.trim().is_empty() {
+		return Err(IndusError::Validation("a subject needs a name".into()))
+	}
+	match info.group {
+		Some(g) if g < 1 || g > 6 =>
+			return Err(IndusError::Validation(format!("there is no IB group {}", g))),
+		Some(_) if info.core =>
+			return Err(IndusError::Validation("core subjects are in no group".into())),
+		_ => {}
+	}
+	for alias in &info.aliases {
+		if alias.parse::<Subject>().is_err() {
+			return Err(IndusError::Validation(format!("{:?} is not a valid alias", alias)))
+		}
+	}
+	Ok(())
+}
+
+fn write_aliases(conn: &GenericConnection, info: &SubjectInfo) -> IndusResult<()> {
+	try!( conn.execute("DELETE FROM subject_aliases WHERE code = $1", &[&info.subject.code()]) );
+	for alias in &info.aliases {
+		match resolve(conn, alias) {
+			Ok(ref other) if *other != info.subject =>
+				return Err(IndusError::Duplicate(format!("alias {} of subject {}", alias, other))),
+			_ => {}
+		}
+		try!( conn.execute(
+			"INSERT INTO subject_aliases (alias, code) VALUES (lower($1), $2)",
+			&[alias, &info.subject.code()]
+		) );
+	}
+	Ok(())
+}
+
+impl IndusDatabase {
+	pub fn subjects(&self) -> IndusResult<Vec<SubjectInfo>> {
+		let conn = try!(self.conn());
+		list(&*conn)
+	}
+
+	/// Looks a subject up by code, name or alias.
+	pub fn subject(&self, name: &str) -> IndusResult<SubjectInfo> {
+		let conn = try!(self.conn());
+		let subject = try!(resolve(&*conn, name));
+		get(&*conn, &subject)
+	}
+
+	pub fn create_subject(&self, info: &SubjectInfo) -> IndusResult<()> {
+		try!(validate(info));
+		self.transaction(|tx| {
+			try!( tx.execute(
+				"INSERT INTO subjects (code, name, ib_group, hl, sl, head_id, core) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+				&[&info.subject.code(), &info.name.trim(), &info.group,
+					&info.levels.contains(&Level::HL), &info.levels.contains(&Level::SL), &info.head, &info.core]
+			).map_err(|e| missing(e, format!("teacher {}", info.head.unwrap_or(0)))) );
+			write_aliases(tx, info)
+		})
+	}
+
+	/// Replaces everything but the code of a subject.
+	pub fn update_subject(&self, info: &SubjectInfo) -> IndusResult<()> {
+		try!(validate(info));
+		self.transaction(|tx| {
+			let updated = try!( tx.execute(
+				"UPDATE subjects SET name = $2, ib_group = $3, hl = $4, sl = $5, head_id = $6, core = $7 WHERE code = $1",
+				&[&info.subject.code(), &info.name.trim(), &info.group,
+					&info.levels.contains(&Level::HL), &info.levels.contains(&Level::SL), &info.head, &info.core]
+			).map_err(|e| missing(e, format!("teacher {}", info.head.unwrap_or(0)))) );
+			if updated == 0 {
+				return Err(IndusError::NotFound(format!("subject {}", info.subject)))
+			}
+			write_aliases(tx, info)
+		})
+	}
+
+	/// Changes the code of a subject. Classes and aliases follow it, and so
+	/// do teachers whose subject was given by its old code, its name or one
+	/// of its aliases.
+	pub fn rename_subject(&self, subject: &Subject, code: &str) -> IndusResult<Subject> {
+		let renamed: Subject = try!( code.parse()
+			.map_err(|_| IndusError::Validation(format!("{:?} is not a valid subject code", code))) );
+		self.transaction(|tx| {
+			let mut info = try!(get(tx, subject));
+			info.subject = renamed.clone();
+			try!(validate(&info));
+			try!( tx.execute("UPDATE subjects SET code = $2 WHERE code = $1", &[&subject.code(), &code]) );
+			try!( tx.execute(
+				"UPDATE teachers SET subject = $2 WHERE lower(trim(subject)) = lower($1)
+					OR lower(trim(subject)) IN (SELECT lower(name) FROM subjects WHERE code = $2
+						UNION SELECT alias FROM subject_aliases WHERE code = $2)",
+				&[&subject.code(), &code]
+			) );
+			Ok(info.subject)
+		})
+	}
+
+	/// Removes a subject that no class uses any more and whose department
+	/// folders hold no files.
+	pub fn delete_subject(&self, subject: &Subject) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		match conn.execute("DELETE FROM subjects WHERE code = $1", &[&subject.code()]) {
+			Ok(0) => Err(IndusError::NotFound(format!("subject {}", subject))),
+			Ok(_) => Ok(()),
+			Err(pgError::DbError(ref e)) if e.code() == &SqlState::ForeignKeyViolation =>
+				Err(IndusError::Validation(match e.constraint() {
+					Some("files_folder_id_fkey") => format!("subject {} still has files in its department folders", subject),
+					_ => format!("subject {} still has classes", subject),
+				})),
+			Err(e) => Err(IndusError::from(e)),
+		}
+	}
+}
@@ -0,0 +1,303 @@
+use postgres::GenericConnection;
+use postgres::rows::Row;
+
+use chrono::UTC;
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crypt::random_token;
+use db::IndusDatabase;
+use error::{self, IndusError, IndusResult};
+use storage::{self, FileInfo, NewFile, Spooled, Storage, CHUNK_SIZE, DEFAULT_MIME};
+
+/// The directory under `uploads.temp` where sessions keep their chunks.
+const SESSIONS: &'static str = "sessions";
+
+const SESSION_COLUMNS: &'static str =
+	"SELECT id, owner_id, folder_id, name, mime, size, received, sha256, created, updated, key FROM upload_sessions";
+
+/// A resumable upload. Chunks are sent one after another, each starting at
+/// `offset`; once all `size` bytes are there it is finished into a file.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct UploadSession {
+	pub id: i32,
+	pub owner: i32,
+	/// Where the file goes once finished.
+	pub folder: i32,
+	pub name: String,
+	pub mime: String,
+	/// Of the whole file, in bytes.
+	pub size: i64,
+	/// Bytes received so far, where the next chunk must start.
+	pub offset: i64,
+	/// The checksum the finished file must have, if given up front.
+	pub sha256: Option<String>,
+	pub created: i64,
+	/// When the last chunk arrived.
+	pub updated: i64,
+	/// When the session is thrown away unless another chunk arrives.
+	pub expires: i64,
+}
+
+fn info(row: &Row, ttl: i64) -> UploadSession {
+	let updated: i64 = row.get(9);
+	UploadSession {
+		id: row.get(0),
+		owner: row.get(1),
+		folder: row.get(2),
+		name: row.get(3),
+		mime: row.get(4),
+		size: row.get(5),
+		offset: row.get(6),
+		sha256: row.get(7),
+		created: row.get(8),
+		updated: updated,
+		expires: updated + ttl,
+	}
+}
+
+fn dir(storage: &Storage) -> PathBuf {
+	storage.temp().join(SESSIONS)
+}
+
+/// A session that has not expired, with the key of its chunks. `lock` holds
+/// it until the end of the transaction.
+fn get(conn: &GenericConnection, storage: &Storage, id: i32, lock: bool) -> IndusResult<(UploadSession, String)> {
+	let stmt = try!( conn.prepare(&format!("{} WHERE id = $1 AND updated >= $2{}",
+		SESSION_COLUMNS, if lock { " FOR UPDATE" } else { "" })) );
+	let rows = try!( stmt.query(&[&id, &(UTC::now().timestamp() - storage.resume_ttl())]) );
+	if rows.is_empty() {
+		return Err(IndusError::NotFound(format!("upload {}", id)))
+	}
+	let row = rows.get(0);
+	Ok((info(&row, storage.resume_ttl()), row.get(10)))
+}
+
+/// Checks a hex encoded SHA-256 given by a client, in lowercase like the
+/// ones worked out here.
+fn check_sha256(sha256: &str) -> IndusResult<String> {
+	let sha256 = sha256.trim().to_lowercase();
+	if sha256.len() != 64 || !sha256.chars().all(|c| match c { '0'...'9' | 'a'...'f' => true, _ => false }) {
+		return Err(IndusError::Validation(format!("{:?} is not a hex encoded SHA-256", sha256)))
+	}
+	Ok(sha256)
+}
+
+/// Appends a chunk read from `src` to the file at `path`, which holds
+/// `offset` bytes that count, failing if the chunk is longer than `limit`.
+/// Returns the bytes kept and, if reading the chunk failed partway, why:
+/// what arrived before that is kept, so that the client can carry on from
+/// there.
+fn append(path: &Path, offset: u64, src: &mut Read, limit: u64) -> IndusResult<(u64, Option<String>)> {
+	let mut out = try!( OpenOptions::new().write(true).create(true).open(path) );
+	// Anything past the offset is from a chunk that was never recorded.
+	try!( out.set_len(offset) );
+	try!( out.seek(SeekFrom::End(0)) );
+	let mut buf = vec![0u8; CHUNK_SIZE];
+	let mut written = 0u64;
+	loop {
+		let n = match src.read(&mut buf) {
+			Ok(0) => break,
+			Ok(n) => n,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => {
+				try!( out.sync_all() );
+				return Ok((written, Some(e.to_string())))
+			}
+		};
+		if written + n as u64 > limit {
+			try!( out.set_len(offset) );
+			return Err(IndusError::Validation(format!("the chunk goes past the end of the upload, {} bytes on", limit)))
+		}
+		try!( out.write_all(&buf[..n]) );
+		written += n as u64;
+	}
+	try!( out.sync_all() );
+	Ok((written, None))
+}
+
+/// Marks a session as having a chunk written to it, until dropped. Chunks are
+/// written without holding a connection, let alone a row lock, so this is
+/// what keeps two of them from being written to the same session at once.
+struct Writing<'a> {
+	sessions: &'a Mutex<HashSet<i32>>,
+	id: i32,
+}
+
+impl<'a> Writing<'a> {
+	fn start(storage: &'a Storage, id: i32) -> IndusResult<Writing<'a>> {
+		if !storage.uploading().lock().unwrap().insert(id) {
+			return Err(IndusError::Conflict(format!("upload {} is already receiving a chunk", id)))
+		}
+		Ok(Writing { sessions: storage.uploading(), id: id })
+	}
+}
+
+impl<'a> Drop for Writing<'a> {
+	fn drop(&mut self) {
+		self.sessions.lock().unwrap().remove(&self.id);
+	}
+}
+
+impl IndusDatabase {
+	/// Starts a resumable upload of a file of `size` bytes by `owner`. As with
+	/// `upload`, whether they may write to the folder is up to the caller.
+	pub fn create_upload(&self, storage: &Storage, owner: i32, file: &NewFile, size: u64, sha256: Option<&str>)
+		-> IndusResult<UploadSession> {
+		try!(storage::check_name(&file.name));
+		let mime = file.mime.clone().unwrap_or(DEFAULT_MIME.into());
+		try!(storage::check_mime(&mime));
+		if size > storage.max_size() {
+			return Err(IndusError::Validation(format!("files may be at most {} bytes", storage.max_size())))
+		}
+		let sha256 = match sha256 {
+			Some(sum) => Some(try!(check_sha256(sum))),
+			None => None,
+		};
+		let key = random_token();
+		self.transaction(|tx| {
+			let now = UTC::now().timestamp();
+			let stmt = try!( tx.prepare(
+				"INSERT INTO upload_sessions (owner_id, folder_id, name, mime, size, sha256, key, created, updated)
+				VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8) RETURNING id"
+			) );
+			let rows = try!( stmt.query(&[&owner, &file.folder, &file.name, &mime, &(size as i64), &sha256, &key, &now])
+				.map_err(|e| error::missing(e, format!("folder {}", file.folder))) );
+			// Made only once the session is recorded, so that `purge_uploads`
+			// never mistakes it for a leftover.
+			try!( fs::create_dir_all(dir(storage)) );
+			try!( File::create(dir(storage).join(&key)) );
+			get(tx, storage, rows.get(0).get(0), false).map(|(session, _)| session)
+		})
+	}
+
+	pub fn upload_session(&self, storage: &Storage, id: i32) -> IndusResult<UploadSession> {
+		let conn = try!(self.conn());
+		get(&*conn, storage, id, false).map(|(session, _)| session)
+	}
+
+	/// The unfinished uploads of a user, oldest first.
+	pub fn upload_sessions(&self, storage: &Storage, owner: i32) -> IndusResult<Vec<UploadSession>> {
+		let conn = try!(self.conn());
+		let stmt = try!( conn.prepare(&format!("{} WHERE owner_id = $1 AND updated >= $2 ORDER BY created, id", SESSION_COLUMNS)) );
+		let rows = try!( stmt.query(&[&owner, &(UTC::now().timestamp() - storage.resume_ttl())]) );
+		Ok( rows.iter().map(|row| info(&row, storage.resume_ttl())).collect() )
+	}
+
+	/// Adds the chunk read from `src` at `offset`, which must be where the
+	/// upload is up to. If the chunk is cut short, what arrived is kept and
+	/// the error says where to carry on from.
+	pub fn upload_chunk<R: Read>(&self, storage: &Storage, id: i32, offset: u64, mut src: R) -> IndusResult<UploadSession> {
+		let _writing = try!(Writing::start(storage, id));
+		let (session, key) = {
+			let conn = try!(self.conn());
+			try!(get(&*conn, storage, id, false))
+		};
+		if offset != session.offset as u64 {
+			return Err(IndusError::Conflict(format!("upload {} is at offset {}, not {}", id, session.offset, offset)))
+		}
+		// However slow the client, no connection is held while the chunk arrives.
+		let (written, cut_short) = try!( append(&dir(storage).join(&key), offset,
+			&mut src, (session.size - session.offset) as u64) );
+		let session = try!( self.transaction(|tx| {
+			// Only if the upload is still where it was, and was not cancelled
+			// meanwhile.
+			let stmt = try!( tx.prepare(
+				"UPDATE upload_sessions SET received = $3, updated = $4 WHERE id = $1 AND received = $2"
+			) );
+			if try!(stmt.execute(&[&id, &session.offset, &(session.offset + written as i64), &UTC::now().timestamp()])) == 0 {
+				return Err(IndusError::Conflict(format!("upload {} changed while the chunk arrived", id)))
+			}
+			get(tx, storage, id, false).map(|(session, _)| session)
+		}) );
+		match cut_short {
+			Some(why) => Err(IndusError::Validation(
+				format!("could not read the chunk: {}; upload {} is at offset {}", why, id, session.offset))),
+			None => Ok(session),
+		}
+	}
+
+	/// Turns a complete upload into a file, once its contents match the
+	/// checksum given now or when the upload was started. An upload that
+	/// does not match is thrown away, as resending chunks cannot fix it.
+	pub fn finish_upload(&self, storage: &Storage, id: i32, sha256: Option<&str>) -> IndusResult<FileInfo> {
+		let (session, key) = {
+			let conn = try!(self.conn());
+			try!(get(&*conn, storage, id, false))
+		};
+		if session.offset != session.size {
+			return Err(IndusError::Conflict(format!("upload {} has {} of {} bytes", id, session.offset, session.size)))
+		}
+		let path = dir(storage).join(&key);
+		let actual = try!(storage::digest(&mut try!(File::open(&path))));
+		let expected = match sha256 {
+			Some(sum) => Some(try!(check_sha256(sum))),
+			None => session.sha256.clone(),
+		};
+		if expected.map(|sum| sum != actual).unwrap_or(false) {
+			try!(self.cancel_upload(storage, id));
+			return Err(IndusError::Validation(format!("upload {} does not match its checksum", id)))
+		}
+		let file = NewFile { name: session.name.clone(), mime: Some(session.mime.clone()), folder: session.folder };
+		let contents = Spooled { path: path, size: session.size as u64, sha256: actual };
+		let stored = random_token();
+		// Copied to the backend before the transaction, so that no connection
+		// is held meanwhile. The chunks stay until the file is recorded, so
+		// that the upload can still be finished if recording it fails.
+		let result = File::open(&contents.path).map_err(IndusError::from)
+			.and_then(|mut src| storage.backend().put(&stored, &mut src, contents.size))
+			.and_then(|_| self.transaction(|tx| {
+				// Taken again under lock, in case the upload was cancelled meanwhile.
+				try!(get(tx, storage, id, true));
+				try!( tx.execute("DELETE FROM upload_sessions WHERE id = $1", &[&id]) );
+				storage::record(tx, session.owner, &file, &contents, &stored)
+			}));
+		match result {
+			Ok(_) => { let _ = fs::remove_file(&contents.path); }
+			Err(_) => { let _ = storage.backend().delete(&stored); }
+		}
+		result
+	}
+
+	pub fn cancel_upload(&self, storage: &Storage, id: i32) -> IndusResult<()> {
+		let key = try!( self.transaction(|tx| {
+			let (_, key) = try!(get(tx, storage, id, true));
+			try!( tx.execute("DELETE FROM upload_sessions WHERE id = $1", &[&id]) );
+			Ok(key)
+		}) );
+		let _ = fs::remove_file(dir(storage).join(&key));
+		Ok(())
+	}
+
+	/// Throws away uploads that have gone `uploads.resume_ttl` seconds
+	/// without a chunk, and chunks left behind by sessions that are gone.
+	/// Returns how many sessions expired.
+	pub fn purge_uploads(&self, storage: &Storage) -> IndusResult<u64> {
+		// Listed before the sessions are, as a session's file is only made
+		// once the session is recorded.
+		let files = match fs::read_dir(dir(storage)) {
+			Ok(entries) => try!(entries.map(|entry| entry.map(|e| e.file_name())).collect::<io::Result<Vec<_>>>()),
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+			Err(e) => return Err(IndusError::from(e)),
+		};
+		let (expired, live) = try!( self.transaction(|tx| {
+			let stmt = try!( tx.prepare("DELETE FROM upload_sessions WHERE updated < $1") );
+			let expired = try!( stmt.execute(&[&(UTC::now().timestamp() - storage.resume_ttl())]) );
+			let stmt = try!( tx.prepare("SELECT key FROM upload_sessions") );
+			let live = try!( stmt.query(&[]) ).iter().map(|row| row.get::<_, String>(0)).collect::<HashSet<_>>();
+			Ok((expired, live))
+		}) );
+		for name in files {
+			if !name.to_str().map(|key| live.contains(key)).unwrap_or(false) {
+				if let Err(e) = fs::remove_file(dir(storage).join(&name)) {
+					warn!("Could not remove abandoned upload {:?}: {}", name, e);
+				}
+			}
+		}
+		Ok(expired)
+	}
+}
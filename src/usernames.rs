@@ -0,0 +1,175 @@
+use postgres::GenericConnection;
+
+use chrono::UTC;
+
+use error::{IndusError, IndusResult};
+
+pub const MAX_LEN: usize = 128;
+
+/// Spells a lowercase letter in ASCII, dropping it if there is no sensible
+/// spelling.
+fn ascii(c: char) -> &'static str {
+	match c {
+		'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ą' => "a",
+		'æ' => "ae",
+		'ç' | 'č' | 'ć' => "c",
+		'ď' | 'đ' => "d",
+		'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ę' | 'ě' => "e",
+		'ğ' => "g",
+		'ì' | 'í' | 'î' | 'ï' | 'ī' | 'ı' => "i",
+		'ł' => "l",
+		'ñ' | 'ń' | 'ň' => "n",
+		'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => "o",
+		'œ' => "oe",
+		'ř' => "r",
+		'ß' => "ss",
+		'ś' | 'š' | 'ş' => "s",
+		'ť' | 'ţ' => "t",
+		'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' => "u",
+		'ý' | 'ÿ' => "y",
+		'ź' | 'ż' | 'ž' => "z",
+		'þ' => "th",
+		_ => "",
+	}
+}
+
+/// Lowercases a name and reduces it to ASCII letters and digits, so that
+/// "José" gives "jose" and "van der Berg" gives "vanderberg".
+pub fn normalize_part(name: &str) -> String {
+	name.trim().chars().flat_map(char::to_lowercase).fold(String::new(), |mut out, c| {
+		match c {
+			'a'...'z' | '0'...'9' => out.push(c),
+			c => out.push_str(ascii(c)),
+		}
+		out
+	})
+}
+
+/// The username a person would get if nobody else had it: `first.last`.
+pub fn base(first_name: &str, last_name: &str) -> String {
+	let first = normalize_part(first_name);
+	let last = normalize_part(last_name);
+	let name = match (first.is_empty(), last.is_empty()) {
+		(false, false) => format!("{}.{}", first, last),
+		(false, true) => first,
+		(true, false) => last,
+		(true, true) => "user".into(),
+	};
+	// leave room for a numeric suffix
+	name.chars().take(MAX_LEN - 8).collect()
+}
+
+/// Whether `name` is acceptable as a username chosen by an admin.
+pub fn validate(name: &str) -> IndusResult<()> {
+	let valid = !name.is_empty() && name.len() <= MAX_LEN
+		&& name.chars().all(|c| match c { 'a'...'z' | '0'...'9' | '.' | '_' | '-' => true, _ => false })
+		&& name.chars().next().map(|c| c.is_alphanumeric()).unwrap_or(false);
+	if valid {
+		Ok(())
+	} else {
+		Err(IndusError::Validation(format!(
+			"{:?} is not a valid username: use lowercase letters, digits, '.', '_' and '-'", name)))
+	}
+}
+
+/// Whether a username is free, that is neither taken nor reserved.
+pub fn available(conn: &GenericConnection, name: &str) -> IndusResult<bool> {
+	let stmt = try!( conn.prepare(
+		"SELECT 1 FROM users WHERE username = $1 UNION ALL SELECT 1 FROM reserved_usernames WHERE username = $1"
+	) );
+	Ok( try!(stmt.query(&[&name])).is_empty() )
+}
+
+/// Picks an unused username for a new user, appending the smallest free
+/// number to `first.last` if needed. Holds a transaction-level lock on the
+/// base name, so `conn` should be a transaction that goes on to insert it.
+pub fn allocate(conn: &GenericConnection, first_name: &str, last_name: &str) -> IndusResult<String> {
+	let base = base(first_name, last_name);
+	try!( conn.execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&base]) );
+	let stmt = try!( conn.prepare(
+		"SELECT username FROM users WHERE username LIKE $1 || '%'
+		UNION SELECT username FROM reserved_usernames WHERE username LIKE $1 || '%'"
+	) );
+	let rows = try!( stmt.query(&[&base]) );
+	let taken = rows.iter().map(|row| row.get(0)).collect::<Vec<String>>();
+	if !taken.contains(&base) {
+		return Ok(base)
+	}
+	let mut n = 2;
+	loop {
+		let name = format!("{}{}", base, n);
+		if !taken.contains(&name) {
+			return Ok(name)
+		}
+		n += 1;
+	}
+}
+
+pub fn reserve(conn: &GenericConnection, name: &str, reason: &str) -> IndusResult<()> {
+	try!(validate(name));
+	if !try!(available(conn, name)) {
+		return Err(IndusError::Duplicate(format!("username {}", name)))
+	}
+	try!( conn.execute(
+		"INSERT INTO reserved_usernames (username, reason, created) VALUES ($1, $2, $3)",
+		&[&name, &reason, &UTC::now().timestamp()]
+	) );
+	Ok(())
+}
+
+pub fn release(conn: &GenericConnection, name: &str) -> IndusResult<()> {
+	match try!( conn.execute("DELETE FROM reserved_usernames WHERE username = $1", &[&name]) ) {
+		0 => Err(IndusError::NotFound(format!("reserved username {}", name))),
+		_ => Ok(()),
+	}
+}
+
+pub fn rename(conn: &GenericConnection, id: i32, name: &str) -> IndusResult<()> {
+	try!(validate(name));
+	if !try!(available(conn, name)) {
+		return Err(IndusError::Duplicate(format!("username {}", name)))
+	}
+	match try!( conn.execute("UPDATE users SET username = $1 WHERE id = $2", &[&name, &id]) ) {
+		0 => Err(IndusError::NotFound(format!("user {}", id))),
+		_ => Ok(()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn plain() {
+		assert_eq!(base("Anshuman", "Medhi"), "anshuman.medhi");
+		assert_eq!(base("  Hari ", "PRASAD"), "hari.prasad");
+	}
+
+	#[test]
+	fn non_ascii() {
+		assert_eq!(base("José", "Müller"), "jose.muller");
+		assert_eq!(base("Łukasz", "Wałęsa"), "lukasz.walesa");
+		assert_eq!(base("Søren", "Kierkegaard"), "soren.kierkegaard");
+	}
+
+	#[test]
+	fn multi_part() {
+		assert_eq!(base("Mary Jane", "van der Berg"), "maryjane.vanderberg");
+		assert_eq!(base("Jean-Luc", "O'Neill"), "jeanluc.oneill");
+	}
+
+	#[test]
+	fn empty_parts() {
+		assert_eq!(base("", "Medhi"), "medhi");
+		assert_eq!(base("李", "明"), "user");
+	}
+
+	#[test]
+	fn admin_names() {
+		assert!(validate("anshuman.medhi").is_ok());
+		assert!(validate("hod-economics").is_ok());
+		assert!(validate(".hidden").is_err());
+		assert!(validate("Anshuman").is_err());
+		assert!(validate("").is_err());
+	}
+}
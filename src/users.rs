@@ -0,0 +1,300 @@
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use postgres::types::ToSql;
+
+use chrono::UTC;
+
+use data::Gender;
+use db::IndusDatabase;
+use error::{IndusError, IndusResult};
+use session;
+
+/// Users per page when the query does not say.
+pub const PAGE_SIZE: i64 = 50;
+/// Most users a single page may hold.
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+const USER_TABLES: &'static str =
+	"FROM users u LEFT JOIN students s ON s.id = u.id LEFT JOIN teachers t ON t.id = u.id";
+
+/// The conditions of a `UserQuery`. `$1` to `$6` are its fields up to
+/// `active`.
+const USER_FILTERS: &'static str =
+	"WHERE ($1::VARCHAR IS NULL OR u.first_name || ' ' || u.last_name ILIKE $1 OR u.username ILIKE $1)
+	AND ($2::VARCHAR IS NULL
+		OR ($2 = 'student' AND s.id IS NOT NULL)
+		OR ($2 = 'teacher' AND t.id IS NOT NULL)
+		OR ($2 = 'hod' AND t.hod)
+		OR EXISTS (SELECT 1 FROM user_roles r WHERE r.user_id = u.id AND r.role = $2))
+	AND ($3::SMALLINT IS NULL OR s.grade = $3)
+	AND ($4::VARCHAR IS NULL OR s.section = $4)
+	AND ($5::VARCHAR IS NULL OR lower($5) IN (
+		lower(t.subject), (SELECT lower(a.code) FROM subject_aliases a WHERE a.alias = lower(t.subject))))
+	AND ($6::BOOLEAN IS NULL OR u.active = $6)";
+
+const USER_COLUMNS: &'static str =
+	"SELECT u.id, u.username, u.first_name, u.last_name, u.gender, u.active, u.deactivated,
+	s.grade, s.section, t.subject, COALESCE(t.hod, FALSE)";
+
+/// A search of the users. Every filter that is set must match.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UserQuery {
+	/// Part of the full name or username, in any case.
+	pub name: Option<String>,
+	/// `student`, `teacher`, `hod` or any other role.
+	pub role: Option<String>,
+	pub grade: Option<i16>,
+	pub section: Option<String>,
+	/// The code of the subject teachers teach. Teachers whose subject is
+	/// written as an alias of it match too.
+	pub subject: Option<String>,
+	pub active: Option<bool>,
+	/// Counted from 1.
+	pub page: i64,
+	/// `PAGE_SIZE` if 0.
+	pub per_page: i64,
+}
+
+/// A user as admins see them.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct UserSummary {
+	pub id: i32,
+	pub username: String,
+	pub first_name: String,
+	pub last_name: String,
+	pub gender: Gender,
+	pub active: bool,
+	/// When the user was deactivated, if they are.
+	pub deactivated: Option<i64>,
+	/// Set for students.
+	pub grade: Option<i16>,
+	pub section: Option<String>,
+	/// Set for teachers.
+	pub subject: Option<String>,
+	pub hod: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct UserPage {
+	pub users: Vec<UserSummary>,
+	/// Users matching the query on all pages.
+	pub total: i64,
+	pub page: i64,
+	pub per_page: i64,
+}
+
+/// Changes to a profile. Fields left `None` stay as they are.
+#[derive(Debug, Clone, PartialEq, Default, RustcDecodable)]
+pub struct UserUpdate {
+	pub first_name: Option<String>,
+	pub last_name: Option<String>,
+	pub gender: Option<Gender>,
+	/// Only for students.
+	pub grade: Option<i16>,
+	pub section: Option<String>,
+	/// Only for teachers.
+	pub subject: Option<String>,
+	pub hod: Option<bool>,
+}
+
+/// Turns a search term into an `ILIKE` pattern that matches it anywhere.
+pub fn name_pattern(name: &str) -> String {
+	let mut pattern = String::from("%");
+	for c in name.trim().chars() {
+		if c == '%' || c == '_' || c == '\\' {
+			pattern.push('\\');
+		}
+		pattern.push(c);
+	}
+	pattern.push('%');
+	pattern
+}
+
+fn summary(row: &Row) -> UserSummary {
+	let first_name: String = row.get(2);
+	let last_name: String = row.get(3);
+	let section: Option<String> = row.get(8);
+	let subject: Option<String> = row.get(9);
+	UserSummary {
+		id: row.get(0),
+		username: row.get(1),
+		first_name: first_name.trim().into(),
+		last_name: last_name.trim().into(),
+		gender: Gender::from(row.get::<_, bool>(4)),
+		active: row.get(5),
+		deactivated: row.get(6),
+		grade: row.get(7),
+		section: section.map(|s| s.trim().into()),
+		subject: subject.map(|s| s.trim().into()),
+		hod: row.get(10),
+	}
+}
+
+fn fetch(conn: &GenericConnection, query: &UserQuery, limit: Option<i64>, offset: i64) -> IndusResult<Vec<UserSummary>> {
+	let name = query.name.as_ref().map(|n| name_pattern(n));
+	let stmt = try!( conn.prepare(&format!(
+		"{} {} {} ORDER BY lower(u.last_name), lower(u.first_name), u.id LIMIT $7 OFFSET $8",
+		USER_COLUMNS, USER_TABLES, USER_FILTERS
+	)) );
+	let rows = try!( stmt.query(&[&name, &query.role, &query.grade, &query.section, &query.subject, &query.active,
+		&limit, &offset]) );
+	Ok( rows.iter().map(|row| summary(&row)).collect() )
+}
+
+/// Every user matching a query, ignoring its page.
+pub fn list(conn: &GenericConnection, query: &UserQuery) -> IndusResult<Vec<UserSummary>> {
+	fetch(conn, query, None, 0)
+}
+
+/// Finds a page of users.
+pub fn search(conn: &GenericConnection, query: &UserQuery) -> IndusResult<UserPage> {
+	let per_page = match query.per_page {
+		0 => PAGE_SIZE,
+		n if n < 0 || n > MAX_PAGE_SIZE =>
+			return Err(IndusError::Validation(format!("pages hold 1 to {} users", MAX_PAGE_SIZE))),
+		n => n,
+	};
+	let page = if query.page < 1 { 1 } else { query.page };
+	let name = query.name.as_ref().map(|n| name_pattern(n));
+	let filters: &[&ToSql] = &[&name, &query.role, &query.grade, &query.section, &query.subject, &query.active];
+
+	let stmt = try!( conn.prepare(&format!("SELECT COUNT(*) {} {}", USER_TABLES, USER_FILTERS)) );
+	let total: i64 = try!(stmt.query(filters)).get(0).get(0);
+	Ok(UserPage {
+		users: try!(fetch(conn, query, Some(per_page), (page - 1) * per_page)),
+		total: total,
+		page: page,
+		per_page: per_page,
+	})
+}
+
+pub fn get(conn: &GenericConnection, id: i32) -> IndusResult<UserSummary> {
+	let stmt = try!( conn.prepare(&format!("{} {} WHERE u.id = $1", USER_COLUMNS, USER_TABLES)) );
+	let rows = try!( stmt.query(&[&id]) );
+	if rows.is_empty() {
+		return Err(IndusError::NotFound(format!("user {}", id)))
+	}
+	Ok(summary(&rows.get(0)))
+}
+
+fn validate(user: &UserSummary, update: &UserUpdate) -> IndusResult<()> {
+	for name in update.first_name.iter().chain(update.last_name.iter()) {
+		if name.trim().is_empty() {
+			return Err(IndusError::Validation("first and last name are required".into()))
+		}
+	}
+	if user.grade.is_none() && (update.grade.is_some() || update.section.is_some()) {
+		return Err(IndusError::Validation(format!("user {} is not a student", user.id)))
+	}
+	if user.subject.is_none() && (update.subject.is_some() || update.hod.is_some()) {
+		return Err(IndusError::Validation(format!("user {} is not a teacher", user.id)))
+	}
+	if let Some(ref section) = update.section {
+		if section.chars().count() != 1 {
+			return Err(IndusError::Validation(format!("section must be a single letter, not {:?}", section)))
+		}
+	}
+	if let Some(ref subject) = update.subject {
+		if subject.trim().is_empty() {
+			return Err(IndusError::Validation("a teacher needs a subject".into()))
+		}
+	}
+	Ok(())
+}
+
+impl IndusDatabase {
+	pub fn users(&self, query: &UserQuery) -> IndusResult<UserPage> {
+		let conn = try!(self.conn());
+		search(&*conn, query)
+	}
+
+	pub fn user(&self, id: i32) -> IndusResult<UserSummary> {
+		let conn = try!(self.conn());
+		get(&*conn, id)
+	}
+
+	/// Edits the profile of a user. The username stays; see `rename_user`.
+	pub fn update_user(&self, id: i32, update: &UserUpdate) -> IndusResult<UserSummary> {
+		self.transaction(|tx| {
+			let user = try!(get(tx, id));
+			try!(validate(&user, update));
+			let gender: Option<bool> = update.gender.map(|g| g.into());
+			try!( tx.execute(
+				"UPDATE users SET first_name = COALESCE($2, first_name), last_name = COALESCE($3, last_name),
+				gender = COALESCE($4, gender) WHERE id = $1",
+				&[&id, &update.first_name.as_ref().map(|n| n.trim()), &update.last_name.as_ref().map(|n| n.trim()), &gender]
+			) );
+			if user.grade.is_some() {
+				try!( tx.execute(
+					"UPDATE students SET grade = COALESCE($2, grade), section = COALESCE($3, section) WHERE id = $1",
+					&[&id, &update.grade, &update.section]
+				) );
+			}
+			if user.subject.is_some() {
+				try!( tx.execute(
+					"UPDATE teachers SET subject = COALESCE($2, subject), hod = COALESCE($3, hod) WHERE id = $1",
+					&[&id, &update.subject.as_ref().map(|s| s.trim()), &update.hod]
+				) );
+			}
+			get(tx, id)
+		})
+	}
+
+	/// Stops a user from logging in and ends their sessions. Everything they
+	/// own stays.
+	pub fn deactivate_user(&self, id: i32) -> IndusResult<()> {
+		self.transaction(|tx| {
+			let updated = try!( tx.execute(
+				"UPDATE users SET active = FALSE, deactivated = COALESCE(deactivated, $2) WHERE id = $1",
+				&[&id, &UTC::now().timestamp()]
+			) );
+			if updated == 0 {
+				return Err(IndusError::NotFound(format!("user {}", id)))
+			}
+			try!( session::revoke_all(tx, id) );
+			try!( tx.execute("DELETE FROM password_resets WHERE user_id = $1", &[&id]) );
+			Ok(())
+		})
+	}
+
+	pub fn reactivate_user(&self, id: i32) -> IndusResult<()> {
+		let conn = try!(self.conn());
+		match try!( conn.execute("UPDATE users SET active = TRUE, deactivated = NULL WHERE id = $1", &[&id]) ) {
+			0 => Err(IndusError::NotFound(format!("user {}", id))),
+			_ => Ok(()),
+		}
+	}
+
+	/// Deletes a user and everything that is theirs for good. `confirm` must
+	/// be their username, so that a mistyped id deletes nobody. Users who still
+	/// own files cannot be deleted, as the contents would be left behind.
+	pub fn delete_user(&self, id: i32, confirm: &str) -> IndusResult<()> {
+		self.transaction(|tx| {
+			let user = try!(get(tx, id));
+			if user.username != confirm {
+				return Err(IndusError::Validation(format!(
+					"confirm deleting user {} by giving their username", id)))
+			}
+			let stmt = try!( tx.prepare("SELECT 1 FROM files WHERE owner_id = $1 LIMIT 1") );
+			if !try!(stmt.query(&[&id])).is_empty() {
+				return Err(IndusError::Validation(format!(
+					"user {} still owns files; delete them first", id)))
+			}
+			try!( tx.execute("DELETE FROM users WHERE id = $1", &[&id]) );
+			Ok(())
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn patterns() {
+		assert_eq!( name_pattern("medhi"), "%medhi%" );
+		assert_eq!( name_pattern(" Anshuman Medhi "), "%Anshuman Medhi%" );
+		assert_eq!( name_pattern("50%_off\\"), "%50\\%\\_off\\\\%" );
+		assert_eq!( name_pattern(""), "%%" );
+	}
+}
@@ -0,0 +1,194 @@
+use postgres::GenericConnection;
+use postgres::rows::Row;
+
+use chrono::UTC;
+
+use std::fs;
+use std::io::Read;
+
+use crypt::random_token;
+use db::IndusDatabase;
+use error::{IndusError, IndusResult};
+use storage::{self, FileInfo, Storage};
+
+const VERSION_COLUMNS: &'static str =
+	"SELECT v.file_id, v.number, v.author_id, v.size, v.mime, v.sha256, v.created, v.number = f.version, v.key
+	FROM file_versions v JOIN files f ON f.id = v.file_id";
+
+/// Which old versions of files to keep when pruning. The current version of
+/// a file is always kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retention {
+	/// The newest `n` versions of each file.
+	Last(u32),
+	/// Versions made in the last `n` days.
+	Days(u32),
+}
+
+/// One version of the contents of a file.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct FileVersion {
+	pub file: i32,
+	/// Counts up from 1 for each file, and is never used twice, even once
+	/// the versions after it are pruned.
+	pub number: i32,
+	/// Who uploaded it, until their account is deleted.
+	pub author: Option<i32>,
+	pub size: i64,
+	pub mime: String,
+	pub sha256: String,
+	pub created: i64,
+	/// Whether the file has this version now.
+	pub current: bool,
+}
+
+fn info(row: &Row) -> FileVersion {
+	FileVersion {
+		file: row.get(0),
+		number: row.get(1),
+		author: row.get(2),
+		size: row.get(3),
+		mime: row.get(4),
+		sha256: row.get(5),
+		created: row.get(6),
+		current: row.get(7),
+	}
+}
+
+/// A version and the key of its contents.
+fn get(conn: &GenericConnection, id: i32, number: i32) -> IndusResult<(FileVersion, String)> {
+	let stmt = try!( conn.prepare(&format!("{} WHERE v.file_id = $1 AND v.number = $2", VERSION_COLUMNS)) );
+	let rows = try!( stmt.query(&[&id, &number]) );
+	if rows.is_empty() {
+		return Err(IndusError::NotFound(format!("version {} of file {}", number, id)))
+	}
+	let row = rows.get(0);
+	Ok((info(&row), row.get(8)))
+}
+
+/// Locks a file until the end of the transaction, so that its versions
+/// change one at a time, and returns its MIME type.
+fn lock(conn: &GenericConnection, id: i32) -> IndusResult<String> {
+	let stmt = try!( conn.prepare("SELECT mime FROM files WHERE id = $1 FOR UPDATE") );
+	let rows = try!( stmt.query(&[&id]) );
+	if rows.is_empty() {
+		return Err(IndusError::NotFound(format!("file {}", id)))
+	}
+	Ok(rows.get(0).get(0))
+}
+
+/// Makes a version the current one of its file.
+fn make_current(conn: &GenericConnection, id: i32, version: &FileVersion, key: &str) -> IndusResult<()> {
+	try!( conn.execute(
+		"UPDATE files SET size = $2, mime = $3, sha256 = $4, key = $5, version = $6, modified = $7 WHERE id = $1",
+		&[&id, &version.size, &version.mime, &version.sha256, &key, &version.number, &UTC::now().timestamp()]
+	) );
+	Ok(())
+}
+
+impl IndusDatabase {
+	/// Stores new contents for a file, read from `src`, as its next version
+	/// by `author`. The file keeps its type unless `mime` is given, and the
+	/// versions before stay until they are pruned. As with `upload`, whether
+	/// the author may write to the file's folder is up to the caller.
+	pub fn replace_file<R: Read>(&self, storage: &Storage, id: i32, author: i32, mime: Option<&str>, mut src: R)
+		-> IndusResult<FileInfo> {
+		if let Some(mime) = mime {
+			try!(storage::check_mime(mime));
+		}
+		let spooled = try!(storage::spool(&mut src, storage.temp(), storage.max_size()));
+		let key = random_token();
+		// Stored before the file is locked, so that the lock is not held while
+		// the backend is slow.
+		if let Err(e) = storage.backend().put_file(&key, &spooled.path, spooled.size) {
+			let _ = fs::remove_file(&spooled.path);
+			return Err(e)
+		}
+		let result = self.transaction(|tx| {
+			let current = try!(lock(tx, id));
+			let mime = mime.map(String::from).unwrap_or(current);
+			let stmt = try!( tx.prepare("UPDATE files SET next_version = next_version + 1 WHERE id = $1 RETURNING next_version - 1") );
+			let number: i32 = try!(stmt.query(&[&id])).get(0).get(0);
+			try!( tx.execute(
+				"INSERT INTO file_versions (file_id, number, author_id, size, mime, sha256, key, created)
+				VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+				&[&id, &number, &author, &(spooled.size as i64), &mime, &spooled.sha256, &key, &UTC::now().timestamp()]
+			) );
+			let (version, _) = try!(get(tx, id, number));
+			try!(make_current(tx, id, &version, &key));
+			storage::get(tx, id).map(|(info, _)| info)
+		});
+		if result.is_err() {
+			let _ = storage.backend().delete(&key);
+		}
+		result
+	}
+
+	/// The versions of a file, newest first.
+	pub fn file_versions(&self, id: i32) -> IndusResult<Vec<FileVersion>> {
+		let conn = try!(self.conn());
+		try!(storage::get(&*conn, id));
+		let stmt = try!( conn.prepare(&format!("{} WHERE v.file_id = $1 ORDER BY v.number DESC", VERSION_COLUMNS)) );
+		let rows = try!( stmt.query(&[&id]) );
+		Ok( rows.iter().map(|row| info(&row)).collect() )
+	}
+
+	/// A file as it was at a version, and the contents it had then.
+	pub fn download_version(&self, storage: &Storage, id: i32, number: i32)
+		-> IndusResult<(FileInfo, Box<Read + Send>)> {
+		let conn = try!(self.conn());
+		let (mut info, _) = try!(storage::get(&*conn, id));
+		let (version, key) = try!(get(&*conn, id, number));
+		info.size = version.size;
+		info.mime = version.mime;
+		info.sha256 = version.sha256;
+		info.modified = version.created;
+		info.version = version.number;
+		let contents = try!(storage.backend().get(&key));
+		Ok((info, contents))
+	}
+
+	/// Makes an earlier version of a file the current one again. The versions
+	/// after it are kept.
+	pub fn restore_version(&self, id: i32, number: i32) -> IndusResult<FileInfo> {
+		self.transaction(|tx| {
+			try!(lock(tx, id));
+			let (version, key) = try!(get(tx, id, number));
+			try!(make_current(tx, id, &version, &key));
+			storage::get(tx, id).map(|(info, _)| info)
+		})
+	}
+
+	/// Deletes the versions of a file, or of every file, that `retention`
+	/// does not keep, and returns them.
+	pub fn prune_versions(&self, storage: &Storage, file: Option<i32>, retention: Retention)
+		-> IndusResult<Vec<FileVersion>> {
+		let (condition, bound) = match retention {
+			Retention::Last(n) => ("v.rank > $2", n as i64),
+			Retention::Days(n) => ("v.created < $2", UTC::now().timestamp() - n as i64 * 24 * 60 * 60),
+		};
+		let pruned = try!( self.transaction(|tx| {
+			// Locked so that no version is restored while it is being pruned.
+			try!( tx.execute("SELECT 1 FROM files WHERE $1::INT IS NULL OR id = $1 FOR UPDATE", &[&file]) );
+			let stmt = try!( tx.prepare(&format!(
+				"DELETE FROM file_versions WHERE id IN (
+					SELECT v.id FROM (
+						SELECT id, file_id, number, created,
+						row_number() OVER (PARTITION BY file_id ORDER BY number DESC) AS rank
+						FROM file_versions
+					) v JOIN files f ON f.id = v.file_id
+					WHERE v.number <> f.version AND ($1::INT IS NULL OR v.file_id = $1) AND {}
+				) RETURNING file_id, number, author_id, size, mime, sha256, created, FALSE, key", condition
+			)) );
+			let rows = try!( stmt.query(&[&file, &bound]) );
+			Ok( rows.iter().map(|row| (info(&row), row.get::<_, String>(8))).collect::<Vec<_>>() )
+		}) );
+		let mut versions = Vec::new();
+		for (version, key) in pruned {
+			storage.remove(&key);
+			versions.push(version);
+		}
+		versions.sort_by(|a, b| (a.file, a.number).cmp(&(b.file, b.number)));
+		Ok(versions)
+	}
+}